@@ -0,0 +1,241 @@
+//! # Usage
+//!
+//! `#[derive(AtomicRecord)]` on a struct `Foo` with named fields generates:
+//!
+//! * `AtomicFoo`, a record with one MCAS location per field. `AtomicFoo::new` stores a `Foo`,
+//!   `AtomicFoo::snapshot` reads all fields at one instant.
+//! * `FooUpdate`, returned by `AtomicFoo::update`. It starts from a snapshot, `set_<field>`
+//!   changes a field and `commit` writes the changed fields with a single MCAS, which fails if
+//!   any of them changed since the snapshot.
+//!
+//! ```
+//! use beee_derive::AtomicRecord;
+//!
+//! #[derive(AtomicRecord, Clone, Debug, PartialEq)]
+//! struct Bounds {
+//!     head: usize,
+//!     tail: usize,
+//!     generation: u64,
+//! }
+//!
+//! let bounds = AtomicBounds::new(Bounds { head: 0, tail: 0, generation: 0 });
+//! loop {
+//!     let update = bounds.update();
+//!     let current = update.current().clone();
+//!     if update
+//!         .set_tail(current.tail + 1)
+//!         .set_generation(current.generation + 1)
+//!         .commit()
+//!     {
+//!         break;
+//!     }
+//! }
+//! assert_eq!(bounds.snapshot(), Bounds { head: 0, tail: 1, generation: 1 });
+//! ```
+//!
+//! # Notes
+//!
+//! Every location holds a value of a generated enum with one variant per field, so that fields
+//! of different types can take part in the same `m_cas`. Field types must be `Clone`, `Send`,
+//! `Sync` and `'static`, generic structs are not supported.
+//!
+//! Cells replaced by `commit` are retired through `beee::epoch`. An update keeps its thread
+//! pinned until it is committed or dropped, so the cells it expects cannot be reused meanwhile.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use quote::{format_ident, quote};
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields};
+
+#[proc_macro_derive(AtomicRecord)]
+pub fn derive_atomic_record(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: DeriveInput) -> Result<proc_macro2::TokenStream, Error> {
+    if !input.generics.params.is_empty() {
+        return Err(Error::new(
+            input.generics.span(),
+            "AtomicRecord does not support generic structs",
+        ));
+    }
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) if !fields.named.is_empty() => &fields.named,
+            _ => {
+                return Err(Error::new(
+                    input.ident.span(),
+                    "AtomicRecord needs a struct with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(Error::new(
+                input.ident.span(),
+                "AtomicRecord can only be derived for structs",
+            ))
+        }
+    };
+
+    let vis = &input.vis;
+    let name = &input.ident;
+    let atomic = format_ident!("Atomic{}", name);
+    let field_enum = format_ident!("{}Field", name);
+    let update = format_ident!("{}Update", name);
+    let count = fields.len();
+
+    let names: Vec<_> = fields
+        .iter()
+        .map(|field| field.ident.clone().unwrap())
+        .collect();
+    let types: Vec<_> = fields.iter().map(|field| field.ty.clone()).collect();
+    let indices: Vec<_> = (0..count).map(syn::Index::from).collect();
+    let setters: Vec<_> = names
+        .iter()
+        .map(|name| format_ident!("set_{}", name))
+        .collect();
+
+    let m_cas = quote!(::beee::cas_utils::m_cas);
+    let epoch = quote!(::beee::epoch);
+    let cell = quote!(#m_cas::MCasPtr<#field_enum>);
+
+    let set_docs = names
+        .iter()
+        .map(|name| format!("Write `{}` when the update commits", name));
+    let values = quote! {
+        #name {
+            #(
+                #names: match unsafe { (*cells[#indices]).get() } {
+                    #field_enum::#names(val) => ::std::clone::Clone::clone(val),
+                    _ => unreachable!(),
+                },
+            )*
+        }
+    };
+
+    Ok(quote! {
+        #[allow(non_camel_case_types)]
+        #[doc(hidden)]
+        #vis enum #field_enum {
+            #( #names(#types), )*
+        }
+
+        #[doc = concat!("`", stringify!(#name), "` with one MCAS location per field")]
+        #vis struct #atomic {
+            #( #names: #m_cas::AtomicMCasPtr<#field_enum>, )*
+        }
+
+        impl #atomic
+        where
+            #( #types: ::std::clone::Clone + ::std::marker::Send + ::std::marker::Sync + 'static, )*
+        {
+            pub fn new(init: #name) -> #atomic {
+                #atomic {
+                    #(
+                        #names: #m_cas::AtomicMCasPtr::new(::std::boxed::Box::new(
+                            #m_cas::MCasPtr::new(#field_enum::#names(init.#names)),
+                        )),
+                    )*
+                }
+            }
+
+            /// Cells of all fields at one instant, valid while `guard` is alive
+            fn cells(&self, guard: &#epoch::Guard) -> [*mut #cell; #count] {
+                #m_cas::m_load_array([#( &self.#names, )*], guard)
+            }
+
+            /// Values of all fields at one instant
+            pub fn snapshot(&self) -> #name {
+                let guard = #epoch::pin();
+                let cells = self.cells(&guard);
+                #values
+            }
+
+            /// Start an update from a snapshot of all fields
+            pub fn update(&self) -> #update<'_> {
+                let guard = #epoch::pin();
+                let cells = self.cells(&guard);
+                #update {
+                    record: self,
+                    current: #values,
+                    cells,
+                    guard,
+                    #( #names: ::std::option::Option::None, )*
+                }
+            }
+        }
+
+        impl ::std::ops::Drop for #atomic {
+            fn drop(&mut self) {
+                for cell in [#( self.#names.load(), )*] {
+                    drop(unsafe { ::std::boxed::Box::from_raw(cell) });
+                }
+            }
+        }
+
+        #[doc = concat!("An update of an `", stringify!(#atomic), "`")]
+        #vis struct #update<'a> {
+            record: &'a #atomic,
+            current: #name,
+            cells: [*mut #cell; #count],
+            guard: #epoch::Guard,
+            #( #names: ::std::option::Option<#types>, )*
+        }
+
+        impl<'a> #update<'a>
+        where
+            #( #types: ::std::clone::Clone + ::std::marker::Send + ::std::marker::Sync + 'static, )*
+        {
+            /// Values the update started from
+            pub fn current(&self) -> &#name {
+                &self.current
+            }
+
+            #(
+                #[doc = #set_docs]
+                pub fn #setters(mut self, val: #types) -> Self {
+                    self.#names = ::std::option::Option::Some(val);
+                    self
+                }
+            )*
+
+            /// Write the changed fields with one MCAS. Returns `false`, and writes nothing, if any
+            /// of them changed since the update started.
+            pub fn commit(self) -> bool {
+                let mut entries = ::std::vec::Vec::new();
+                let mut written = ::std::vec::Vec::new();
+                #(
+                    if let ::std::option::Option::Some(val) = self.#names {
+                        let new = ::std::boxed::Box::into_raw(::std::boxed::Box::new(
+                            #m_cas::MCasPtr::new(#field_enum::#names(val)),
+                        ));
+                        entries.push(#m_cas::SingleCas::new(
+                            &self.record.#names,
+                            self.cells[#indices],
+                            new,
+                        ));
+                        written.push((self.cells[#indices], new));
+                    }
+                )*
+                if entries.is_empty() {
+                    return true;
+                }
+                if !#m_cas::MCas::m_cas(&entries) {
+                    for (_, new) in written {
+                        drop(unsafe { ::std::boxed::Box::from_raw(new) });
+                    }
+                    return false;
+                }
+                for (old, _) in written {
+                    unsafe { #m_cas::MCasPtr::retire(old, &self.guard) };
+                }
+                true
+            }
+        }
+    })
+}
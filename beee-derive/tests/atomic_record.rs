@@ -0,0 +1,84 @@
+use beee_derive::AtomicRecord;
+use std::sync::Arc;
+use std::thread;
+
+#[derive(AtomicRecord, Clone, Debug, PartialEq)]
+pub struct Window {
+    head: usize,
+    tail: usize,
+    len: usize,
+    generation: u64,
+}
+
+#[derive(AtomicRecord, Clone, Debug, PartialEq)]
+struct Named {
+    name: String,
+    version: u32,
+}
+
+#[test]
+fn update_changed_fields() {
+    let named = AtomicNamed::new(Named {
+        name: String::from("a"),
+        version: 0,
+    });
+    let stale = named.update();
+    assert!(named.update().set_name(String::from("b")).commit());
+    // Only `version` is written, which has not changed since `stale` started.
+    assert_eq!(stale.current().name, "a");
+    assert!(stale.set_version(1).commit());
+
+    let stale = named.update();
+    assert!(named.update().set_version(2).commit());
+    assert!(!stale.set_version(3).set_name(String::from("c")).commit());
+    assert!(named.update().commit());
+    assert_eq!(
+        named.snapshot(),
+        Named {
+            name: String::from("b"),
+            version: 2,
+        }
+    );
+}
+
+#[test]
+fn multi_thread_snapshot() {
+    const THREAD_NUM: usize = 4;
+    const ITER_NUM: usize = 2000;
+
+    let window = Arc::new(AtomicWindow::new(Window {
+        head: 0,
+        tail: 0,
+        len: 0,
+        generation: 0,
+    }));
+    let threads: Vec<_> = (0..THREAD_NUM)
+        .map(|i| {
+            let window = window.clone();
+            thread::spawn(move || {
+                for _ in 0..ITER_NUM {
+                    loop {
+                        let update = window.update();
+                        let current = update.current().clone();
+                        let update = if i % 2 == 0 || current.len == 0 {
+                            update.set_tail(current.tail + 1).set_len(current.len + 1)
+                        } else {
+                            update.set_head(current.head + 1).set_len(current.len - 1)
+                        };
+                        if update.set_generation(current.generation + 1).commit() {
+                            break;
+                        }
+                    }
+                    let snapshot = window.snapshot();
+                    assert_eq!(snapshot.tail - snapshot.head, snapshot.len);
+                }
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    let snapshot = window.snapshot();
+    assert_eq!(snapshot.generation, (THREAD_NUM * ITER_NUM) as u64);
+    assert_eq!(snapshot.tail - snapshot.head, snapshot.len);
+}
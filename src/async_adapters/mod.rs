@@ -0,0 +1,571 @@
+use crate::blocking::Container;
+use crate::mcas_queue;
+use crate::trieber_stack::Stack;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{fence, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+enum Slot {
+    Free,
+    Waiting(Waker),
+    /// Woken by a push. The pop which owns the slot passes the notification on if it is dropped
+    /// before it takes a value.
+    Notified,
+}
+
+/// # Fields
+///
+/// * `slots`: One slot per registered pop, which it keeps until it completes or is dropped, so
+///   polling again only swaps the waker
+/// * `free`: Indices of free slots
+/// * `queue`: Indices of `Waiting` slots, in the order they started waiting, so that pushes
+///   wake pops first come first served
+struct Slots {
+    slots: Vec<Slot>,
+    free: Vec<usize>,
+    queue: VecDeque<usize>,
+}
+
+/// Wakers of pending pops
+///
+/// # Fields
+///
+/// * `waiting`: Number of `Waiting` slots, so that a push finds out with one load that no pop
+///   is pending
+/// * `slots`: The slots
+struct WakerSet {
+    waiting: AtomicUsize,
+    slots: Mutex<Slots>,
+}
+
+impl WakerSet {
+    fn new() -> WakerSet {
+        WakerSet {
+            waiting: AtomicUsize::new(0),
+            slots: Mutex::new(Slots {
+                slots: Vec::new(),
+                free: Vec::new(),
+                queue: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Make `waker` the waker of the slot `key`, taking a slot first if `key` is `None`. The
+    /// structure must be checked again afterwards.
+    fn register(&self, key: &mut Option<usize>, waker: &Waker) {
+        let mut slots = self.slots.lock().unwrap();
+        let index = match *key {
+            Some(index) => index,
+            None => {
+                let index = match slots.free.pop() {
+                    Some(index) => index,
+                    None => {
+                        slots.slots.push(Slot::Free);
+                        slots.slots.len() - 1
+                    }
+                };
+                *key = Some(index);
+                index
+            }
+        };
+        match &mut slots.slots[index] {
+            Slot::Waiting(old) => {
+                if !old.will_wake(waker) {
+                    *old = waker.clone();
+                }
+            }
+            slot => {
+                *slot = Slot::Waiting(waker.clone());
+                slots.queue.push_back(index);
+                self.waiting.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Free the slot `key`, if any. `pass_on` wakes another pop in place of this one if it was
+    /// notified.
+    fn unregister(&self, key: &mut Option<usize>, pass_on: bool) {
+        let index = match key.take() {
+            Some(index) => index,
+            None => return,
+        };
+        let notified = {
+            let mut slots = self.slots.lock().unwrap();
+            let slot = std::mem::replace(&mut slots.slots[index], Slot::Free);
+            slots.free.push(index);
+            match slot {
+                Slot::Waiting(_) => {
+                    // Most pops unregister right after they registered, from the back.
+                    let position = slots.queue.iter().rposition(|&i| i == index).unwrap();
+                    slots.queue.remove(position);
+                    self.waiting.fetch_sub(1, Ordering::SeqCst);
+                    false
+                }
+                Slot::Notified => true,
+                Slot::Free => unreachable!(),
+            }
+        };
+        if notified && pass_on {
+            self.notify(false);
+        }
+    }
+
+    fn notify(&self, all: bool) {
+        // Orders the push before the load of `waiting`, against `register` which increments
+        // `waiting` before the structure is checked again.
+        fence(Ordering::SeqCst);
+        if self.waiting.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut woken = Vec::new();
+        {
+            let slots = &mut *self.slots.lock().unwrap();
+            while let Some(index) = slots.queue.pop_front() {
+                if let Slot::Waiting(waker) =
+                    std::mem::replace(&mut slots.slots[index], Slot::Notified)
+                {
+                    self.waiting.fetch_sub(1, Ordering::SeqCst);
+                    woken.push(waker);
+                }
+                if !all {
+                    break;
+                }
+            }
+        }
+        for waker in woken {
+            waker.wake();
+        }
+    }
+}
+
+/// `inner` with pops which can be awaited
+///
+/// A pending pop registers its waker, and every push wakes one registered pop. A push costs one
+/// fence and one load more when no pop is pending.
+pub struct Async<C> {
+    inner: C,
+    wakers: WakerSet,
+}
+
+pub type AsyncQueue<T> = Async<mcas_queue::Queue<T>>;
+pub type AsyncStack<T> = Async<Stack<T>>;
+
+impl<C: Container> Async<C> {
+    pub fn new(inner: C) -> Async<C> {
+        Async {
+            inner,
+            wakers: WakerSet::new(),
+        }
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    pub fn push(&self, val: C::Item) {
+        self.inner.push(val);
+        self.wakers.notify(false);
+    }
+
+    /// Pop a value, or `None` if there is none, without waiting.
+    pub fn try_pop(&self) -> Option<C::Item> {
+        self.inner.pop()
+    }
+
+    /// Pop a value, waiting until there is one.
+    pub fn pop(&self) -> Pop<'_, C> {
+        Pop {
+            structure: self,
+            key: None,
+        }
+    }
+
+    /// Pop a value, registering `cx` under `key` if there is none. `closed` is checked after
+    /// registering, and ends the wait with `None` once it returns `true`.
+    fn poll_pop(
+        &self,
+        key: &mut Option<usize>,
+        cx: &mut Context<'_>,
+        closed: impl Fn() -> bool,
+    ) -> Poll<Option<C::Item>> {
+        if let Some(val) = self.inner.pop() {
+            self.wakers.unregister(key, false);
+            return Poll::Ready(Some(val));
+        }
+        self.wakers.register(key, cx.waker());
+        if let Some(val) = self.inner.pop() {
+            self.wakers.unregister(key, false);
+            return Poll::Ready(Some(val));
+        }
+        if closed() {
+            self.wakers.unregister(key, false);
+            // A push may have come before the close.
+            return Poll::Ready(self.inner.pop());
+        }
+        Poll::Pending
+    }
+}
+
+impl<C: Container + Default> Default for Async<C> {
+    fn default() -> Self {
+        Async::new(C::default())
+    }
+}
+
+/// Future of `Async::pop`
+pub struct Pop<'a, C: Container> {
+    structure: &'a Async<C>,
+    key: Option<usize>,
+}
+
+impl<C: Container> Future for Pop<'_, C> {
+    type Output = C::Item;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<C::Item> {
+        let this = self.get_mut();
+        match this.structure.poll_pop(&mut this.key, cx, || false) {
+            Poll::Ready(Some(val)) => Poll::Ready(val),
+            Poll::Ready(None) => unreachable!(),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<C: Container> Drop for Pop<'_, C> {
+    fn drop(&mut self) {
+        self.structure.wakers.unregister(&mut self.key, true);
+    }
+}
+
+/// # Fields
+///
+/// * `structure`: The structure values go through
+/// * `senders`: Number of `Sender`s not yet dropped or closed
+struct Shared<C> {
+    structure: Async<C>,
+    senders: AtomicUsize,
+}
+
+/// A channel through `C`, which ends once all senders are dropped or closed and `C` is empty.
+/// Both ends can be cloned.
+pub fn channel<C: Container + Default>() -> (Sender<C>, Receiver<C>) {
+    let shared = Arc::new(Shared {
+        structure: Async::default(),
+        senders: AtomicUsize::new(1),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+            closed: false,
+        },
+        Receiver { shared, key: None },
+    )
+}
+
+/// The pushing end of a `channel`, with the methods of a `Sink`
+pub struct Sender<C: Container> {
+    shared: Arc<Shared<C>>,
+    closed: bool,
+}
+
+impl<C: Container> Sender<C> {
+    pub fn send(&self, val: C::Item) {
+        self.shared.structure.push(val);
+    }
+
+    pub fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    pub fn start_send(self: Pin<&mut Self>, val: C::Item) -> Result<(), Infallible> {
+        self.send(val);
+        Ok(())
+    }
+
+    pub fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Stop counting this sender, ending the channel if it is the last one.
+    pub fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        let this = self.get_mut();
+        if !this.closed {
+            this.closed = true;
+            this.release();
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn release(&self) {
+        if self.shared.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.shared.structure.wakers.notify(true);
+        }
+    }
+}
+
+impl<C: Container> Clone for Sender<C> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::SeqCst);
+        Sender {
+            shared: self.shared.clone(),
+            closed: false,
+        }
+    }
+}
+
+impl<C: Container> Drop for Sender<C> {
+    fn drop(&mut self) {
+        if !self.closed {
+            self.release();
+        }
+    }
+}
+
+/// The popping end of a `channel`, with the methods of a `Stream`
+pub struct Receiver<C: Container> {
+    shared: Arc<Shared<C>>,
+    key: Option<usize>,
+}
+
+impl<C: Container> Receiver<C> {
+    /// Pop a value, or `None` if there is none, without waiting.
+    pub fn try_recv(&self) -> Option<C::Item> {
+        self.shared.structure.try_pop()
+    }
+
+    /// Pop a value, waiting until there is one. `None` once the channel has ended.
+    pub fn recv(&mut self) -> Recv<'_, C> {
+        Recv { receiver: self }
+    }
+
+    pub fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<C::Item>> {
+        let this = self.get_mut();
+        let shared = &this.shared;
+        shared.structure.poll_pop(&mut this.key, cx, || {
+            shared.senders.load(Ordering::SeqCst) == 0
+        })
+    }
+}
+
+impl<C: Container> Clone for Receiver<C> {
+    fn clone(&self) -> Self {
+        Receiver {
+            shared: self.shared.clone(),
+            key: None,
+        }
+    }
+}
+
+impl<C: Container> Drop for Receiver<C> {
+    fn drop(&mut self) {
+        self.shared.structure.wakers.unregister(&mut self.key, true);
+    }
+}
+
+/// Future of `Receiver::recv`
+pub struct Recv<'a, C: Container> {
+    receiver: &'a mut Receiver<C>,
+}
+
+impl<C: Container> Future for Recv<'_, C> {
+    type Output = Option<C::Item>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<C::Item>> {
+        Pin::new(&mut *self.get_mut().receiver).poll_next(cx)
+    }
+}
+
+impl<C: Container> Drop for Recv<'_, C> {
+    fn drop(&mut self) {
+        let receiver = &mut *self.receiver;
+        receiver
+            .shared
+            .structure
+            .wakers
+            .unregister(&mut receiver.key, true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ms_queue;
+    use std::pin::pin;
+    use std::sync::atomic::AtomicBool;
+    use std::task::Wake;
+    use std::thread::{self, Thread};
+    use std::time::Duration;
+
+    struct ThreadWaker {
+        thread: Thread,
+        woken: AtomicBool,
+    }
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.woken.store(true, Ordering::SeqCst);
+            self.thread.unpark();
+        }
+    }
+
+    fn thread_waker() -> Arc<ThreadWaker> {
+        Arc::new(ThreadWaker {
+            thread: thread::current(),
+            woken: AtomicBool::new(false),
+        })
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let waker = Waker::from(thread_waker());
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(res) => return res,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn pop_waits_for_push() {
+        let s = Arc::new(AsyncStack::new(Stack::new()));
+        let producer = {
+            let s = s.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                s.push(1);
+            })
+        };
+        assert_eq!(block_on(s.pop()), 1);
+        producer.join().unwrap();
+        assert_eq!(s.try_pop(), None);
+    }
+
+    #[test]
+    fn dropped_pop_passes_notification_on() {
+        let q: AsyncQueue<usize> = Async::default();
+        let (first_waker, second_waker) = (thread_waker(), thread_waker());
+        let mut first = Box::pin(q.pop());
+        let mut second = Box::pin(q.pop());
+        let pending = |pop: &mut Pin<Box<Pop<'_, _>>>, waker: &Arc<ThreadWaker>| {
+            let waker = Waker::from(waker.clone());
+            pop.as_mut()
+                .poll(&mut Context::from_waker(&waker))
+                .is_pending()
+        };
+        assert!(pending(&mut first, &first_waker));
+        assert!(pending(&mut second, &second_waker));
+        // Polling again reuses the slot.
+        assert!(pending(&mut first, &first_waker));
+        assert_eq!(q.wakers.slots.lock().unwrap().slots.len(), 2);
+
+        q.push(7);
+        assert!(first_waker.woken.load(Ordering::SeqCst));
+        assert!(!second_waker.woken.load(Ordering::SeqCst));
+        drop(first);
+        assert!(second_waker.woken.load(Ordering::SeqCst));
+        assert_eq!(block_on(second), 7);
+        assert_eq!(q.wakers.waiting.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn pops_are_woken_in_order() {
+        let q: AsyncQueue<usize> = Async::default();
+        let wakers = [thread_waker(), thread_waker(), thread_waker()];
+        let pending = |pop: &mut Pin<Box<Pop<'_, _>>>, waker: &Arc<ThreadWaker>| {
+            let waker = Waker::from(waker.clone());
+            pop.as_mut()
+                .poll(&mut Context::from_waker(&waker))
+                .is_pending()
+        };
+        let mut first = Box::pin(q.pop());
+        let mut second = Box::pin(q.pop());
+        assert!(pending(&mut first, &wakers[0]));
+        assert!(pending(&mut second, &wakers[1]));
+        // The third pop takes the slot of the first one, but waits behind the second.
+        drop(first);
+        let mut third = Box::pin(q.pop());
+        assert!(pending(&mut third, &wakers[2]));
+        assert_eq!(q.wakers.slots.lock().unwrap().slots.len(), 2);
+
+        q.push(1);
+        assert!(wakers[1].woken.load(Ordering::SeqCst));
+        assert!(!wakers[2].woken.load(Ordering::SeqCst));
+        q.push(2);
+        assert!(wakers[2].woken.load(Ordering::SeqCst));
+        assert_eq!(block_on(second), 1);
+        assert_eq!(block_on(third), 2);
+        assert!(q.wakers.slots.lock().unwrap().queue.is_empty());
+    }
+
+    #[test]
+    fn channel_ends_after_senders() {
+        let (tx, mut rx) = channel::<ms_queue::Queue<usize>>();
+        let tx2 = tx.clone();
+        tx.send(1);
+        drop(tx);
+        let producer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            let mut tx2 = pin!(tx2);
+            let waker = Waker::from(thread_waker());
+            let mut cx = Context::from_waker(&waker);
+            assert!(tx2.as_mut().poll_ready(&mut cx).is_ready());
+            tx2.as_mut().start_send(2).unwrap();
+            assert!(tx2.as_mut().poll_close(&mut cx).is_ready());
+        });
+        assert_eq!(block_on(rx.recv()), Some(1));
+        assert_eq!(block_on(rx.recv()), Some(2));
+        assert_eq!(block_on(rx.recv()), None);
+        producer.join().unwrap();
+    }
+
+    #[test]
+    fn multi_producer_multi_consumer() {
+        const PRODUCER_NUM: usize = 4;
+        const CONSUMER_NUM: usize = 4;
+        const ITER_NUM: usize = 1 << 12;
+
+        let (tx, rx) = channel::<mcas_queue::Queue<(usize, usize)>>();
+        let consumers: Vec<_> = (0..CONSUMER_NUM)
+            .map(|_| {
+                let mut rx = rx.clone();
+                thread::spawn(move || {
+                    let mut popped = Vec::new();
+                    while let Some(val) = block_on(rx.recv()) {
+                        popped.push(val);
+                    }
+                    popped
+                })
+            })
+            .collect();
+        drop(rx);
+        let producers: Vec<_> = (0..PRODUCER_NUM)
+            .map(|producer| {
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    for i in 0..ITER_NUM {
+                        tx.send((producer, i));
+                    }
+                })
+            })
+            .collect();
+        drop(tx);
+        for t in producers {
+            t.join().unwrap();
+        }
+        let mut popped: Vec<_> = consumers
+            .into_iter()
+            .flat_map(|t| t.join().unwrap())
+            .collect();
+        popped.sort_unstable();
+        let expected: Vec<_> = (0..PRODUCER_NUM)
+            .flat_map(|producer| (0..ITER_NUM).map(move |i| (producer, i)))
+            .collect();
+        assert_eq!(popped, expected);
+    }
+}
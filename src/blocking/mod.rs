@@ -0,0 +1,177 @@
+use crate::eventcount::EventCount;
+use crate::trieber_stack::Stack;
+use crate::{mcas_queue, ms_queue};
+use std::time::{Duration, Instant};
+
+/// A structure `Blocking` can wrap
+pub trait Container {
+    type Item;
+
+    fn push(&self, val: Self::Item);
+    fn pop(&self) -> Option<Self::Item>;
+}
+
+impl<T: 'static> Container for Stack<T> {
+    type Item = T;
+
+    fn push(&self, val: T) {
+        Stack::push(self, val)
+    }
+
+    fn pop(&self) -> Option<T> {
+        Stack::pop(self)
+    }
+}
+
+impl<T: 'static> Container for ms_queue::Queue<T> {
+    type Item = T;
+
+    fn push(&self, val: T) {
+        ms_queue::Queue::push(self, val)
+    }
+
+    fn pop(&self) -> Option<T> {
+        ms_queue::Queue::pop(self)
+    }
+}
+
+impl<T: 'static> Container for mcas_queue::Queue<T> {
+    type Item = T;
+
+    fn push(&self, val: T) {
+        mcas_queue::Queue::push(self, val)
+    }
+
+    fn pop(&self) -> Option<T> {
+        mcas_queue::Queue::pop(self)
+    }
+}
+
+/// `inner` with pops which wait for a value
+///
+/// A waiting pop spins for a while and then parks its thread on `event`, which every push
+/// notifies. A push costs one fence and one load more when no pop is waiting.
+pub struct Blocking<C> {
+    inner: C,
+    event: EventCount,
+}
+
+pub type BlockingQueue<T> = Blocking<ms_queue::Queue<T>>;
+pub type BlockingStack<T> = Blocking<Stack<T>>;
+
+impl<C: Container> Blocking<C> {
+    pub fn new(inner: C) -> Blocking<C> {
+        Blocking {
+            inner,
+            event: EventCount::new(),
+        }
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    pub fn push(&self, val: C::Item) {
+        self.inner.push(val);
+        self.event.notify_one();
+    }
+
+    /// Pop a value, or `None` if there is none, without waiting.
+    pub fn pop(&self) -> Option<C::Item> {
+        self.inner.pop()
+    }
+
+    /// Pop a value, waiting until there is one.
+    pub fn pop_wait(&self) -> C::Item {
+        match self.event.wait_for(|| self.inner.pop(), None) {
+            Some(val) => val,
+            None => unreachable!(),
+        }
+    }
+
+    /// Pop a value, waiting at most `timeout` for one.
+    pub fn pop_timeout(&self, timeout: Duration) -> Option<C::Item> {
+        self.event
+            .wait_for(|| self.inner.pop(), Some(Instant::now() + timeout))
+    }
+}
+
+impl<C: Container + Default> Default for Blocking<C> {
+    fn default() -> Self {
+        Blocking::new(C::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn pop_timeout_empty() {
+        let q: BlockingQueue<usize> = Blocking::default();
+        let start = Instant::now();
+        assert_eq!(q.pop_timeout(Duration::from_millis(20)), None);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        q.push(1);
+        assert_eq!(q.pop_timeout(Duration::from_millis(20)), Some(1));
+    }
+
+    #[test]
+    fn stack_pop_wait() {
+        let s = Arc::new(BlockingStack::new(Stack::new()));
+        let consumer = {
+            let s = s.clone();
+            thread::spawn(move || s.pop_wait())
+        };
+        thread::sleep(Duration::from_millis(20));
+        s.push(7);
+        assert_eq!(consumer.join().unwrap(), 7);
+    }
+
+    #[test]
+    fn multi_producer_multi_consumer() {
+        const PRODUCER_NUM: usize = 4;
+        const CONSUMER_NUM: usize = 4;
+        const ITER_NUM: usize = 1 << 12;
+
+        let q = Arc::new(BlockingQueue::default());
+        let consumers: Vec<_> = (0..CONSUMER_NUM)
+            .map(|_| {
+                let q = q.clone();
+                thread::spawn(move || {
+                    (0..PRODUCER_NUM * ITER_NUM / CONSUMER_NUM)
+                        .map(|_| q.pop_wait())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let producers: Vec<_> = (0..PRODUCER_NUM)
+            .map(|producer| {
+                let q = q.clone();
+                thread::spawn(move || {
+                    for i in 0..ITER_NUM {
+                        q.push((producer, i));
+                        if i % 64 == 0 {
+                            thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in producers {
+            t.join().unwrap();
+        }
+        let mut popped: Vec<_> = consumers
+            .into_iter()
+            .flat_map(|t| t.join().unwrap())
+            .collect();
+        popped.sort_unstable();
+        let expected: Vec<_> = (0..PRODUCER_NUM)
+            .flat_map(|producer| (0..ITER_NUM).map(move |i| (producer, i)))
+            .collect();
+        assert_eq!(popped, expected);
+        assert_eq!(q.pop(), None);
+    }
+}
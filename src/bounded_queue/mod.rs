@@ -0,0 +1,228 @@
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// # Fields
+///
+/// * `seq`: Position the slot is ready for. It equals the position of a push which may write
+///   the slot, and is one past the position of a pop which may read it.
+/// * `val`: The value, initialized between a push and the following pop
+struct Slot<T> {
+    seq: AtomicUsize,
+    val: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// The bounded MPMC queue of
+/// [Bounded MPMC queue](https://www.1024cores.net/home/lock-free-algorithms/queues/bounded-mpmc-queue)
+///
+/// `head` and `tail` are the positions of the next pop and push, the slot of a position is the
+/// position modulo the capacity. A push or pop claims its position with one CAS after it has
+/// seen from the sequence number that the slot is ready, so slots are reused without
+/// allocation and nothing needs to be reclaimed.
+pub struct Queue<T> {
+    slots: Box<[Slot<T>]>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+unsafe impl<T: Send> Send for Queue<T> {}
+unsafe impl<T: Send> Sync for Queue<T> {}
+
+impl<T> Queue<T> {
+    /// A queue holding up to `capacity` values. Panics if `capacity` is 0.
+    pub fn new(capacity: usize) -> Queue<T> {
+        assert!(capacity > 0, "capacity must be positive");
+        Queue {
+            slots: (0..capacity)
+                .map(|i| Slot {
+                    seq: AtomicUsize::new(i),
+                    val: UnsafeCell::new(MaybeUninit::uninit()),
+                })
+                .collect(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Push `val`, giving it back if the queue is full. It is also given back while the pop of
+    /// the slot a lap ago has claimed it but not read it yet.
+    pub fn try_push(&self, val: T) -> Result<(), T> {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % self.capacity()];
+            let seq = slot.seq.load(Ordering::Acquire);
+            match seq.wrapping_sub(pos) as isize {
+                0 => match self.tail.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.val.get()).write(val) };
+                        slot.seq.store(pos.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => pos = current,
+                },
+                // The value pushed a lap ago has not been popped yet.
+                diff if diff < 0 => return Err(val),
+                _ => pos = self.tail.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Pop a value, or `None` if the queue is empty. It is also `None` while the push of the
+    /// front position has claimed its slot but not written it yet, even if later pushes have
+    /// completed.
+    pub fn try_pop(&self) -> Option<T> {
+        let mut pos = self.head.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % self.capacity()];
+            let seq = slot.seq.load(Ordering::Acquire);
+            match seq.wrapping_sub(pos.wrapping_add(1)) as isize {
+                0 => match self.head.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let val = unsafe { (*slot.val.get()).as_ptr().read() };
+                        slot.seq
+                            .store(pos.wrapping_add(self.capacity()), Ordering::Release);
+                        return Some(val);
+                    }
+                    Err(current) => pos = current,
+                },
+                // The value of this position has not been pushed yet.
+                diff if diff < 0 => return None,
+                _ => pos = self.head.load(Ordering::Relaxed),
+            }
+        }
+    }
+}
+
+impl<T> Drop for Queue<T> {
+    fn drop(&mut self) {
+        while self.try_pop().is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use test::Bencher;
+
+    #[test]
+    fn single_thread_bounded() {
+        let q = Queue::new(3);
+        assert_eq!(q.try_pop(), None);
+        for lap in 0..4 {
+            for i in 0..3 {
+                assert_eq!(q.try_push(lap * 3 + i), Ok(()));
+            }
+            assert_eq!(q.try_push(-1), Err(-1));
+            for i in 0..3 {
+                assert_eq!(q.try_pop(), Some(lap * 3 + i));
+            }
+            assert_eq!(q.try_pop(), None);
+        }
+    }
+
+    #[test]
+    fn drop_remaining_values() {
+        let val = Arc::new(());
+        let q = Queue::new(8);
+        for _ in 0..5 {
+            q.try_push(val.clone()).unwrap();
+        }
+        drop(q.try_pop());
+        assert_eq!(Arc::strong_count(&val), 5);
+        drop(q);
+        assert_eq!(Arc::strong_count(&val), 1);
+    }
+
+    #[test]
+    fn multi_producer_multi_consumer() {
+        const PRODUCER_NUM: usize = 4;
+        const CONSUMER_NUM: usize = 4;
+        const ITER_NUM: usize = 1 << 14;
+
+        let q = Arc::new(Queue::new(16));
+        let producers: Vec<_> = (0..PRODUCER_NUM)
+            .map(|producer| {
+                let q = q.clone();
+                thread::spawn(move || {
+                    for i in 0..ITER_NUM {
+                        let mut val = (producer, i);
+                        while let Err(back) = q.try_push(val) {
+                            val = back;
+                            thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect();
+        let consumers: Vec<_> = (0..CONSUMER_NUM)
+            .map(|_| {
+                let q = q.clone();
+                thread::spawn(move || {
+                    let mut last = [None; PRODUCER_NUM];
+                    let mut popped = Vec::new();
+                    while popped.len() < PRODUCER_NUM * ITER_NUM / CONSUMER_NUM {
+                        match q.try_pop() {
+                            Some((producer, i)) => {
+                                assert!(last[producer].is_none_or(|last| last < i));
+                                last[producer] = Some(i);
+                                popped.push((producer, i));
+                            }
+                            None => thread::yield_now(),
+                        }
+                    }
+                    popped
+                })
+            })
+            .collect();
+        for t in producers {
+            t.join().unwrap();
+        }
+        let mut popped: Vec<_> = consumers
+            .into_iter()
+            .flat_map(|t| t.join().unwrap())
+            .collect();
+        popped.sort_unstable();
+        let expected: Vec<_> = (0..PRODUCER_NUM)
+            .flat_map(|producer| (0..ITER_NUM).map(move |i| (producer, i)))
+            .collect();
+        assert_eq!(popped, expected);
+        assert_eq!(q.try_pop(), None);
+    }
+
+    #[bench]
+    fn bench_push_pop(b: &mut Bencher) {
+        b.iter(|| {
+            let q = Arc::new(Queue::new(64));
+            let threads: Vec<_> = (0..4)
+                .map(|_| {
+                    let q = q.clone();
+                    thread::spawn(move || {
+                        for i in 0..1 << 8 {
+                            while q.try_push(i).is_err() {}
+                            while q.try_pop().is_none() {}
+                        }
+                    })
+                })
+                .collect();
+            for t in threads {
+                t.join().unwrap();
+            }
+        });
+    }
+}
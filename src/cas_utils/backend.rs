@@ -0,0 +1,285 @@
+//! # Usage
+//!
+//! `MCasBackend` lets code be written once against either MCAS algorithm of this crate. The
+//! backend is chosen per location type: `<HarrisMCas as MCasBackend<T>>::Location` is an
+//! `AtomicMCasPtr<T>`, `<EfficientMCas as MCasBackend<T>>::Location` is an `AtomicKCasPtr<T>`.
+//!
+//! ```
+//! # use beee::cas_utils::backend::*;
+//! fn swap_values<T: 'static, B: MCasBackend<T>>(first: &B::Location, second: &B::Location) -> bool {
+//!     let first_cell = B::load(first);
+//!     let second_cell = B::load(second);
+//!     B::m_cas(&[
+//!         B::entry(first, first_cell, second_cell),
+//!         B::entry(second, second_cell, first_cell),
+//!     ])
+//! }
+//!
+//! let first = EfficientMCas::new_location(Box::new(EfficientMCas::new_cell(1)));
+//! let second = EfficientMCas::new_location(Box::new(EfficientMCas::new_cell(2)));
+//! assert!(swap_values::<i32, EfficientMCas>(&first, &second));
+//! assert_eq!(unsafe { *EfficientMCas::value(EfficientMCas::load(&first)) }, 2);
+//! ```
+//!
+//! # Notes
+//!
+//! * `HarrisMCas`: the CCAS based design of `m_cas`. It supports compare-only entries and
+//!   removes its descriptor from every location once decided, at the cost of about `3k` CAS.
+//! * `EfficientMCas`: the design of `k_cas`, `k + 1` CAS and `4k` pin count updates without
+//!   contention. Descriptors stay installed, so every read looks at the status of the last MCAS
+//!   of the location.
+//! * `WaitFreeMCas`: `wf_m_cas`, the Harris/Fraser MCAS with announcement based helping. It
+//!   shares its locations with `HarrisMCas`.
+
+use crate::cas_utils::k_cas::{AtomicKCasPtr, KCasPtr, SingleKCas};
+use crate::cas_utils::m_cas::{AtomicMCasPtr, MCas, MCasPtr, SingleCas};
+use crate::cas_utils::wf_m_cas::WaitFree;
+
+pub trait MCasBackend<T> {
+    /// A shared location
+    type Location: Clone;
+    /// A value cell, locations point to cells
+    type Cell;
+    /// One entry of a MCAS
+    type Entry;
+
+    fn new_cell(val: T) -> Self::Cell;
+    /// A location holding `cell`, which it never frees
+    fn new_location(cell: Box<Self::Cell>) -> Self::Location;
+    /// The cell currently stored in `location`, after helping any operation in progress
+    fn load(location: &Self::Location) -> *mut Self::Cell;
+    /// # Safety
+    ///
+    /// `cell` must point to a live cell.
+    unsafe fn value(cell: *mut Self::Cell) -> *const T;
+    fn entry(
+        location: &Self::Location,
+        expect: *mut Self::Cell,
+        new: *mut Self::Cell,
+    ) -> Self::Entry;
+    fn m_cas(entries: &[Self::Entry]) -> bool;
+}
+
+/// The Harris/Fraser MCAS of `m_cas`
+pub struct HarrisMCas;
+
+impl<T: 'static> MCasBackend<T> for HarrisMCas {
+    type Location = AtomicMCasPtr<T>;
+    type Cell = MCasPtr<T>;
+    type Entry = SingleCas<T>;
+
+    fn new_cell(val: T) -> Self::Cell {
+        MCasPtr::new(val)
+    }
+    fn new_location(cell: Box<Self::Cell>) -> Self::Location {
+        AtomicMCasPtr::new(cell)
+    }
+    fn load(location: &Self::Location) -> *mut Self::Cell {
+        location.load()
+    }
+    unsafe fn value(cell: *mut Self::Cell) -> *const T {
+        (*cell).get()
+    }
+    fn entry(
+        location: &Self::Location,
+        expect: *mut Self::Cell,
+        new: *mut Self::Cell,
+    ) -> Self::Entry {
+        SingleCas::new(location, expect, new)
+    }
+    fn m_cas(entries: &[Self::Entry]) -> bool {
+        entries.m_cas()
+    }
+}
+
+/// The Guerraoui–Kogan–Marathe–Zablotchi MCAS of `k_cas`
+pub struct EfficientMCas;
+
+impl<T: 'static> MCasBackend<T> for EfficientMCas {
+    type Location = AtomicKCasPtr<T>;
+    type Cell = KCasPtr<T>;
+    type Entry = SingleKCas<T>;
+
+    fn new_cell(val: T) -> Self::Cell {
+        KCasPtr::new(val)
+    }
+    fn new_location(cell: Box<Self::Cell>) -> Self::Location {
+        AtomicKCasPtr::new(cell)
+    }
+    fn load(location: &Self::Location) -> *mut Self::Cell {
+        location.load()
+    }
+    unsafe fn value(cell: *mut Self::Cell) -> *const T {
+        (*cell).get()
+    }
+    fn entry(
+        location: &Self::Location,
+        expect: *mut Self::Cell,
+        new: *mut Self::Cell,
+    ) -> Self::Entry {
+        SingleKCas::new(location, expect, new)
+    }
+    fn m_cas(entries: &[Self::Entry]) -> bool {
+        entries.m_cas()
+    }
+}
+
+/// The wait-free MCAS of `wf_m_cas`
+pub struct WaitFreeMCas;
+
+impl<T: 'static> MCasBackend<T> for WaitFreeMCas {
+    type Location = AtomicMCasPtr<T>;
+    type Cell = MCasPtr<T>;
+    type Entry = SingleCas<T>;
+
+    fn new_cell(val: T) -> Self::Cell {
+        MCasPtr::new(val)
+    }
+    fn new_location(cell: Box<Self::Cell>) -> Self::Location {
+        AtomicMCasPtr::new(cell)
+    }
+    fn load(location: &Self::Location) -> *mut Self::Cell {
+        location.load()
+    }
+    unsafe fn value(cell: *mut Self::Cell) -> *const T {
+        (*cell).get()
+    }
+    fn entry(
+        location: &Self::Location,
+        expect: *mut Self::Cell,
+        new: *mut Self::Cell,
+    ) -> Self::Entry {
+        SingleCas::new(location, expect, new)
+    }
+    fn m_cas(entries: &[Self::Entry]) -> bool {
+        WaitFree(entries).m_cas()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use test::Bencher;
+
+    /// Locations of `words` counters, all starting at zero
+    fn counters<B: MCasBackend<usize>>(words: usize) -> Vec<B::Location> {
+        (0..words)
+            .map(|_| B::new_location(Box::new(B::new_cell(0))))
+            .collect()
+    }
+
+    /// Increment every counter by one in a single MCAS
+    fn increment<B: MCasBackend<usize>>(locations: &[B::Location]) {
+        loop {
+            let entries: Vec<B::Entry> = locations
+                .iter()
+                .map(|location| {
+                    let cell = B::load(location);
+                    let new = Box::leak(Box::new(B::new_cell(unsafe { *B::value(cell) } + 1)));
+                    B::entry(location, cell, new)
+                })
+                .collect();
+            if B::m_cas(&entries) {
+                break;
+            }
+        }
+    }
+
+    fn multi_thread_increment<B: MCasBackend<usize>>()
+    where
+        B::Location: Send + 'static,
+    {
+        const THREAD_NUM: usize = 8;
+        const ITER_NUM: usize = 2000;
+
+        let locations = counters::<B>(4);
+        let threads: Vec<_> = (0..THREAD_NUM)
+            .map(|i| {
+                // Every thread touches an overlapping window of the counters.
+                let window = vec![locations[i % 4].clone(), locations[(i + 1) % 4].clone()];
+                thread::spawn(move || {
+                    for _ in 0..ITER_NUM {
+                        increment::<B>(&window);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        let total: usize = locations
+            .iter()
+            .map(|location| unsafe { *B::value(B::load(location)) })
+            .sum();
+        assert_eq!(total, 2 * THREAD_NUM * ITER_NUM);
+    }
+
+    #[test]
+    fn multi_thread_harris() {
+        multi_thread_increment::<HarrisMCas>();
+    }
+
+    #[test]
+    fn multi_thread_efficient() {
+        multi_thread_increment::<EfficientMCas>();
+    }
+
+    #[test]
+    fn multi_thread_wait_free() {
+        multi_thread_increment::<WaitFreeMCas>();
+    }
+
+    /// Swap every location between two fixed cells, so the benchmark does not allocate cells.
+    fn bench_words<B: MCasBackend<usize>>(b: &mut Bencher, words: usize) {
+        let locations: Vec<B::Location> = (0..words)
+            .map(|i| B::new_location(Box::new(B::new_cell(i))))
+            .collect();
+        let (forward, backward): (Vec<B::Entry>, Vec<B::Entry>) = locations
+            .iter()
+            .enumerate()
+            .map(|(i, location)| {
+                let first = B::load(location);
+                let second = Box::into_raw(Box::new(B::new_cell(i)));
+                (
+                    B::entry(location, first, second),
+                    B::entry(location, second, first),
+                )
+            })
+            .unzip();
+        b.iter(|| {
+            assert!(B::m_cas(&forward));
+            assert!(B::m_cas(&backward));
+        });
+    }
+
+    #[bench]
+    fn bench_harris_two_words(b: &mut Bencher) {
+        bench_words::<HarrisMCas>(b, 2);
+    }
+
+    #[bench]
+    fn bench_efficient_two_words(b: &mut Bencher) {
+        bench_words::<EfficientMCas>(b, 2);
+    }
+
+    #[bench]
+    fn bench_harris_eight_words(b: &mut Bencher) {
+        bench_words::<HarrisMCas>(b, 8);
+    }
+
+    #[bench]
+    fn bench_efficient_eight_words(b: &mut Bencher) {
+        bench_words::<EfficientMCas>(b, 8);
+    }
+
+    #[bench]
+    fn bench_wait_free_two_words(b: &mut Bencher) {
+        bench_words::<WaitFreeMCas>(b, 2);
+    }
+
+    #[bench]
+    fn bench_wait_free_eight_words(b: &mut Bencher) {
+        bench_words::<WaitFreeMCas>(b, 8);
+    }
+}
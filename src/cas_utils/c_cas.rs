@@ -34,25 +34,40 @@
 //!
 //! The detail algorithm is written in [Practicallock-freedom](https://www.cl.cam.ac.uk/techreports/UCAM-CL-TR-579.pdf).
 
+use crate::cas_utils::metrics;
+use crate::cas_utils::pool::{self, Pooled, Recycle};
 use crate::cas_utils::Status;
-use crate::utils::{AtomicNumLikes, AtomicNumLikesMethods, AtomicPtrAddOn};
+use crate::utils::{AtomicNumLikes, AtomicNumLikesMethods};
+use std::cell::UnsafeCell;
+use std::ptr::null_mut;
 use std::sync::atomic::AtomicPtr;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 /// A CCas Descriptor
 ///
+/// Descriptors are pooled per thread, see `cas_utils::pool`. Everything but `recycle` is only
+/// rewritten when the descriptor is reused, so it lives in an `UnsafeCell`.
+pub struct CCasDesc<T> {
+    recycle: Recycle,
+    op: UnsafeCell<CCasOp<T>>,
+}
+
 /// # Fields
 ///
 /// * `inner`: Store original AtomicPtr
 /// * `expect`: Expected value of inner
 /// * `new`: New value of inner
 /// * `cond`: Cond var. Only if it equals Status::Undecided will cas happens
-pub struct CCasDesc<T> {
-    inner: Arc<AtomicPtr<CCasUnion<T>>>, // TODO: UnsafeCell is only for get_addr, maybe there is a better way to get addr rather than use UnsafeCell?
+/// * `cond_owner`: Keeps `cond` alive until the descriptor is reused, if `cond` was passed as an
+///   `Arc`
+struct CCasOp<T> {
+    inner: *const AtomicPtr<CCasUnion<T>>,
     expect: *mut CCasUnion<T>,
     new: *mut CCasUnion<T>,
-    cond: Arc<AtomicNumLikes>,
+    cond: *const AtomicNumLikes,
+    #[allow(dead_code)]
+    cond_owner: Option<Arc<AtomicNumLikes>>,
 }
 
 impl<T> CCasDesc<T> {
@@ -62,16 +77,41 @@ impl<T> CCasDesc<T> {
     ///
     /// * `desc_ptr`: Address of desc_ptr which was cas into inner at `CCasPtr::c_cas` function
     pub fn help(&self, desc_ptr: *mut CCasUnion<T>) {
-        let cond: Status = self.cond.get(Ordering::Relaxed);
+        let op = unsafe { &*self.op.get() };
+        let cond: Status = unsafe { (*op.cond).get(Ordering::SeqCst) };
         let success = cond == Status::Undecided;
-        self.inner.compare_and_swap(
+        unsafe { &*op.inner }.compare_and_swap(
             desc_ptr,
-            if success { self.new } else { self.expect },
+            if success { op.new } else { op.expect },
             Ordering::SeqCst,
         ); // TODO: set order carefully
     }
 }
 
+/// Pool marker of CCAS descriptors
+struct CCasKind;
+
+impl<T: 'static> Pooled<CCasKind> for CCasUnion<T> {
+    fn fresh() -> Self {
+        CCasUnion::CCasDesc(CCasDesc {
+            recycle: Recycle::new(),
+            op: UnsafeCell::new(CCasOp {
+                inner: std::ptr::null(),
+                expect: null_mut(),
+                new: null_mut(),
+                cond: std::ptr::null(),
+                cond_owner: None,
+            }),
+        })
+    }
+    fn recycle(&self) -> &Recycle {
+        match self {
+            CCasUnion::CCasDesc(c_cas_desc) => &c_cas_desc.recycle,
+            _ => unreachable!(),
+        }
+    }
+}
+
 /// Union Type of CCasDesc and True Value
 ///
 /// # Variants
@@ -91,6 +131,8 @@ impl<T> CCasUnion<T> {
             _ => unreachable!(),
         }
     }
+    /// Get the value of a `Value`. Use `CCasPtr::load_value` to read a location which may hold a
+    /// `CCasDesc`.
     pub fn load(&mut self) -> *mut T {
         let self_ptr = self as *mut Self;
         loop {
@@ -119,6 +161,57 @@ impl<T> Clone for CCasPtr<T> {
     }
 }
 
+impl<T: 'static> CCasPtr<T> {
+    pub fn c_cas(
+        &self,
+        expect: *mut CCasUnion<T>,
+        new: *mut CCasUnion<T>,
+        cond: Arc<AtomicNumLikes>,
+    ) {
+        let cond_ptr = &*cond as *const AtomicNumLikes;
+        self.c_cas_with(expect, new, cond_ptr, Some(cond));
+    }
+
+    /// CCAS whose condition is owned by the caller. `cond` must never be freed, which is the
+    /// case for the status of a pooled descriptor.
+    pub(crate) fn c_cas_with(
+        &self,
+        expect: *mut CCasUnion<T>,
+        new: *mut CCasUnion<T>,
+        cond: *const AtomicNumLikes,
+        cond_owner: Option<Arc<AtomicNumLikes>>,
+    ) {
+        let desc_ptr = pool::take::<CCasKind, CCasUnion<T>>();
+        let desc = match unsafe { &*desc_ptr } {
+            CCasUnion::CCasDesc(desc) => desc,
+            _ => unreachable!(),
+        };
+        unsafe {
+            *desc.op.get() = CCasOp {
+                inner: &*self.inner,
+                expect,
+                new,
+                cond,
+                cond_owner,
+            };
+        }
+
+        loop {
+            let res = self
+                .inner
+                .compare_and_swap(expect, desc_ptr, Ordering::SeqCst); // TODO: set order carefully
+            if std::ptr::eq(res, expect) {
+                desc.help(desc_ptr);
+                break;
+            } else if !self.help_c_cas(res) {
+                break; // TODO: mark failed
+            }
+            metrics::c_cas_retry();
+        }
+        pool::give_back::<CCasKind, CCasUnion<T>>(desc_ptr);
+    }
+}
+
 impl<T> CCasPtr<T> {
     pub fn from_value(val: T) -> CCasPtr<T> {
         CCasPtr::<T> {
@@ -130,38 +223,51 @@ impl<T> CCasPtr<T> {
             inner: Arc::new(AtomicPtr::new(union)),
         }
     }
-    pub fn c_cas(
-        &self,
-        expect: *mut CCasUnion<T>,
-        new: *mut CCasUnion<T>,
-        cond: Arc<AtomicNumLikes>,
-    ) {
-        let mut desc = CCasUnion::CCasDesc(CCasDesc::<T> {
-            inner: self.inner.clone(),
-            expect,
-            new,
-            cond: cond.clone(),
-        });
 
-        let expect_ptr = desc.borrow_mut_c_cas_desc().expect;
-        let desc_ptr = &mut desc as *mut CCasUnion<T>;
+    /// Help the CCAS descriptor `ptr`, which has been loaded from this location. Returns
+    /// `false` if `ptr` is a value.
+    pub(crate) fn help_c_cas(&self, ptr: *mut CCasUnion<T>) -> bool {
+        match unsafe { &*ptr } {
+            CCasUnion::CCasDesc(c_cas_desc) => {
+                if self.pin(&c_cas_desc.recycle, ptr) {
+                    c_cas_desc.help(ptr);
+                    c_cas_desc.recycle.unpin();
+                }
+                true
+            }
+            CCasUnion::Value(_) => false,
+        }
+    }
 
+    /// Pin a pooled descriptor which has been loaded from this location.
+    pub(crate) fn pin(&self, recycle: &Recycle, ptr: *mut CCasUnion<T>) -> bool {
+        recycle.pin(&self.inner, ptr)
+    }
+
+    /// Logical value of the location without helping: the expected value of a CCAS in
+    /// progress, which has not taken effect yet.
+    pub(crate) fn peek_value(&self) -> *mut CCasUnion<T> {
         loop {
-            unsafe {
-                let res = desc.borrow_mut_c_cas_desc().inner.compare_and_swap(
-                    expect_ptr,
-                    desc_ptr,
-                    Ordering::SeqCst,
-                ); // TODO: set order carefully
-                if std::ptr::eq(res, expect_ptr) {
-                    desc.borrow_mut_c_cas_desc().help(desc_ptr);
-                    break;
-                } else {
-                    match &*res {
-                        CCasUnion::CCasDesc(c_cas_desc) => c_cas_desc.help(desc_ptr),
-                        _ => return, // TODO: mark failed
+            let ptr = self.inner.load(Ordering::SeqCst);
+            match unsafe { &*ptr } {
+                CCasUnion::CCasDesc(c_cas_desc) => {
+                    if self.pin(&c_cas_desc.recycle, ptr) {
+                        let expect = unsafe { (*c_cas_desc.op.get()).expect };
+                        c_cas_desc.recycle.unpin();
+                        return expect;
                     }
                 }
+                CCasUnion::Value(_) => return ptr,
+            }
+        }
+    }
+
+    /// Load the location and help any CCAS in progress, so the result is always a `Value`.
+    pub fn load_value(&self) -> *mut CCasUnion<T> {
+        loop {
+            let ptr = self.inner.load(Ordering::SeqCst);
+            if !self.help_c_cas(ptr) {
+                return ptr;
             }
         }
     }
@@ -177,8 +283,10 @@ impl<T> CCasPtr<T> {
     ) -> *mut CCasUnion<T> {
         self.inner.compare_and_swap(current, new, order)
     }
+    /// Address of the shared location itself (not of the value it points to). Used to give
+    /// every location a stable total order and identity.
     pub fn get_addr(&self) -> u64 {
-        unsafe { self.inner.get_addr() }
+        Arc::as_ptr(&self.inner) as u64
     }
 }
 
@@ -242,7 +350,7 @@ mod test {
             let c_cas_ptr = c_cas_ptr.clone();
             thread::spawn(move || {
                 for _ in 0..ITER_NUM {
-                    let num = unsafe { *(*c_cas_ptr.load(Ordering::Relaxed)).load() };
+                    let num = unsafe { *(*c_cas_ptr.load_value()).load() };
                     assert!(num == 1 || num == 2);
                 }
             })
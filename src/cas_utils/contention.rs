@@ -0,0 +1,457 @@
+//! # Usage
+//!
+//! By default a MCAS which runs into another MCAS helps it to completion, and the helped MCAS
+//! may itself help a third one, and so on. Wrapping the entries in `Managed` lets a
+//! `ContentionManager` decide what to do on every such conflict instead.
+//!
+//! ```
+//! # use beee::cas_utils::m_cas::*;
+//! # use beee::epoch;
+//! # use beee::cas_utils::contention::{Karma, Managed};
+//! let atomic_num = AtomicMCasPtr::new(Box::new(MCasPtr::new(1)));
+//! let num1_ptr = atomic_num.load();
+//! let num2_ptr = Box::into_raw(Box::new(MCasPtr::new(2)));
+//!
+//! assert!(Managed(vec![SingleCas::new(&atomic_num, num1_ptr, num2_ptr)], Karma::default()).m_cas());
+//! assert_eq!(*atomic_num.read(&epoch::pin()), 2);
+//! ```
+//!
+//! A MCAS which is retried after a failure should keep its priority. `Managed::m_cas_as` runs
+//! the MCAS as the next attempt of an `Op`, which is created once per logical operation:
+//!
+//! ```
+//! # use beee::cas_utils::m_cas::*;
+//! # use beee::cas_utils::contention::{Managed, Op, Timestamp};
+//! let atomic_num = AtomicMCasPtr::new(Box::new(MCasPtr::new(1)));
+//! let op = Op::new();
+//! loop {
+//!     let cell = atomic_num.load();
+//!     let new = Box::into_raw(Box::new(MCasPtr::new(unsafe { *(*cell).get() } + 1)));
+//!     if Managed([SingleCas::new(&atomic_num, cell, new)], Timestamp::default()).m_cas_as(&op) {
+//!         break;
+//!     }
+//!     drop(unsafe { Box::from_raw(new) });
+//! }
+//! ```
+//!
+//! # Notes
+//!
+//! The policies are the ones of
+//! [Advanced Contention Management for Dynamic Software Transactional Memory](https://dl.acm.org/doi/10.1145/1073814.1073861).
+//! Only the owner of a MCAS consults its manager. A thread which helps the MCAS of another one
+//! always helps, so a policy never affects operations it does not own.
+
+use crate::cas_utils::c_cas::CCasUnion;
+use crate::cas_utils::m_cas::{self, MCas, MCasKind, MCasUnion, SingleCas};
+use crate::cas_utils::pool;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// What a MCAS does about another MCAS installed in a location it needs
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Decision {
+    /// Help the other MCAS to completion, then try again.
+    Help,
+    /// Wait, then try again.
+    Backoff(Duration),
+    /// Fail the other MCAS if it is still acquiring its locations, then try again. A MCAS of
+    /// `wf_m_cas` is helped instead, so that it keeps its bound.
+    AbortOther,
+    /// Fail this MCAS, it returns `false`.
+    AbortSelf,
+}
+
+/// Priority information of a MCAS
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct OpInfo {
+    /// Start order of the MCAS: a lower timestamp is an older operation.
+    pub timestamp: usize,
+    /// Work of the MCAS, i.e. the number of its entries.
+    pub karma: usize,
+}
+
+/// A conflict between the MCAS consulting the manager and another one
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Conflict {
+    /// Number of conflicts this MCAS ran into so far, this one included
+    pub attempt: usize,
+    pub own: OpInfo,
+    pub other: OpInfo,
+}
+
+pub trait ContentionManager {
+    fn on_conflict(&self, conflict: &Conflict) -> Decision;
+}
+
+/// Always help, the behavior of a plain MCAS.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AlwaysHelp;
+
+impl ContentionManager for AlwaysHelp {
+    fn on_conflict(&self, _: &Conflict) -> Decision {
+        Decision::Help
+    }
+}
+
+/// Back off exponentially, and abort the other MCAS once `max_attempts` backoffs did not help.
+#[derive(Debug, Clone, Copy)]
+pub struct Polite {
+    pub base: Duration,
+    pub max_attempts: usize,
+}
+
+impl Default for Polite {
+    fn default() -> Self {
+        Polite {
+            base: Duration::from_micros(1),
+            max_attempts: 8,
+        }
+    }
+}
+
+impl ContentionManager for Polite {
+    fn on_conflict(&self, conflict: &Conflict) -> Decision {
+        if conflict.attempt > self.max_attempts {
+            Decision::AbortOther
+        } else {
+            Decision::Backoff(exponential(self.base, conflict.attempt))
+        }
+    }
+}
+
+/// Abort the other MCAS if this one has done more work, counting every conflict as one unit of
+/// work. Otherwise back off.
+#[derive(Debug, Clone, Copy)]
+pub struct Karma {
+    pub base: Duration,
+}
+
+impl Default for Karma {
+    fn default() -> Self {
+        Karma {
+            base: Duration::from_micros(1),
+        }
+    }
+}
+
+impl ContentionManager for Karma {
+    fn on_conflict(&self, conflict: &Conflict) -> Decision {
+        if conflict.own.karma + conflict.attempt > conflict.other.karma {
+            Decision::AbortOther
+        } else {
+            Decision::Backoff(jitter(self.base))
+        }
+    }
+}
+
+/// Always abort the other MCAS. Two aggressive operations may abort each other forever.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Aggressive;
+
+impl ContentionManager for Aggressive {
+    fn on_conflict(&self, _: &Conflict) -> Decision {
+        Decision::AbortOther
+    }
+}
+
+/// The older MCAS wins: a younger one is aborted, an older one is waited for. After
+/// `max_attempts` backoffs the older one is helped instead, in case its thread is stalled.
+#[derive(Debug, Clone, Copy)]
+pub struct Timestamp {
+    pub base: Duration,
+    pub max_attempts: usize,
+}
+
+impl Default for Timestamp {
+    fn default() -> Self {
+        Timestamp {
+            base: Duration::from_micros(1),
+            max_attempts: 8,
+        }
+    }
+}
+
+impl ContentionManager for Timestamp {
+    fn on_conflict(&self, conflict: &Conflict) -> Decision {
+        if conflict.own.timestamp < conflict.other.timestamp {
+            Decision::AbortOther
+        } else if conflict.attempt > self.max_attempts {
+            Decision::Help
+        } else {
+            Decision::Backoff(exponential(self.base, conflict.attempt))
+        }
+    }
+}
+
+static CLOCK: AtomicUsize = AtomicUsize::new(0);
+
+/// Start timestamp of a new MCAS
+pub(crate) fn timestamp() -> usize {
+    CLOCK.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A logical operation, whose MCAS may be run several times
+///
+/// The timestamp is taken once, when the operation starts, and the karma of every attempt, its
+/// entries and its conflicts, adds up. An operation which keeps failing thus gets older and
+/// heavier, so `Timestamp` and `Karma` eventually let it win.
+#[derive(Debug)]
+pub struct Op {
+    info: Cell<OpInfo>,
+}
+
+impl Op {
+    pub fn new() -> Op {
+        Op {
+            info: Cell::new(OpInfo {
+                timestamp: timestamp(),
+                karma: 0,
+            }),
+        }
+    }
+
+    /// Priority of the operation, including the work of every attempt so far
+    pub fn info(&self) -> OpInfo {
+        self.info.get()
+    }
+}
+
+impl Default for Op {
+    fn default() -> Self {
+        Op::new()
+    }
+}
+
+/// `manager`, counting the conflicts of one attempt
+struct Counted<'a, C> {
+    manager: &'a C,
+    conflicts: Cell<usize>,
+}
+
+impl<C: ContentionManager> ContentionManager for Counted<'_, C> {
+    fn on_conflict(&self, conflict: &Conflict) -> Decision {
+        self.conflicts.set(conflict.attempt);
+        self.manager.on_conflict(conflict)
+    }
+}
+
+/// `base * 2^(attempt - 1)` with jitter, capped to avoid overflow
+fn exponential(base: Duration, attempt: usize) -> Duration {
+    jitter(base * (1 << (attempt.clamp(1, 16) - 1)))
+}
+
+thread_local! {
+    static SEED: Cell<u64> = Cell::new({
+        let local = 0u8;
+        (&local as *const u8 as u64) | 1
+    });
+}
+
+/// A random duration in `[d / 2, d]`
+fn jitter(d: Duration) -> Duration {
+    let random = SEED.with(|seed| {
+        // xorshift64
+        let mut x = seed.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        seed.set(x);
+        x
+    });
+    d / 2 + Duration::from_nanos(random % (d.as_nanos() as u64 / 2 + 1))
+}
+
+/// Wait for `d` without giving up the time slice for much longer than that.
+pub(crate) fn backoff(d: Duration) {
+    let until = Instant::now() + d;
+    while Instant::now() < until {
+        thread::yield_now();
+    }
+}
+
+/// Entries of a MCAS whose conflicts are resolved by a contention manager, e.g.
+/// `Managed(vec![...], Polite::default()).m_cas()`.
+pub struct Managed<M, C>(pub M, pub C);
+
+impl<M, C: ContentionManager> Managed<M, C> {
+    /// Run the MCAS as the next attempt of `op`, with its priority. `m_cas` is the first
+    /// attempt of a new operation.
+    pub fn m_cas_as<T: 'static>(&self, op: &Op) -> bool
+    where
+        M: AsRef<[SingleCas<T>]>,
+    {
+        let entries = self.0.as_ref();
+        let info = OpInfo {
+            timestamp: op.info().timestamp,
+            karma: op.info().karma + entries.len(),
+        };
+        let desc_ptr = m_cas::take_desc(|desc_entries| {
+            desc_entries.extend(entries.iter().cloned());
+            desc_entries.sort_unstable();
+        });
+        let desc = m_cas::desc_of(desc_ptr);
+        desc.set_info(info);
+        let manager = Counted {
+            manager: &self.1,
+            conflicts: Cell::new(0),
+        };
+        let success = desc.help_with(desc_ptr, &manager);
+        pool::give_back::<MCasKind, CCasUnion<MCasUnion<T>>>(desc_ptr);
+        op.info.set(OpInfo {
+            karma: info.karma + manager.conflicts.get(),
+            ..info
+        });
+        success
+    }
+}
+
+impl<T: 'static, M: AsRef<[SingleCas<T>]>, C: ContentionManager> MCas<T> for Managed<M, C> {
+    fn m_cas(&self) -> bool {
+        self.m_cas_as(&Op::new())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cas_utils::m_cas::{AtomicMCasPtr, MCasPtr};
+    use crate::cas_utils::Status;
+    use crate::epoch;
+
+    fn conflict(attempt: usize, own: (usize, usize), other: (usize, usize)) -> Conflict {
+        Conflict {
+            attempt,
+            own: OpInfo {
+                timestamp: own.0,
+                karma: own.1,
+            },
+            other: OpInfo {
+                timestamp: other.0,
+                karma: other.1,
+            },
+        }
+    }
+
+    #[test]
+    fn policies() {
+        let polite = Polite::default();
+        assert!(matches!(
+            polite.on_conflict(&conflict(1, (0, 2), (1, 2))),
+            Decision::Backoff(_)
+        ));
+        assert_eq!(
+            polite.on_conflict(&conflict(9, (0, 2), (1, 2))),
+            Decision::AbortOther
+        );
+
+        let karma = Karma::default();
+        assert!(matches!(
+            karma.on_conflict(&conflict(1, (0, 2), (1, 8))),
+            Decision::Backoff(_)
+        ));
+        assert_eq!(
+            karma.on_conflict(&conflict(7, (0, 2), (1, 8))),
+            Decision::AbortOther
+        );
+
+        let timestamp = Timestamp::default();
+        assert_eq!(
+            timestamp.on_conflict(&conflict(1, (0, 2), (1, 2))),
+            Decision::AbortOther
+        );
+        assert!(matches!(
+            timestamp.on_conflict(&conflict(1, (1, 2), (0, 2))),
+            Decision::Backoff(_)
+        ));
+        assert_eq!(
+            timestamp.on_conflict(&conflict(9, (1, 2), (0, 2))),
+            Decision::Help
+        );
+
+        for attempt in 1..40 {
+            let d = exponential(Duration::from_micros(1), attempt);
+            assert!(d >= Duration::from_nanos(500));
+            assert!(d <= Duration::from_micros(1 << 15));
+        }
+    }
+
+    fn multi_thread_increment<C: ContentionManager + Copy + Send + 'static>(manager: C) {
+        const THREAD_NUM: usize = 8;
+        const ITER_NUM: usize = 1000;
+
+        let locations: Vec<AtomicMCasPtr<usize>> = (0..3)
+            .map(|_| AtomicMCasPtr::new(Box::new(MCasPtr::new(0))))
+            .collect();
+        let threads: Vec<_> = (0..THREAD_NUM)
+            .map(|i| {
+                let window = [locations[i % 3].clone(), locations[(i + 1) % 3].clone()];
+                std::thread::spawn(move || {
+                    for _ in 0..ITER_NUM {
+                        loop {
+                            let entries: Vec<SingleCas<usize>> = window
+                                .iter()
+                                .map(|location| {
+                                    let cell = location.load();
+                                    let new = Box::leak(Box::new(MCasPtr::new(
+                                        unsafe { *(*cell).get() } + 1,
+                                    )));
+                                    SingleCas::new(location, cell, new)
+                                })
+                                .collect();
+                            if Managed(entries, manager).m_cas() {
+                                break;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        let total: usize = locations
+            .iter()
+            .map(|location| *location.read(&epoch::pin()))
+            .sum();
+        assert_eq!(total, 2 * THREAD_NUM * ITER_NUM);
+    }
+
+    #[test]
+    fn retried_op_wins_against_newer_ones() {
+        let location = AtomicMCasPtr::new(Box::new(MCasPtr::new(0)));
+        let op = Op::new();
+        // Every attempt of `op` runs into a MCAS which started after `op`, stalled in the
+        // location. `op` is older, so `Timestamp` aborts it.
+        let won = (0..8).any(|_| {
+            let cell = location.load();
+            let newer_new = Box::into_raw(Box::new(MCasPtr::new(-1)));
+            let newer = m_cas::take_desc(|entries| {
+                entries.push(SingleCas::new(&location, cell, newer_new))
+            });
+            assert!(m_cas::stall(newer, Status::Undecided));
+            let new = Box::into_raw(Box::new(MCasPtr::new(1)));
+            let won =
+                Managed([SingleCas::new(&location, cell, new)], Timestamp::default()).m_cas_as(&op);
+            assert!(!m_cas::desc_of(newer).help(newer));
+            pool::give_back::<MCasKind, CCasUnion<MCasUnion<i32>>>(newer);
+            drop(unsafe { Box::from_raw(newer_new) });
+            if !won {
+                drop(unsafe { Box::from_raw(new) });
+            }
+            won
+        });
+        assert!(won);
+        assert_eq!(*location.read(&epoch::pin()), 1);
+        assert!(op.info().karma >= 2);
+    }
+
+    #[test]
+    fn multi_thread_managed() {
+        multi_thread_increment(Polite::default());
+        multi_thread_increment(Karma::default());
+        multi_thread_increment(Aggressive);
+        multi_thread_increment(Timestamp::default());
+    }
+}
@@ -0,0 +1,212 @@
+//! # Usage
+//!
+//! `Until` bounds how long an operation may keep retrying: until a deadline, until a
+//! `CancellationToken` is cancelled, or both. The `try_*_until` operations return `Busy` (or
+//! give their argument back) once it has expired instead of spinning.
+//!
+//! ```
+//! # use beee::cas_utils::deadline::*;
+//! # use beee::cas_utils::m_cas::*;
+//! # use beee::epoch;
+//! # use std::time::{Duration, Instant};
+//! let atomic_num = AtomicMCasPtr::new(Box::new(MCasPtr::new(1)));
+//! let num1_ptr = atomic_num.load();
+//! let num2_ptr = Box::into_raw(Box::new(MCasPtr::new(2)));
+//!
+//! let until = Until::from(Instant::now() + Duration::from_millis(10));
+//! let res = try_m_cas_until(vec![SingleCas::new(&atomic_num, num1_ptr, num2_ptr)], &until);
+//! assert_eq!(res, Ok(true));
+//!
+//! let token = CancellationToken::new();
+//! token.cancel();
+//! let res = try_m_cas_until(vec![SingleCas::new(&atomic_num, num2_ptr, num1_ptr)], &token.into());
+//! assert_eq!(res, Err(Busy));
+//! assert_eq!(*atomic_num.read(&epoch::pin()), 2);
+//! ```
+//!
+//! # Notes
+//!
+//! A MCAS is only abandoned while it acquires its locations: on a conflict with another MCAS
+//! after the deadline, its status is CASed from `Undecided` to `Failed`. Once it has been
+//! decided it runs to completion, which takes a bounded number of steps.
+
+use crate::cas_utils::contention::{Conflict, ContentionManager, Decision, Managed};
+use crate::cas_utils::m_cas::{MCas, SingleCas};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// The operation gave up before it could take effect.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Busy;
+
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// When to give up. The default never expires.
+#[derive(Debug, Clone, Default)]
+pub struct Until {
+    deadline: Option<Instant>,
+    token: Option<CancellationToken>,
+}
+
+impl Until {
+    pub fn never() -> Until {
+        Until::default()
+    }
+    /// Also expire once `token` is cancelled.
+    pub fn or_cancelled(mut self, token: &CancellationToken) -> Until {
+        self.token = Some(token.clone());
+        self
+    }
+    pub fn expired(&self) -> bool {
+        self.token
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+            || self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+impl From<Instant> for Until {
+    fn from(deadline: Instant) -> Until {
+        Until {
+            deadline: Some(deadline),
+            token: None,
+        }
+    }
+}
+
+impl From<CancellationToken> for Until {
+    fn from(token: CancellationToken) -> Until {
+        Until {
+            deadline: None,
+            token: Some(token),
+        }
+    }
+}
+
+/// Help on conflicts until `until` expires, then abort.
+struct UntilManager<'a> {
+    until: &'a Until,
+    gave_up: Cell<bool>,
+}
+
+impl ContentionManager for UntilManager<'_> {
+    fn on_conflict(&self, _: &Conflict) -> Decision {
+        if self.until.expired() {
+            self.gave_up.set(true);
+            Decision::AbortSelf
+        } else {
+            Decision::Help
+        }
+    }
+}
+
+/// MCAS which gives up once `until` has expired. `Ok(false)` means the MCAS failed because a
+/// location did not hold its expected value, `Err(Busy)` that it did not finish in time.
+pub fn try_m_cas_until<T: 'static, M: AsRef<[SingleCas<T>]>>(
+    entries: M,
+    until: &Until,
+) -> Result<bool, Busy> {
+    if until.expired() {
+        return Err(Busy);
+    }
+    let manager = UntilManager {
+        until,
+        gave_up: Cell::new(false),
+    };
+    let managed = Managed(entries, manager);
+    let success = managed.m_cas();
+    if !success && managed.1.gave_up.get() {
+        Err(Busy)
+    } else {
+        Ok(success)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cas_utils::m_cas::{AtomicMCasPtr, MCasPtr};
+    use crate::epoch;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn expired_until() {
+        assert!(!Until::never().expired());
+        let past = Until::from(Instant::now());
+        assert!(past.expired());
+        let token = CancellationToken::new();
+        let until = Until::from(Instant::now() + Duration::from_secs(60)).or_cancelled(&token);
+        assert!(!until.expired());
+        token.cancel();
+        assert!(until.expired());
+
+        let atomic_num = AtomicMCasPtr::new(Box::new(MCasPtr::new(1)));
+        let num1_ptr = atomic_num.load();
+        let new = Box::leak(Box::new(MCasPtr::new(2)));
+        assert_eq!(
+            try_m_cas_until([SingleCas::new(&atomic_num, num1_ptr, new)], &past),
+            Err(Busy)
+        );
+        assert_eq!(*atomic_num.read(&epoch::pin()), 1);
+    }
+
+    #[test]
+    fn cancel_contended_m_cas() {
+        const THREAD_NUM: usize = 8;
+
+        let first = AtomicMCasPtr::new(Box::new(MCasPtr::new(0)));
+        let second = AtomicMCasPtr::new(Box::new(MCasPtr::new(0)));
+        let token = CancellationToken::new();
+        let threads: Vec<_> = (0..THREAD_NUM)
+            .map(|_| {
+                let (first, second) = (first.clone(), second.clone());
+                let until = Until::from(token.clone());
+                thread::spawn(move || {
+                    let mut done = 0;
+                    loop {
+                        let (first_ptr, second_ptr) = (first.load(), second.load());
+                        let (a, b) = unsafe { (*(*first_ptr).get(), *(*second_ptr).get()) };
+                        let new_first = Box::leak(Box::new(MCasPtr::new(a + 1)));
+                        let new_second = Box::leak(Box::new(MCasPtr::new(b + 1)));
+                        match try_m_cas_until(
+                            [
+                                SingleCas::new(&first, first_ptr, new_first),
+                                SingleCas::new(&second, second_ptr, new_second),
+                            ],
+                            &until,
+                        ) {
+                            Ok(true) => done += 1,
+                            Ok(false) => {}
+                            Err(Busy) => return done,
+                        }
+                    }
+                })
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(20));
+        token.cancel();
+        let done: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+        assert_eq!(*first.read(&epoch::pin()), done);
+        assert_eq!(*second.read(&epoch::pin()), done);
+    }
+}
@@ -0,0 +1,462 @@
+//! # Usage
+//!
+//! A MCAS backend which needs `k + 1` CAS for `k` words when there is no contention. The API
+//! mirrors `m_cas`: values live in `KCasPtr` cells, locations are `AtomicKCasPtr` and a `Vec`
+//! or array of `SingleKCas` implements `MCas`.
+//!
+//! ```
+//! # use beee::cas_utils::k_cas::*;
+//! # use beee::cas_utils::m_cas::MCas;
+//! # use beee::epoch;
+//! let atomic_num = AtomicKCasPtr::new(Box::new(KCasPtr::new(1)));
+//! let num1_ptr = atomic_num.load();
+//! let num2_ptr = Box::into_raw(Box::new(KCasPtr::new(2)));
+//!
+//! assert!(vec![SingleKCas::new(&atomic_num, num1_ptr, num2_ptr)].m_cas());
+//! assert_eq!(*atomic_num.read(&epoch::pin()), 2);
+//! ```
+//!
+//! # Notes
+//!
+//! The algorithm is described in [Efficient Multi-Word Compare and Swap](https://arxiv.org/abs/2008.02527).
+//! A location always points to a word descriptor, and its value is the new or the old cell of
+//! that word depending on the status of the descriptor it belongs to. Descriptors are not
+//! released from locations: they stay installed until another MCAS replaces them or the
+//! location is dropped. Cells replaced by a successful MCAS are freed through
+//! `KCasPtr::retire`, like the cells of `m_cas`.
+//!
+//! Descriptors are reused through pin counts, which cost more than the CAS: installing a word
+//! also pins the descriptor it reads, holds its own one for the location and unpins the
+//! replaced one twice. A MCAS without contention thus takes `k + 1` CAS and `4k` other atomic
+//! read-modify-writes. A descriptor stays pinned while a location holds one of its words, so
+//! the pool of a thread keeps about one descriptor per location it was the last to write, and
+//! taking a descriptor scans them.
+
+use crate::cas_utils::m_cas::MCas;
+use crate::cas_utils::pool::{self, Pooled, Recycle};
+use crate::cas_utils::Status;
+use crate::epoch::{self, Guard};
+use crate::utils::{AtomicNumLikes, AtomicNumLikesMethods};
+use std::cell::UnsafeCell;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::Arc;
+
+/// A word of a `KCasDesc`. `parent` never changes, so it can be read before the descriptor is
+/// pinned.
+struct WordDesc<T> {
+    parent: *const KCasDesc<T>,
+    op: UnsafeCell<WordOp<T>>,
+}
+
+/// # Fields
+///
+/// * `origin`: The location. `None` only for the initial word of a location.
+/// * `expect`: Expected cell
+/// * `new`: New cell
+struct WordOp<T> {
+    origin: Option<Arc<AtomicPtr<WordDesc<T>>>>,
+    expect: *mut KCasPtr<T>,
+    new: *mut KCasPtr<T>,
+}
+
+/// A KCas Descriptor
+///
+/// Descriptors are pooled per thread. Every location a word of the descriptor is installed in
+/// holds a pin on it, so it is only reused once all of its words have been replaced.
+///
+/// # Fields
+///
+/// * `words`: Word descriptors sorted by location address. Buffers replaced when they grow are
+///   retired through `epoch`, because a stale word pointer is dereferenced before pinning.
+///   Every thread reading a location is pinned.
+/// * `len`: Number of words used by the current incarnation
+/// * `status`: Decision of this MCAS
+pub struct KCasDesc<T> {
+    recycle: Recycle,
+    words: UnsafeCell<*mut [WordDesc<T>]>,
+    len: UnsafeCell<usize>,
+    status: AtomicNumLikes,
+}
+
+/// Pool marker of KCAS descriptors
+struct KCasKind;
+
+impl<T: 'static> Pooled<KCasKind> for KCasDesc<T> {
+    fn fresh() -> Self {
+        KCasDesc {
+            recycle: Recycle::new(),
+            words: UnsafeCell::new(Box::into_raw(Box::new([]))),
+            len: UnsafeCell::new(0),
+            status: AtomicNumLikes::new(Status::Undecided),
+        }
+    }
+    fn recycle(&self) -> &Recycle {
+        &self.recycle
+    }
+}
+
+impl<T: 'static> KCasDesc<T> {
+    /// Make room for `len` words.
+    ///
+    /// # Safety
+    ///
+    /// The descriptor must be exclusively owned, i.e. freshly taken from the pool.
+    unsafe fn reserve(&self, len: usize, guard: &Guard) {
+        let words = &mut *self.words.get();
+        if (*words).len() < len {
+            let old = *words;
+            guard.defer_unchecked(move || drop(Box::from_raw(old)));
+            *words = Box::into_raw(
+                (0..len)
+                    .map(|_| WordDesc {
+                        parent: self,
+                        op: UnsafeCell::new(WordOp {
+                            origin: None,
+                            expect: null_mut(),
+                            new: null_mut(),
+                        }),
+                    })
+                    .collect::<Vec<_>>()
+                    .into_boxed_slice(),
+            );
+        }
+        *self.len.get() = len;
+    }
+}
+
+impl<T> KCasDesc<T> {
+    fn words(&self) -> &[WordDesc<T>] {
+        unsafe { &(&**self.words.get())[..*self.len.get()] }
+    }
+
+    /// Cell which is the value of `word` for everyone but its own descriptor
+    fn value(&self, word: &WordDesc<T>) -> *mut KCasPtr<T> {
+        let op = unsafe { &*word.op.get() };
+        let status: Status = self.status.get(Ordering::SeqCst);
+        if status == Status::Successful {
+            op.new
+        } else {
+            op.expect
+        }
+    }
+
+    fn help(&self) -> bool {
+        let mut success = true;
+        'iter: for word in self.words() {
+            let op = unsafe { &*word.op.get() };
+            let origin = op.origin.as_ref().unwrap();
+            let word_ptr = word as *const WordDesc<T> as *mut WordDesc<T>;
+            'retry: loop {
+                let status: Status = self.status.get(Ordering::SeqCst);
+                if status != Status::Undecided {
+                    break 'iter;
+                }
+
+                let (content, cell) = read_internal(origin, self);
+                let content_parent = unsafe { &*(*content).parent };
+                if std::ptr::eq(content, word_ptr) {
+                    content_parent.recycle.unpin();
+                    break 'retry;
+                }
+                if !std::ptr::eq(cell, op.expect) {
+                    content_parent.recycle.unpin();
+                    success = false;
+                    break 'iter;
+                }
+
+                // The location pins the descriptor of the word it holds.
+                self.recycle.hold();
+                let installed = origin
+                    .compare_exchange(content, word_ptr, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok();
+                if installed {
+                    content_parent.recycle.unpin();
+                } else {
+                    self.recycle.unpin();
+                }
+                content_parent.recycle.unpin();
+                if installed {
+                    break 'retry;
+                }
+            }
+        }
+
+        self.status.compare_and_swap(
+            Status::Undecided,
+            if success {
+                Status::Successful
+            } else {
+                Status::Failed
+            },
+            Ordering::SeqCst,
+        );
+        let status: Status = self.status.get(Ordering::SeqCst);
+        status == Status::Successful
+    }
+}
+
+/// Read the word installed in `origin`, helping any other MCAS in progress. Returns the word and
+/// its value; the descriptor of the word is pinned and the caller must unpin it. The thread
+/// must be pinned, see `KCasDesc::words`.
+fn read_internal<T>(
+    origin: &AtomicPtr<WordDesc<T>>,
+    own: *const KCasDesc<T>,
+) -> (*mut WordDesc<T>, *mut KCasPtr<T>) {
+    loop {
+        let word = origin.load(Ordering::SeqCst);
+        let parent = unsafe { &*(*word).parent };
+        if !parent.recycle.pin(origin, word) {
+            continue;
+        }
+        let status: Status = parent.status.get(Ordering::SeqCst);
+        if !std::ptr::eq(parent, own) && status == Status::Undecided {
+            parent.help();
+            parent.recycle.unpin();
+            continue;
+        }
+        return (word, parent.value(unsafe { &*word }));
+    }
+}
+
+/// Run a MCAS with a descriptor from the pool of this thread.
+fn k_cas<T: 'static>(entries: &[SingleKCas<T>]) -> bool {
+    let guard = epoch::pin();
+    let desc_ptr = pool::take::<KCasKind, KCasDesc<T>>();
+    let desc = unsafe { &*desc_ptr };
+    unsafe { desc.reserve(entries.len(), &guard) };
+
+    // Stale helpers may still read `parent` of these words, so they are only written through
+    // their `UnsafeCell`.
+    let words = desc.words();
+    for (word, entry) in words.iter().zip(entries) {
+        unsafe {
+            *word.op.get() = WordOp {
+                origin: Some(entry.origin.inner.0.clone()),
+                expect: entry.expect,
+                new: entry.new,
+            };
+        }
+    }
+    // Insertion sort by location address: a KCAS has only a few words and this needs no buffer.
+    let addr =
+        |word: &WordDesc<T>| unsafe { Arc::as_ptr((*word.op.get()).origin.as_ref().unwrap()) };
+    for i in 1..words.len() {
+        let mut j = i;
+        while j > 0 && addr(&words[j - 1]) > addr(&words[j]) {
+            unsafe { std::ptr::swap(words[j - 1].op.get(), words[j].op.get()) };
+            j -= 1;
+        }
+    }
+    desc.status.set(Status::Undecided, Ordering::SeqCst);
+
+    let success = desc.help();
+    pool::give_back::<KCasKind, KCasDesc<T>>(desc_ptr);
+    success
+}
+
+/// One entry of a KCAS
+pub struct SingleKCas<T> {
+    origin: AtomicKCasPtr<T>,
+    expect: *mut KCasPtr<T>,
+    new: *mut KCasPtr<T>,
+}
+
+impl<T> SingleKCas<T> {
+    pub fn new(
+        origin: &AtomicKCasPtr<T>,
+        expect: *mut KCasPtr<T>,
+        new: *mut KCasPtr<T>,
+    ) -> SingleKCas<T> {
+        SingleKCas {
+            origin: origin.clone(),
+            expect,
+            new,
+        }
+    }
+}
+
+impl<T> Clone for SingleKCas<T> {
+    fn clone(&self) -> Self {
+        SingleKCas {
+            origin: self.origin.clone(),
+            expect: self.expect,
+            new: self.new,
+        }
+    }
+}
+
+impl<T: 'static> MCas<T> for [SingleKCas<T>] {
+    fn m_cas(&self) -> bool {
+        k_cas(self)
+    }
+}
+
+impl<T: 'static> MCas<T> for Vec<SingleKCas<T>> {
+    fn m_cas(&self) -> bool {
+        self.as_slice().m_cas()
+    }
+}
+
+impl<T: 'static, const N: usize> MCas<T> for [SingleKCas<T>; N] {
+    fn m_cas(&self) -> bool {
+        self[..].m_cas()
+    }
+}
+
+/// The location of a `AtomicKCasPtr`, shared by its clones
+///
+/// The words installed in it refer to the inner pointer too, so dropping the last handle
+/// releases the descriptor of the current word. Once that descriptor is reused, nothing refers
+/// to the inner pointer any more.
+struct Location<T>(Arc<AtomicPtr<WordDesc<T>>>);
+
+impl<T> Drop for Location<T> {
+    fn drop(&mut self) {
+        let word = self.0.load(Ordering::SeqCst);
+        unsafe { (*(*word).parent).recycle.unpin() };
+    }
+}
+
+pub struct AtomicKCasPtr<T> {
+    inner: Arc<Location<T>>,
+}
+
+impl<T> Clone for AtomicKCasPtr<T> {
+    fn clone(&self) -> Self {
+        AtomicKCasPtr {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: 'static> AtomicKCasPtr<T> {
+    /// A location holding `cell`, which it never frees. It initially holds the only word of an
+    /// already successful descriptor.
+    pub fn new(cell: Box<KCasPtr<T>>) -> Self {
+        unsafe { AtomicKCasPtr::from_raw(Box::into_raw(cell)) }
+    }
+
+    /// A location holding `cell`, which may also be held by other locations.
+    ///
+    /// # Safety
+    ///
+    /// `cell` must come from `Box::into_raw`, and must not be freed while it is installed in
+    /// this location, nor afterwards except through `KCasPtr::retire`.
+    pub unsafe fn from_raw(cell: *mut KCasPtr<T>) -> Self {
+        let guard = epoch::pin();
+        let desc_ptr = pool::take::<KCasKind, KCasDesc<T>>();
+        let desc = &*desc_ptr;
+        desc.reserve(1, &guard);
+        *desc.words()[0].op.get() = WordOp {
+            origin: None,
+            expect: null_mut(),
+            new: cell,
+        };
+        desc.status.set(Status::Successful, Ordering::SeqCst);
+        // The location pins the descriptor of the word it holds.
+        desc.recycle.hold();
+
+        let word = &desc.words()[0] as *const WordDesc<T> as *mut WordDesc<T>;
+        pool::give_back::<KCasKind, KCasDesc<T>>(desc_ptr);
+        AtomicKCasPtr {
+            inner: Arc::new(Location(Arc::new(AtomicPtr::new(word)))),
+        }
+    }
+
+    /// The value cell currently stored in the location. Operations in progress are helped to
+    /// completion first.
+    pub fn load(&self) -> *mut KCasPtr<T> {
+        let _guard = epoch::pin();
+        let (word, cell) = read_internal(&self.inner.0, std::ptr::null());
+        unsafe { (*(*word).parent).recycle.unpin() };
+        cell
+    }
+
+    /// The current value. It stays valid while `guard` is alive, as long as cells replaced in
+    /// this location are freed through `KCasPtr::retire`.
+    pub fn read<'g>(&self, _guard: &'g Guard) -> &'g T {
+        unsafe { (*self.load()).get() }
+    }
+}
+
+pub struct KCasPtr<T> {
+    val: T,
+}
+
+impl<T> KCasPtr<T> {
+    pub fn new(val: T) -> KCasPtr<T> {
+        KCasPtr { val }
+    }
+    pub fn get(&self) -> &T {
+        &self.val
+    }
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.val
+    }
+}
+
+impl<T: 'static> KCasPtr<T> {
+    /// Free a cell which a successful MCAS replaced, once no thread pinned now can read it.
+    ///
+    /// # Safety
+    ///
+    /// `cell` must come from `Box::into_raw`, be installed in no location any more, and be
+    /// freed only once. `T` may be dropped on any thread.
+    pub unsafe fn retire(cell: *mut KCasPtr<T>, guard: &Guard) {
+        guard.defer_destroy(cell);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::epoch;
+
+    #[test]
+    fn single_thread_k_cas() {
+        let ptrs: Vec<*mut KCasPtr<i32>> = (0..6)
+            .map(|val| Box::into_raw(Box::new(KCasPtr::new(val))))
+            .collect();
+        let atomic: Vec<AtomicKCasPtr<i32>> = ptrs[..3]
+            .iter()
+            .map(|&ptr| unsafe { AtomicKCasPtr::from_raw(ptr) })
+            .collect();
+
+        let m_cas = vec![
+            SingleKCas::new(&atomic[2], ptrs[2], ptrs[5]),
+            SingleKCas::new(&atomic[0], ptrs[3], ptrs[4]),
+        ];
+        assert!(!m_cas.m_cas());
+        assert_eq!(*atomic[0].read(&epoch::pin()), 0);
+        assert_eq!(*atomic[2].read(&epoch::pin()), 2);
+
+        let m_cas = [
+            SingleKCas::new(&atomic[2], ptrs[2], ptrs[5]),
+            SingleKCas::new(&atomic[1], ptrs[1], ptrs[4]),
+            SingleKCas::new(&atomic[0], ptrs[0], ptrs[3]),
+        ];
+        assert!(m_cas.m_cas());
+        assert_eq!(*atomic[0].read(&epoch::pin()), 3);
+        assert_eq!(*atomic[1].read(&epoch::pin()), 4);
+        assert_eq!(*atomic[2].read(&epoch::pin()), 5);
+    }
+
+    #[test]
+    fn dropped_location_releases_its_descriptor() {
+        let first = Box::into_raw(Box::new(KCasPtr::new(0)));
+        let location = unsafe { AtomicKCasPtr::from_raw(first) };
+        let second = Box::into_raw(Box::new(KCasPtr::new(1)));
+        assert!([SingleKCas::new(&location, first, second)].m_cas());
+        let inner = Arc::downgrade(&location.inner.0);
+        drop(location);
+        assert!(inner.upgrade().is_some());
+
+        // Reusing the descriptors of this thread drops the word which referred to the location.
+        let other = AtomicKCasPtr::new(Box::new(KCasPtr::new(0)));
+        let cell = other.load();
+        let new = Box::into_raw(Box::new(KCasPtr::new(1)));
+        assert!([SingleKCas::new(&other, cell, new)].m_cas());
+        assert!(inner.upgrade().is_none());
+    }
+}
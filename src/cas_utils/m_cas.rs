@@ -1,64 +1,295 @@
 use crate::cas_utils::c_cas::{CCasPtr, CCasUnion};
+use crate::cas_utils::contention::{
+    self, AlwaysHelp, Conflict, ContentionManager, Decision, OpInfo,
+};
+use crate::cas_utils::metrics::{self, FailureCause};
+use crate::cas_utils::pool::{self, Pooled, Recycle};
 use crate::cas_utils::Status;
+use crate::epoch::{self, Guard};
 use crate::utils::{AtomicNumLikes, AtomicNumLikesMethods};
-use std::sync::atomic::Ordering;
-use std::sync::Arc;
+use std::cell::UnsafeCell;
+use std::mem::ManuallyDrop;
+use std::sync::atomic::{AtomicBool, Ordering};
 
+/// A MCas Descriptor
+///
+/// Descriptors are pooled per thread like `CCasDesc`.
+///
+/// # Fields
+///
+/// * `inner`: Entries sorted by location address. Only rewritten when the descriptor is reused.
+/// * `info`: Priority of this MCAS for contention managers, rewritten with `inner`
+/// * `wait_free`: Whether this is a MCAS of `wf_m_cas`, which the contention managers of other
+///   MCASes may not abort. Rewritten with `inner`.
+/// * `aborted`: Set before a wait-free MCAS is aborted by another wait-free one in `check_read`,
+///   so that its owner runs it again. Rewritten with `inner`.
+/// * `status`: Decision of this MCAS
 pub struct MCasDesc<T> {
-    inner: Arc<Vec<SingleCas<T>>>,
-    status: Arc<AtomicNumLikes>,
+    recycle: Recycle,
+    inner: UnsafeCell<Vec<SingleCas<T>>>,
+    info: UnsafeCell<OpInfo>,
+    wait_free: AtomicBool,
+    aborted: AtomicBool,
+    status: AtomicNumLikes,
 }
 
-impl<T> MCasDesc<T> {
-    fn help(&self, desc_ptr: *mut CCasUnion<MCasUnion<T>>) -> bool {
-        'iter: for (index, item) in self.inner.iter().enumerate() {
-            'retry: loop {
-                item.origin.c_cas(item.expect, desc_ptr, self.status.clone());
-                unsafe {
-                    let c_cas_ptr = item.origin.load(Ordering::Relaxed);
-                    if std::ptr::eq(c_cas_ptr, desc_ptr) {
-                        break 'retry;
-                    } else {
-                        match &mut *c_cas_ptr {
-                            CCasUnion::Value(v) => match v {
-                                MCasUnion::MCasDesc(v) => {
-                                    v.help(c_cas_ptr);
-                                }
-                                _ => {
-                                    self.status.compare_and_swap(
-                                        Status::Undecided,
-                                        Status::Failed,
-                                        Ordering::SeqCst,
-                                    );
-                                    break 'iter;
-                                }
-                            },
-                            CCasUnion::CCasDesc(c_desc) => {
-                                c_desc.help(c_cas_ptr);
-                            }
-                        }
-                    }
-                }
-            }
-            if index == self.inner.len() - 1 {
-                self.status.compare_and_swap(
-                    Status::Undecided,
-                    Status::Successful,
-                    Ordering::SeqCst,
-                );
+impl<T: 'static> MCasDesc<T> {
+    fn entries(&self) -> &[SingleCas<T>] {
+        unsafe { &*self.inner.get() }
+    }
+
+    fn info(&self) -> OpInfo {
+        unsafe { *self.info.get() }
+    }
+
+    /// Protect this MCAS from `Decision::AbortOther`: an abort would make it fail although its
+    /// locations match, and its caller retry without bound. Must be called before the
+    /// descriptor is installed anywhere.
+    pub(crate) fn set_wait_free(&self) {
+        self.wait_free.store(true, Ordering::SeqCst);
+    }
+
+    /// Set the priority of this MCAS, see `contention::Op`. Must be called before the
+    /// descriptor is installed anywhere.
+    pub(crate) fn set_info(&self, info: OpInfo) {
+        unsafe { *self.info.get() = info };
+    }
+
+    /// Whether a wait-free MCAS may have failed only because another wait-free MCAS aborted it.
+    /// Running it again is harmless, a failed attempt does not change anything.
+    pub(crate) fn was_aborted(&self) -> bool {
+        self.aborted.load(Ordering::SeqCst)
+    }
+
+    /// CAS the status from `current` to `Failed`, counting the failure if this decided it.
+    fn fail(&self, current: Status, cause: FailureCause) {
+        if self
+            .status
+            .compare_and_swap(current, Status::Failed, Ordering::SeqCst)
+            == current
+        {
+            metrics::m_cas_failure(cause);
+        }
+    }
+
+    /// CAS the status from `current` to `Successful`, counting the success if this decided it.
+    fn succeed(&self, current: Status) {
+        if self
+            .status
+            .compare_and_swap(current, Status::Successful, Ordering::SeqCst)
+            == current
+        {
+            metrics::m_cas_success();
+        }
+    }
+
+    pub(crate) fn help(&self, desc_ptr: *mut CCasUnion<MCasUnion<T>>) -> bool {
+        self.help_with(desc_ptr, &AlwaysHelp)
+    }
+
+    /// Help the MCAS to completion. `manager` is consulted every time acquiring a location runs
+    /// into another MCAS. Only the owner of the descriptor may pass a manager other than
+    /// `AlwaysHelp`.
+    pub(crate) fn help_with(
+        &self,
+        desc_ptr: *mut CCasUnion<MCasUnion<T>>,
+        manager: &dyn ContentionManager,
+    ) -> bool {
+        let _scope = metrics::help(desc_ptr as *const ());
+        let status: Status = self.status.get(Ordering::SeqCst);
+        if status == Status::Undecided {
+            self.acquire(desc_ptr, manager);
+        }
+
+        let status: Status = self.status.get(Ordering::SeqCst);
+        if status == Status::ReadChecking {
+            let valid = self
+                .entries()
+                .iter()
+                .filter(|item| item.compare_only)
+                .all(|item| self.check_read(item));
+            if valid {
+                self.succeed(Status::ReadChecking);
+            } else {
+                self.fail(Status::ReadChecking, FailureCause::ReadCheck);
             }
         }
 
-        let cond: Status = self.status.get(Ordering::Relaxed);
+        let cond: Status = self.status.get(Ordering::SeqCst);
         let success = cond == Status::Successful;
-        for item in self.inner.iter() {
+        for item in self.entries().iter().filter(|item| !item.compare_only) {
             item.origin.compare_and_swap(
                 desc_ptr,
                 if success { item.new } else { item.expect },
                 Ordering::SeqCst,
             );
         }
-        return success;
+        success
+    }
+
+    /// Help the descriptor of another MCAS, which has been loaded from `origin`.
+    fn help_other(
+        origin: &CCasPtr<MCasUnion<T>>,
+        other_ptr: *mut CCasUnion<MCasUnion<T>>,
+        other: &MCasDesc<T>,
+    ) {
+        if origin.pin(&other.recycle, other_ptr) {
+            other.help(other_ptr);
+            other.recycle.unpin();
+        }
+    }
+
+    /// Install `desc_ptr` into every location that is written. Compare-only entries are left
+    /// untouched here and validated later by `check_read`.
+    fn acquire(&self, desc_ptr: *mut CCasUnion<MCasUnion<T>>, manager: &dyn ContentionManager) {
+        let mut attempt = 0;
+        'iter: for item in self.entries().iter().filter(|item| !item.compare_only) {
+            'retry: loop {
+                let status: Status = self.status.get(Ordering::SeqCst);
+                if status != Status::Undecided {
+                    break 'iter;
+                }
+                item.origin
+                    .c_cas_with(item.expect, desc_ptr, &self.status, None);
+                let c_cas_ptr = item.origin.load(Ordering::SeqCst);
+                if std::ptr::eq(c_cas_ptr, desc_ptr) {
+                    break 'retry;
+                }
+                match unsafe { &*c_cas_ptr } {
+                    CCasUnion::Value(MCasUnion::MCasDesc(other)) => {
+                        if !item.origin.pin(&other.recycle, c_cas_ptr) {
+                            continue;
+                        }
+                        attempt += 1;
+                        let decision = manager.on_conflict(&Conflict {
+                            attempt,
+                            own: self.info(),
+                            other: other.info(),
+                        });
+                        match decision {
+                            Decision::AbortOther if !other.wait_free.load(Ordering::SeqCst) => {
+                                other.fail(Status::Undecided, FailureCause::Aborted);
+                                other.help(c_cas_ptr);
+                            }
+                            Decision::Help | Decision::AbortOther => {
+                                other.help(c_cas_ptr);
+                            }
+                            Decision::Backoff(_) | Decision::AbortSelf => {}
+                        }
+                        other.recycle.unpin();
+                        match decision {
+                            Decision::Backoff(duration) => contention::backoff(duration),
+                            Decision::AbortSelf => {
+                                self.fail(Status::Undecided, FailureCause::Aborted);
+                                break 'iter;
+                            }
+                            _ => {}
+                        }
+                    }
+                    CCasUnion::Value(MCasUnion::Value(_)) => {
+                        if !std::ptr::eq(c_cas_ptr, item.expect) {
+                            self.fail(Status::Undecided, FailureCause::Mismatch);
+                            break 'iter;
+                        }
+                    }
+                    CCasUnion::CCasDesc(_) => {
+                        item.origin.help_c_cas(c_cas_ptr);
+                    }
+                }
+            }
+        }
+
+        let has_reads = self.entries().iter().any(|item| item.compare_only);
+        if has_reads {
+            self.status
+                .compare_and_swap(Status::Undecided, Status::ReadChecking, Ordering::SeqCst);
+        } else {
+            self.succeed(Status::Undecided);
+        }
+    }
+
+    /// Check that a compare-only location still holds its expected value.
+    ///
+    /// This runs after every written location has been acquired, so the operation is linearized
+    /// at the moment its status became `ReadChecking`. If the location is owned by another MCAS
+    /// which is still acquiring, that operation will be linearized after us and its expected
+    /// value is the logical one. Two operations that are both checking reads are ordered by
+    /// descriptor address: the lower one is helped, the higher one is aborted, so helping can
+    /// never form a cycle. A wait-free MCAS is not aborted by another MCAS, which fails itself
+    /// instead. Between two wait-free ones the higher is aborted and its owner runs it again.
+    fn check_read(&self, item: &SingleCas<T>) -> bool {
+        loop {
+            let c_cas_ptr = item.origin.load(Ordering::SeqCst);
+            if std::ptr::eq(c_cas_ptr, item.expect) {
+                return true;
+            }
+            match unsafe { &*c_cas_ptr } {
+                CCasUnion::Value(MCasUnion::MCasDesc(other)) => {
+                    if !item.origin.pin(&other.recycle, c_cas_ptr) {
+                        continue;
+                    }
+                    let other_status: Status = other.status.get(Ordering::SeqCst);
+                    let valid = match other_status {
+                        Status::Undecided => Some(
+                            other
+                                .entries()
+                                .iter()
+                                .find(|other_item| other_item == &item)
+                                .is_some_and(|other_item| {
+                                    std::ptr::eq(other_item.expect, item.expect)
+                                }),
+                        ),
+                        Status::ReadChecking if (other as *const Self) > (self as *const Self) => {
+                            if !other.wait_free.load(Ordering::SeqCst) {
+                                other.fail(Status::ReadChecking, FailureCause::Aborted);
+                                None
+                            } else if !self.wait_free.load(Ordering::SeqCst) {
+                                self.fail(Status::ReadChecking, FailureCause::Aborted);
+                                Some(false)
+                            } else {
+                                other.aborted.store(true, Ordering::SeqCst);
+                                other.fail(Status::ReadChecking, FailureCause::Aborted);
+                                None
+                            }
+                        }
+                        _ => {
+                            other.help(c_cas_ptr);
+                            None
+                        }
+                    };
+                    other.recycle.unpin();
+                    if let Some(valid) = valid {
+                        return valid;
+                    }
+                }
+                CCasUnion::Value(MCasUnion::Value(_)) => return false,
+                CCasUnion::CCasDesc(_) => {
+                    item.origin.help_c_cas(c_cas_ptr);
+                }
+            }
+        }
+    }
+}
+
+/// Pool marker of MCAS descriptors
+pub(crate) struct MCasKind;
+
+impl<T: 'static> Pooled<MCasKind> for CCasUnion<MCasUnion<T>> {
+    fn fresh() -> Self {
+        CCasUnion::Value(MCasUnion::MCasDesc(MCasDesc {
+            recycle: Recycle::new(),
+            inner: UnsafeCell::new(Vec::new()),
+            info: UnsafeCell::new(OpInfo::default()),
+            wait_free: AtomicBool::new(false),
+            aborted: AtomicBool::new(false),
+            status: AtomicNumLikes::new(Status::Undecided),
+        }))
+    }
+    fn recycle(&self) -> &Recycle {
+        match self {
+            CCasUnion::Value(MCasUnion::MCasDesc(desc)) => &desc.recycle,
+            _ => unreachable!(),
+        }
     }
 }
 
@@ -71,10 +302,16 @@ pub trait MCas<T> {
     fn m_cas(&self) -> bool;
 }
 
+/// One entry of a MCAS.
+///
+/// An entry created by `SingleCas::compare` is compare-only: its location is never written and
+/// never holds the descriptor, it is only checked to still hold `expect` when the MCAS decides.
+/// Every location may appear at most once in a MCAS.
 pub struct SingleCas<T> {
     origin: CCasPtr<MCasUnion<T>>,
     expect: *mut CCasUnion<MCasUnion<T>>,
     new: *mut CCasUnion<MCasUnion<T>>,
+    compare_only: bool,
 }
 impl<T> SingleCas<T> {
     pub fn new(
@@ -86,15 +323,28 @@ impl<T> SingleCas<T> {
             origin: origin.inner.clone(),
             expect: expect as *mut CCasUnion<MCasUnion<T>>,
             new: new as *mut CCasUnion<MCasUnion<T>>,
+            compare_only: false,
+        }
+    }
+    pub fn compare(origin: &AtomicMCasPtr<T>, expect: *mut MCasPtr<T>) -> SingleCas<T> {
+        Self {
+            origin: origin.inner.clone(),
+            expect: expect as *mut CCasUnion<MCasUnion<T>>,
+            new: expect as *mut CCasUnion<MCasUnion<T>>,
+            compare_only: true,
         }
     }
+    pub fn is_compare_only(&self) -> bool {
+        self.compare_only
+    }
 }
 impl<T> Clone for SingleCas<T> {
     fn clone(&self) -> Self {
         SingleCas::<T> {
             origin: self.origin.clone(),
-            expect: self.expect.clone(),
-            new: self.new.clone(),
+            expect: self.expect,
+            new: self.new,
+            compare_only: self.compare_only,
         }
     }
 }
@@ -119,23 +369,232 @@ impl<T> PartialEq for SingleCas<T> {
 
 impl<T> Eq for SingleCas<T> {}
 
-impl<T> MCas<T> for Vec<SingleCas<T>> {
+/// Run a MCAS with a descriptor from the pool of this thread. `fill` writes the entries into the
+/// (empty) entry buffer of the descriptor and must leave them sorted by location address. Once
+/// the buffers of the pool are large enough this does not allocate.
+fn m_cas_with<T: 'static>(fill: impl FnOnce(&mut Vec<SingleCas<T>>)) -> bool {
+    let desc_ptr = take_desc(fill);
+    let success = desc_of(desc_ptr).help(desc_ptr);
+    pool::give_back::<MCasKind, CCasUnion<MCasUnion<T>>>(desc_ptr);
+    success
+}
+
+/// Take a descriptor from the pool of this thread and prepare it for a new MCAS, see
+/// `m_cas_with`. It must be given back to the `MCasKind` pool once decided.
+pub(crate) fn take_desc<T: 'static>(
+    fill: impl FnOnce(&mut Vec<SingleCas<T>>),
+) -> *mut CCasUnion<MCasUnion<T>> {
+    let desc_ptr = pool::take::<MCasKind, CCasUnion<MCasUnion<T>>>();
+    let desc = desc_of(desc_ptr);
+    let entries = unsafe { &mut *desc.inner.get() };
+    entries.clear();
+    fill(entries);
+    unsafe {
+        *desc.info.get() = OpInfo {
+            timestamp: contention::timestamp(),
+            karma: entries.len(),
+        };
+    }
+    desc.wait_free.store(false, Ordering::SeqCst);
+    desc.aborted.store(false, Ordering::SeqCst);
+    desc.status.set(Status::Undecided, Ordering::SeqCst);
+    metrics::m_cas_attempt(desc_ptr as *const ());
+    desc_ptr
+}
+
+pub(crate) fn desc_of<'a, T>(desc_ptr: *mut CCasUnion<MCasUnion<T>>) -> &'a MCasDesc<T> {
+    match unsafe { &*desc_ptr } {
+        CCasUnion::Value(MCasUnion::MCasDesc(desc)) => desc,
+        _ => unreachable!(),
+    }
+}
+
+/// Install `desc_ptr` in its first written location only and move it to `status`, which is
+/// `Undecided` or `ReadChecking`, as if its owner stalled there. Returns whether it was
+/// installed.
+#[cfg(test)]
+pub(crate) fn stall<T: 'static>(desc_ptr: *mut CCasUnion<MCasUnion<T>>, status: Status) -> bool {
+    let desc = desc_of(desc_ptr);
+    let item = desc
+        .entries()
+        .iter()
+        .find(|item| !item.compare_only)
+        .unwrap();
+    item.origin
+        .c_cas_with(item.expect, desc_ptr, &desc.status, None);
+    let installed = std::ptr::eq(item.origin.load(Ordering::SeqCst), desc_ptr);
+    if installed {
+        desc.status.set(status, Ordering::SeqCst);
+    }
+    installed
+}
+
+impl<T: 'static> MCas<T> for [SingleCas<T>] {
     fn m_cas(&self) -> bool {
-        let mut sort_self: Vec<SingleCas<T>> = self.clone();
-        sort_self.sort();
-
-        let mut desc = CCasUnion::Value(MCasUnion::MCasDesc(MCasDesc::<T> {
-            inner: Arc::new(sort_self),
-            status: Arc::new(AtomicNumLikes::new(Status::Undecided)),
-        }));
-        let desc_ptr = &mut desc as *mut CCasUnion<MCasUnion<T>>;
-
-        match desc {
-            CCasUnion::Value(v) => match v {
-                MCasUnion::MCasDesc(v) => v.help(desc_ptr),
-                _ => unreachable!(),
-            },
-            _ => unreachable!(),
+        m_cas_with(|entries| {
+            entries.extend(self.iter().cloned());
+            entries.sort_unstable();
+        })
+    }
+}
+
+impl<T: 'static> MCas<T> for Vec<SingleCas<T>> {
+    fn m_cas(&self) -> bool {
+        self.as_slice().m_cas()
+    }
+}
+
+/// Fixed-arity MCAS. The entries are sorted by a sorting network, and nothing but the pooled
+/// descriptor is involved, so no heap allocation happens.
+impl<T: 'static, const N: usize> MCas<T> for [SingleCas<T>; N] {
+    fn m_cas(&self) -> bool {
+        m_cas_with(|entries| {
+            entries.extend(self.iter().cloned());
+            sort_network::<T, N>(entries);
+        })
+    }
+}
+
+/// Odd-even transposition sort. The sequence of comparators only depends on `N`, which lets the
+/// compiler unroll it completely for the small arrays it is used for.
+fn sort_network<T, const N: usize>(entries: &mut [SingleCas<T>]) {
+    for round in 0..N {
+        let mut i = round % 2;
+        while i + 1 < N {
+            if entries[i] > entries[i + 1] {
+                entries.swap(i, i + 1);
+            }
+            i += 2;
+        }
+    }
+}
+
+/// Double-word CAS
+pub fn dcas<T: 'static>(first: SingleCas<T>, second: SingleCas<T>) -> bool {
+    [first, second].m_cas()
+}
+
+/// Triple-word CAS
+pub fn tcas<T: 'static>(first: SingleCas<T>, second: SingleCas<T>, third: SingleCas<T>) -> bool {
+    [first, second, third].m_cas()
+}
+
+/// One entry of a MCAS by value: the location must hold a value equal to `expect`, whichever
+/// cell holds it. The new value is moved into a new cell when the MCAS runs.
+pub struct ValueCas<T> {
+    origin: AtomicMCasPtr<T>,
+    expect: T,
+    new: Option<T>,
+}
+
+impl<T> ValueCas<T> {
+    pub fn new(origin: &AtomicMCasPtr<T>, expect: T, new: T) -> ValueCas<T> {
+        ValueCas {
+            origin: origin.clone(),
+            expect,
+            new: Some(new),
+        }
+    }
+    /// A compare-only entry, see `SingleCas::compare`
+    pub fn compare(origin: &AtomicMCasPtr<T>, expect: T) -> ValueCas<T> {
+        ValueCas {
+            origin: origin.clone(),
+            expect,
+            new: None,
+        }
+    }
+}
+
+/// MCAS comparing values instead of cells.
+///
+/// The cells currently installed are loaded and, as long as all of their values are equal to
+/// the expected ones, a MCAS on those cells is tried again. It returns `false` once a location
+/// is seen with a different value; the new cells are freed then, as they were never installed.
+/// On success the replaced cells are retired through `epoch`, so every cell ever installed in
+/// a written location must be one that may be retired, see `AtomicMCasPtr::from_raw`.
+pub fn value_m_cas<T: PartialEq + 'static>(entries: Vec<ValueCas<T>>) -> bool {
+    let entries = entries
+        .into_iter()
+        .map(|entry| {
+            let new = entry
+                .new
+                .map(|new| Box::into_raw(Box::new(MCasPtr::new(new))));
+            (entry.origin, entry.expect, new)
+        })
+        .collect::<Vec<_>>();
+    let mut m_cas = Vec::with_capacity(entries.len());
+    let guard = epoch::pin();
+    loop {
+        m_cas.clear();
+        for (origin, expect, new) in &entries {
+            let current = origin.load();
+            if unsafe { (*current).get() } != expect {
+                for new in entries.iter().filter_map(|(_, _, new)| *new) {
+                    drop(unsafe { Box::from_raw(new) });
+                }
+                return false;
+            }
+            m_cas.push(match new {
+                Some(new) => SingleCas::new(origin, current, *new),
+                None => SingleCas::compare(origin, current),
+            });
+        }
+        if m_cas.m_cas() {
+            for entry in m_cas.iter().filter(|entry| !entry.compare_only) {
+                unsafe { MCasPtr::retire(entry.expect as *mut MCasPtr<T>, &guard) };
+            }
+            return true;
+        }
+    }
+}
+
+/// Read several locations atomically.
+///
+/// The cells of all locations are loaded, then validated together by a compare-only MCAS, which
+/// succeeds only if none of them has changed in between. The snapshot is linearized at that
+/// MCAS; it is retried until it succeeds.
+pub fn m_read<T: Clone + 'static>(locations: &[&AtomicMCasPtr<T>]) -> Vec<T> {
+    // Keeps the loaded cells alive against writers which retire them.
+    let _guard = epoch::pin();
+    let mut cells = Vec::with_capacity(locations.len());
+    loop {
+        cells.clear();
+        cells.extend(locations.iter().map(|location| location.load()));
+        let m_cas: Vec<SingleCas<T>> = locations
+            .iter()
+            .zip(&cells)
+            .map(|(location, cell)| SingleCas::compare(location, *cell))
+            .collect();
+        if m_cas.m_cas() {
+            return cells
+                .iter()
+                .map(|cell| unsafe { (**cell).get().clone() })
+                .collect();
+        }
+    }
+}
+
+/// Fixed-arity `m_read`, which does not allocate.
+pub fn m_read_array<T: Clone + 'static, const N: usize>(
+    locations: [&AtomicMCasPtr<T>; N],
+) -> [T; N] {
+    let guard = epoch::pin();
+    m_load_array(locations, &guard).map(|cell| unsafe { (*cell).get().clone() })
+}
+
+/// The cells of several locations at one instant, validated like `m_read`. They can be used as
+/// `expect` of a later MCAS, and stay valid while `guard` is alive, like the value of
+/// `AtomicMCasPtr::read`.
+pub fn m_load_array<T: 'static, const N: usize>(
+    locations: [&AtomicMCasPtr<T>; N],
+    _guard: &Guard,
+) -> [*mut MCasPtr<T>; N] {
+    loop {
+        let cells = locations.map(|location| location.load());
+        let m_cas: [SingleCas<T>; N] =
+            std::array::from_fn(|i| SingleCas::compare(locations[i], cells[i]));
+        if m_cas.m_cas() {
+            return cells;
         }
     }
 }
@@ -152,30 +611,82 @@ impl<T> Clone for AtomicMCasPtr<T> {
 }
 
 impl<T> AtomicMCasPtr<T> {
-    pub fn new(ptr: &mut MCasPtr<T>) -> Self {
+    /// A location holding `cell`. The location never frees the cell it holds: whoever replaces
+    /// it retires it, and a structure frees the cells left in its locations when it is dropped.
+    pub fn new(cell: Box<MCasPtr<T>>) -> Self {
+        unsafe { AtomicMCasPtr::from_raw(Box::into_raw(cell)) }
+    }
+    /// A location holding `cell`, which may also be held by other locations.
+    ///
+    /// # Safety
+    ///
+    /// `cell` must come from `Box::into_raw`, and must not be freed while it is installed in
+    /// this location, nor afterwards except through `MCasPtr::retire` or
+    /// `MCasPtr::take_replaced`.
+    pub unsafe fn from_raw(cell: *mut MCasPtr<T>) -> Self {
         AtomicMCasPtr {
-            inner: CCasPtr::from_c_cas_union(ptr.get_mut_ptr()),
+            inner: CCasPtr::from_c_cas_union(std::ptr::addr_of_mut!((*cell).inner)),
+        }
+    }
+    /// Whether both are the same shared location
+    pub fn same_location(&self, other: &AtomicMCasPtr<T>) -> bool {
+        self.inner.get_addr() == other.inner.get_addr()
+    }
+    /// Raw content of the location, which may be the descriptor of an operation in progress.
+    pub fn get_m_cas_ptr(&self, order: Ordering) -> *mut MCasPtr<T> {
+        self.inner.load(order) as *mut MCasPtr<T>
+    }
+}
+
+impl<T: 'static> AtomicMCasPtr<T> {
+    /// The current value. It stays valid while `guard` is alive, as long as cells replaced in
+    /// this location are freed through `MCasPtr::retire` or `MCasPtr::take_replaced`.
+    pub fn read<'g>(&self, _guard: &'g Guard) -> &'g T {
+        unsafe { (*self.load()).get() }
+    }
+    /// The value cell currently stored in the location. Operations in progress are helped to
+    /// completion first, so the result is never a descriptor.
+    pub fn load(&self) -> *mut MCasPtr<T> {
+        loop {
+            let c_union_ptr = self.inner.load_value();
+            match unsafe { &*c_union_ptr } {
+                CCasUnion::Value(MCasUnion::MCasDesc(desc)) => {
+                    MCasDesc::help_other(&self.inner, c_union_ptr, desc);
+                }
+                _ => return c_union_ptr as *mut MCasPtr<T>,
+            }
         }
     }
-    pub fn read(&self) -> &mut T {
+
+    /// The value cell of the location, without helping any operation in progress: the new cell
+    /// of a MCAS which has already succeeded, the expected one otherwise.
+    pub(crate) fn peek(&self) -> *mut MCasPtr<T> {
         loop {
-            let c_union_ptr = self.inner.load(Ordering::Relaxed);
-            let c_cas_ptr = unsafe { (*c_union_ptr).load() };
-            unsafe {
-                match &mut *c_cas_ptr {
-                    MCasUnion::MCasDesc(desc) => {
-                        desc.help(c_union_ptr);
-                    }
-                    MCasUnion::Value(v) => {
-                        return v;
+            let c_union_ptr = self.inner.peek_value();
+            match unsafe { &*c_union_ptr } {
+                CCasUnion::Value(MCasUnion::MCasDesc(desc)) => {
+                    if !self.inner.pin(&desc.recycle, c_union_ptr) {
+                        continue;
                     }
+                    let addr = self.inner.get_addr();
+                    let item = desc
+                        .entries()
+                        .iter()
+                        .find(|item| item.origin.get_addr() == addr)
+                        .unwrap();
+                    let status: Status = desc.status.get(Ordering::SeqCst);
+                    let cell = if status == Status::Successful {
+                        item.new
+                    } else {
+                        item.expect
+                    };
+                    desc.recycle.unpin();
+                    return cell as *mut MCasPtr<T>;
                 }
+                _ => return c_union_ptr as *mut MCasPtr<T>,
             }
         }
     }
-    pub fn get_m_cas_ptr(&self, order: Ordering) -> *mut MCasPtr<T> {
-        self.inner.load(order) as *mut MCasPtr<T>
-    }
 }
 
 pub struct MCasPtr<T> {
@@ -187,59 +698,321 @@ impl<T> MCasPtr<T> {
             inner: CCasUnion::Value(MCasUnion::Value(val)),
         }
     }
-    pub fn read_mut(&mut self) -> *mut T {
-        let c_cas_union = &mut self.inner;
-        let c_cas_union_ptr = c_cas_union as *mut CCasUnion<MCasUnion<T>>;
-        loop {
-            match c_cas_union {
-                CCasUnion::Value(v) => match v {
-                    MCasUnion::Value(v) => return v as *mut T,
-                    MCasUnion::MCasDesc(desc) => {
-                        desc.help(c_cas_union_ptr);
-                    }
-                },
-                CCasUnion::CCasDesc(desc) => {
-                    desc.help(c_cas_union_ptr);
-                }
-            }
+    pub fn get(&self) -> &T {
+        match &self.inner {
+            CCasUnion::Value(MCasUnion::Value(v)) => v,
+            _ => unreachable!(), // Cells are never descriptors
+        }
+    }
+    pub fn get_mut(&mut self) -> &mut T {
+        match &mut self.inner {
+            CCasUnion::Value(MCasUnion::Value(v)) => v,
+            _ => unreachable!(),
         }
     }
     pub fn get_mut_ptr(&mut self) -> *mut CCasUnion<MCasUnion<T>> {
-       &mut self.inner as *mut CCasUnion<MCasUnion<T>>
+        &mut self.inner as *mut CCasUnion<MCasUnion<T>>
+    }
+}
+
+impl<T: 'static> MCasPtr<T> {
+    /// Free a cell which a successful MCAS replaced, once no thread pinned now can read it.
+    ///
+    /// # Safety
+    ///
+    /// `cell` must come from `Box::into_raw`, be installed in no location any more, and be
+    /// freed only once. `T` may be dropped on any thread.
+    pub unsafe fn retire(cell: *mut MCasPtr<T>, guard: &Guard) {
+        guard.defer_destroy(cell);
+    }
+
+    /// Move the value out of a cell which a successful MCAS replaced. The cell itself is freed
+    /// once no thread pinned now can read it.
+    ///
+    /// # Safety
+    ///
+    /// As for `retire`. In addition, no other thread may use the value through a reference it
+    /// got before: only the caller owns it from now on.
+    pub unsafe fn take_replaced(cell: *mut MCasPtr<T>, guard: &Guard) -> T {
+        let val = std::ptr::read((*cell).get());
+        guard.defer_destroy(cell as *mut ManuallyDrop<MCasPtr<T>>);
+        val
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::epoch;
+    use std::thread;
+
+    fn cell(val: i32) -> *mut MCasPtr<i32> {
+        Box::into_raw(Box::new(MCasPtr::new(val)))
+    }
 
     #[test]
     fn single_thread_m_cas() {
-        let mut num1 = MCasPtr::new(1);
-        let num1_ptr = &mut num1 as *mut MCasPtr<i32>;
-        let mut num2 = MCasPtr::new(2);
-        let num2_ptr = &mut num2 as *mut MCasPtr<i32>;
-        let mut num3 = MCasPtr::new(3);
-        let num3_ptr = &mut num3 as *mut MCasPtr<i32>;
-        let mut num4 = MCasPtr::new(4);
-        let num4_ptr = &mut num4 as *mut MCasPtr<i32>;
-
-        let atomic_num1 = AtomicMCasPtr::new(&mut num1);
+        let [num1_ptr, num2_ptr, num3_ptr, num4_ptr] = [1, 2, 3, 4].map(cell);
+
+        let atomic_num1 = unsafe { AtomicMCasPtr::from_raw(num1_ptr) };
         let first_cas = SingleCas::new(&atomic_num1.clone(), num2_ptr, num2_ptr);
 
-        let atomic_num3 = AtomicMCasPtr::new(&mut num3);
+        let atomic_num3 = unsafe { AtomicMCasPtr::from_raw(num3_ptr) };
         let second_cas = SingleCas::new(&atomic_num3.clone(), num3_ptr, num4_ptr);
 
         let m_cas = vec![first_cas, second_cas];
         assert_eq!(m_cas.m_cas(), false);
-        assert_eq!(*atomic_num1.read(), 1);
-        assert_eq!(*atomic_num3.read(), 3);
+        assert_eq!(*atomic_num1.read(&epoch::pin()), 1);
+        assert_eq!(*atomic_num3.read(&epoch::pin()), 3);
 
         let first_cas = SingleCas::new(&atomic_num1.clone(), num1_ptr, num2_ptr);
         let second_cas = SingleCas::new(&atomic_num3.clone(), num3_ptr, num4_ptr);
         let m_cas = vec![first_cas, second_cas];
         assert_eq!(m_cas.m_cas(), true);
-        assert_eq!(*atomic_num1.read(), 2);
-        assert_eq!(*atomic_num3.read(), 4);
+        assert_eq!(*atomic_num1.read(&epoch::pin()), 2);
+        assert_eq!(*atomic_num3.read(&epoch::pin()), 4);
+    }
+    #[test]
+    fn compare_only_m_cas() {
+        let [num1_ptr, num2_ptr, num3_ptr, num4_ptr] = [1, 2, 3, 4].map(cell);
+
+        let atomic_num1 = unsafe { AtomicMCasPtr::from_raw(num1_ptr) };
+        let atomic_num3 = unsafe { AtomicMCasPtr::from_raw(num3_ptr) };
+
+        let m_cas = vec![
+            SingleCas::new(&atomic_num1, num1_ptr, num2_ptr),
+            SingleCas::compare(&atomic_num3, num4_ptr),
+        ];
+        assert!(!m_cas.m_cas());
+        assert_eq!(*atomic_num1.read(&epoch::pin()), 1);
+        assert_eq!(*atomic_num3.read(&epoch::pin()), 3);
+
+        let m_cas = vec![
+            SingleCas::new(&atomic_num1, num1_ptr, num2_ptr),
+            SingleCas::compare(&atomic_num3, num3_ptr),
+        ];
+        assert!(m_cas.m_cas());
+        assert_eq!(*atomic_num1.read(&epoch::pin()), 2);
+        assert_eq!(*atomic_num3.read(&epoch::pin()), 3);
+        assert!(std::ptr::eq(
+            atomic_num3.get_m_cas_ptr(Ordering::Relaxed),
+            num3_ptr
+        ));
+
+        let m_cas = vec![
+            SingleCas::compare(&atomic_num1, num2_ptr),
+            SingleCas::compare(&atomic_num3, num3_ptr),
+        ];
+        assert!(m_cas.m_cas());
+    }
+
+    #[test]
+    fn fixed_arity_m_cas() {
+        let ptrs: Vec<*mut MCasPtr<i32>> = (0..6).map(cell).collect();
+        let atomic: Vec<AtomicMCasPtr<i32>> = ptrs[..3]
+            .iter()
+            .map(|&ptr| unsafe { AtomicMCasPtr::from_raw(ptr) })
+            .collect();
+
+        assert!(!tcas(
+            SingleCas::new(&atomic[2], ptrs[2], ptrs[5]),
+            SingleCas::new(&atomic[0], ptrs[0], ptrs[3]),
+            SingleCas::new(&atomic[1], ptrs[4], ptrs[4]),
+        ));
+        assert_eq!(*atomic[0].read(&epoch::pin()), 0);
+        assert_eq!(*atomic[2].read(&epoch::pin()), 2);
+
+        assert!(dcas(
+            SingleCas::new(&atomic[2], ptrs[2], ptrs[5]),
+            SingleCas::new(&atomic[0], ptrs[0], ptrs[3]),
+        ));
+        assert_eq!(*atomic[0].read(&epoch::pin()), 3);
+        assert_eq!(*atomic[1].read(&epoch::pin()), 1);
+        assert_eq!(*atomic[2].read(&epoch::pin()), 5);
+
+        let m_cas = [
+            SingleCas::new(&atomic[1], ptrs[1], ptrs[4]),
+            SingleCas::compare(&atomic[2], ptrs[5]),
+            SingleCas::compare(&atomic[0], ptrs[3]),
+        ];
+        assert!(m_cas.m_cas());
+        assert_eq!(*atomic[1].read(&epoch::pin()), 4);
+    }
+    #[test]
+    fn value_m_cas_ignores_cell_identity() {
+        let atomic1 = AtomicMCasPtr::new(Box::new(MCasPtr::new(1)));
+        let atomic2 = AtomicMCasPtr::new(Box::new(MCasPtr::new(2)));
+
+        assert!(!value_m_cas(vec![
+            ValueCas::new(&atomic1, 1, 10),
+            ValueCas::compare(&atomic2, 3),
+        ]));
+        assert_eq!(*atomic1.read(&epoch::pin()), 1);
+
+        assert!(value_m_cas(vec![
+            ValueCas::new(&atomic1, 1, 2),
+            ValueCas::new(&atomic2, 2, 1),
+        ]));
+        assert_eq!(*atomic1.read(&epoch::pin()), 2);
+        assert_eq!(*atomic2.read(&epoch::pin()), 1);
+
+        // Swap back: both locations hold other cells now, but the same values.
+        assert!(value_m_cas(vec![
+            ValueCas::new(&atomic1, 2, 1),
+            ValueCas::compare(&atomic2, 1),
+        ]));
+        assert_eq!(*atomic1.read(&epoch::pin()), 1);
+    }
+
+    #[test]
+    fn value_m_cas_retires_replaced_cells() {
+        use std::sync::Arc;
+
+        let first = Arc::new(1);
+        let location = AtomicMCasPtr::new(Box::new(MCasPtr::new(first.clone())));
+        assert!(value_m_cas(vec![ValueCas::new(
+            &location,
+            Arc::new(1),
+            Arc::new(2)
+        )]));
+        assert_eq!(**location.read(&epoch::pin()), 2);
+        for _ in 0..100_000 {
+            if Arc::strong_count(&first) == 1 {
+                break;
+            }
+            epoch::pin().flush();
+            thread::yield_now();
+        }
+        assert_eq!(Arc::strong_count(&first), 1);
+    }
+
+    #[test]
+    fn m_read_snapshot() {
+        const ITER_NUM: usize = 2000;
+
+        let first = AtomicMCasPtr::new(Box::new(MCasPtr::new(0)));
+        let second = AtomicMCasPtr::new(Box::new(MCasPtr::new(0)));
+        let third = AtomicMCasPtr::new(Box::new(MCasPtr::new(0)));
+        let writer = {
+            let (first, second) = (first.clone(), second.clone());
+            thread::spawn(move || {
+                for i in 1..=ITER_NUM {
+                    assert!(value_m_cas(vec![
+                        ValueCas::new(&first, i - 1, i),
+                        ValueCas::new(&second, i - 1, i),
+                    ]));
+                }
+            })
+        };
+        for _ in 0..ITER_NUM {
+            let values = m_read(&[&first, &second, &third]);
+            assert_eq!(values[0], values[1]);
+            assert_eq!(values[2], 0);
+            let [a, b] = m_read_array([&second, &first]);
+            assert_eq!(a, b);
+        }
+        writer.join().unwrap();
+        assert_eq!(m_read_array([&first, &second]), [ITER_NUM, ITER_NUM]);
+    }
+
+    #[test]
+    fn replaced_cells_are_reclaimed() {
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::Arc;
+
+        struct Counted(usize, Arc<AtomicUsize>);
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                self.1.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let drops = Arc::new(AtomicUsize::new(0));
+        let cell = |val| Box::into_raw(Box::new(MCasPtr::new(Counted(val, drops.clone()))));
+        let (first, second, third) = (cell(1), cell(2), cell(3));
+        let location = unsafe { AtomicMCasPtr::from_raw(first) };
+
+        let guard = epoch::pin();
+        let seen = location.read(&guard);
+        assert!(vec![SingleCas::new(&location, first, second)].m_cas());
+        unsafe { MCasPtr::retire(first, &guard) };
+        // The replaced value is still readable while the guard is alive.
+        assert_eq!(seen.0, 1);
+        assert!(vec![SingleCas::new(&location, second, third)].m_cas());
+        let taken = unsafe { MCasPtr::take_replaced(second, &guard) };
+        assert_eq!(taken.0, 2);
+        drop(guard);
+
+        assert_eq!(location.read(&epoch::pin()).0, 3);
+        drop(taken);
+        for _ in 0..100_000 {
+            if drops.load(Ordering::SeqCst) == 2 {
+                break;
+            }
+            epoch::pin().flush();
+            thread::yield_now();
+        }
+        // `first` is dropped once with its value, `second` only as the taken value.
+        assert_eq!(drops.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn multi_thread_m_cas() {
+        const THREAD_NUM: usize = 8;
+        const ITER_NUM: usize = 2000;
+
+        let first = AtomicMCasPtr::new(Box::new(MCasPtr::new(0)));
+        let second = AtomicMCasPtr::new(Box::new(MCasPtr::new(0)));
+        let threads: Vec<_> = (0..THREAD_NUM)
+            .map(|_| {
+                let first = first.clone();
+                let second = second.clone();
+                thread::spawn(move || {
+                    for _ in 0..ITER_NUM {
+                        loop {
+                            let first_ptr = first.load();
+                            let second_ptr = second.load();
+                            let (a, b) = unsafe { (*(*first_ptr).get(), *(*second_ptr).get()) };
+                            let new_first = Box::leak(Box::new(MCasPtr::new(a + 1)));
+                            let new_second = Box::leak(Box::new(MCasPtr::new(b + 1)));
+                            if dcas(
+                                SingleCas::new(&first, first_ptr, new_first),
+                                SingleCas::new(&second, second_ptr, new_second),
+                            ) {
+                                break;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        let readers: Vec<_> = (0..THREAD_NUM)
+            .map(|_| {
+                let first = first.clone();
+                let second = second.clone();
+                thread::spawn(move || {
+                    for _ in 0..ITER_NUM {
+                        let first_ptr = first.load();
+                        let second_ptr = second.load();
+                        // Writers keep both values equal, so a validated snapshot must agree.
+                        if [
+                            SingleCas::compare(&first, first_ptr),
+                            SingleCas::compare(&second, second_ptr),
+                        ]
+                        .m_cas()
+                        {
+                            unsafe {
+                                assert_eq!(*(*first_ptr).get(), *(*second_ptr).get());
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads.into_iter().chain(readers) {
+            t.join().unwrap();
+        }
+        assert_eq!(*first.read(&epoch::pin()), THREAD_NUM * ITER_NUM);
+        assert_eq!(*second.read(&epoch::pin()), THREAD_NUM * ITER_NUM);
     }
 }
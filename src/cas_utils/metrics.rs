@@ -0,0 +1,350 @@
+//! # Usage
+//!
+//! With the `metrics` feature enabled, every thread counts what its CCAS and MCAS operations
+//! do. `snapshot` sums the counters of all threads, `thread_snapshot` reads the ones of the
+//! calling thread and `reset` sets all of them back to zero. Without the feature nothing is
+//! counted and the counting compiles to nothing.
+//!
+//! ```
+//! # use beee::cas_utils::m_cas::*;
+//! # #[cfg(feature = "metrics")]
+//! # {
+//! # use beee::cas_utils::metrics;
+//! let atomic_num = AtomicMCasPtr::new(Box::new(MCasPtr::new(1)));
+//! let num1_ptr = atomic_num.load();
+//! let num2_ptr = Box::into_raw(Box::new(MCasPtr::new(2)));
+//!
+//! let before = metrics::thread_snapshot();
+//! assert!(vec![SingleCas::new(&atomic_num, num1_ptr, num2_ptr)].m_cas());
+//! let after = metrics::thread_snapshot();
+//! assert_eq!(after.m_cas_attempts, before.m_cas_attempts + 1);
+//! assert_eq!(after.m_cas_successes, before.m_cas_successes + 1);
+//! # }
+//! ```
+//!
+//! # Notes
+//!
+//! The counters of a thread live in a block registered in a global list. A thread only ever
+//! adds to its own block, so counting never contends with other threads. When a thread exits
+//! its block is kept, with its counts, and taken over by the next new thread.
+//!
+//! Attempts are counted by the thread which started the MCAS, but its outcome is counted by
+//! the thread which decided it: the one whose status CAS made it `Successful` or `Failed`. This
+//! is the owner unless a helper finished or aborted the MCAS, so the sums over all threads
+//! match while a single thread may see more outcomes than attempts.
+
+/// Why a MCAS failed
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FailureCause {
+    /// A written location held another value than expected.
+    Mismatch,
+    /// A compare-only location changed before the MCAS was linearized.
+    ReadCheck,
+    /// A contention manager, a deadline or a conflicting MCAS aborted it.
+    Aborted,
+}
+
+pub(crate) use self::imp::{c_cas_retry, help, m_cas_attempt, m_cas_failure, m_cas_success};
+#[cfg(feature = "metrics")]
+pub use self::imp::{reset, snapshot, thread_snapshot, Metrics};
+
+#[cfg(feature = "metrics")]
+mod imp {
+    use super::FailureCause;
+    use std::cell::Cell;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::sync::Mutex;
+
+    /// Counters of one or more threads
+    ///
+    /// # Fields
+    ///
+    /// * `m_cas_attempts`: MCASes started
+    /// * `m_cas_successes`: MCASes decided `Successful`
+    /// * `m_cas_mismatches`, `m_cas_read_check_failures`, `m_cas_aborts`: MCASes decided
+    ///   `Failed`, by `FailureCause`
+    /// * `own_helps`: Calls to help a MCAS descriptor of the thread itself
+    /// * `other_helps`: Calls to help a MCAS descriptor of another thread
+    /// * `c_cas_retries`: Times a CCAS found another CCAS installed and tried again
+    /// * `max_help_depth`: Most MCASes a thread has been helping at once, its own included
+    #[derive(Debug, PartialEq, Clone, Copy, Default)]
+    pub struct Metrics {
+        pub m_cas_attempts: u64,
+        pub m_cas_successes: u64,
+        pub m_cas_mismatches: u64,
+        pub m_cas_read_check_failures: u64,
+        pub m_cas_aborts: u64,
+        pub own_helps: u64,
+        pub other_helps: u64,
+        pub c_cas_retries: u64,
+        pub max_help_depth: u64,
+    }
+
+    impl Metrics {
+        /// Failed MCASes, whatever the cause
+        pub fn m_cas_failures(&self) -> u64 {
+            self.m_cas_mismatches + self.m_cas_read_check_failures + self.m_cas_aborts
+        }
+    }
+
+    #[derive(Default)]
+    struct Counters {
+        in_use: AtomicBool,
+        m_cas_attempts: AtomicU64,
+        m_cas_successes: AtomicU64,
+        m_cas_mismatches: AtomicU64,
+        m_cas_read_check_failures: AtomicU64,
+        m_cas_aborts: AtomicU64,
+        own_helps: AtomicU64,
+        other_helps: AtomicU64,
+        c_cas_retries: AtomicU64,
+        max_help_depth: AtomicU64,
+    }
+
+    impl Counters {
+        fn read(&self) -> Metrics {
+            Metrics {
+                m_cas_attempts: self.m_cas_attempts.load(Ordering::Relaxed),
+                m_cas_successes: self.m_cas_successes.load(Ordering::Relaxed),
+                m_cas_mismatches: self.m_cas_mismatches.load(Ordering::Relaxed),
+                m_cas_read_check_failures: self.m_cas_read_check_failures.load(Ordering::Relaxed),
+                m_cas_aborts: self.m_cas_aborts.load(Ordering::Relaxed),
+                own_helps: self.own_helps.load(Ordering::Relaxed),
+                other_helps: self.other_helps.load(Ordering::Relaxed),
+                c_cas_retries: self.c_cas_retries.load(Ordering::Relaxed),
+                max_help_depth: self.max_help_depth.load(Ordering::Relaxed),
+            }
+        }
+
+        fn reset(&self) {
+            for counter in [
+                &self.m_cas_attempts,
+                &self.m_cas_successes,
+                &self.m_cas_mismatches,
+                &self.m_cas_read_check_failures,
+                &self.m_cas_aborts,
+                &self.own_helps,
+                &self.other_helps,
+                &self.c_cas_retries,
+                &self.max_help_depth,
+            ] {
+                counter.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Counter blocks of all threads, alive or not. Blocks are leaked.
+    static BLOCKS: Mutex<Vec<&'static Counters>> = Mutex::new(Vec::new());
+
+    /// # Fields
+    ///
+    /// * `counters`: The block of this thread
+    /// * `own`: Descriptor of the MCAS this thread started last
+    /// * `depth`: MCASes this thread is helping right now
+    struct Local {
+        counters: &'static Counters,
+        own: Cell<*const ()>,
+        depth: Cell<u64>,
+    }
+
+    impl Local {
+        fn claim() -> Local {
+            let mut blocks = BLOCKS.lock().unwrap();
+            let free = blocks.iter().find(|block| {
+                block
+                    .in_use
+                    .compare_exchange(false, true, Ordering::SeqCst, Ordering::Relaxed)
+                    .is_ok()
+            });
+            let counters = match free {
+                Some(block) => *block,
+                None => {
+                    let block: &'static Counters = Box::leak(Box::default());
+                    block.in_use.store(true, Ordering::SeqCst);
+                    blocks.push(block);
+                    block
+                }
+            };
+            Local {
+                counters,
+                own: Cell::new(std::ptr::null()),
+                depth: Cell::new(0),
+            }
+        }
+    }
+
+    impl Drop for Local {
+        fn drop(&mut self) {
+            self.counters.in_use.store(false, Ordering::SeqCst);
+        }
+    }
+
+    thread_local! {
+        static LOCAL: Local = Local::claim();
+    }
+
+    /// Run `f` on the counters of this thread. Nothing is counted during thread teardown.
+    fn with_local(f: impl FnOnce(&Local)) {
+        let _ = LOCAL.try_with(f);
+    }
+
+    fn add(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Sum of the counters of all threads, and the deepest helping of any thread
+    pub fn snapshot() -> Metrics {
+        let blocks = BLOCKS.lock().unwrap();
+        blocks
+            .iter()
+            .map(|block| block.read())
+            .fold(Metrics::default(), |sum, m| Metrics {
+                m_cas_attempts: sum.m_cas_attempts + m.m_cas_attempts,
+                m_cas_successes: sum.m_cas_successes + m.m_cas_successes,
+                m_cas_mismatches: sum.m_cas_mismatches + m.m_cas_mismatches,
+                m_cas_read_check_failures: sum.m_cas_read_check_failures
+                    + m.m_cas_read_check_failures,
+                m_cas_aborts: sum.m_cas_aborts + m.m_cas_aborts,
+                own_helps: sum.own_helps + m.own_helps,
+                other_helps: sum.other_helps + m.other_helps,
+                c_cas_retries: sum.c_cas_retries + m.c_cas_retries,
+                max_help_depth: sum.max_help_depth.max(m.max_help_depth),
+            })
+    }
+
+    /// Counters of the calling thread
+    pub fn thread_snapshot() -> Metrics {
+        let mut metrics = Metrics::default();
+        with_local(|local| metrics = local.counters.read());
+        metrics
+    }
+
+    /// Set the counters of all threads to zero.
+    pub fn reset() {
+        for block in BLOCKS.lock().unwrap().iter() {
+            block.reset();
+        }
+    }
+
+    pub(crate) fn m_cas_attempt(desc: *const ()) {
+        with_local(|local| {
+            local.own.set(desc);
+            add(&local.counters.m_cas_attempts);
+        });
+    }
+
+    pub(crate) fn m_cas_success() {
+        with_local(|local| add(&local.counters.m_cas_successes));
+    }
+
+    pub(crate) fn m_cas_failure(cause: FailureCause) {
+        with_local(|local| {
+            add(match cause {
+                FailureCause::Mismatch => &local.counters.m_cas_mismatches,
+                FailureCause::ReadCheck => &local.counters.m_cas_read_check_failures,
+                FailureCause::Aborted => &local.counters.m_cas_aborts,
+            })
+        });
+    }
+
+    pub(crate) fn c_cas_retry() {
+        with_local(|local| add(&local.counters.c_cas_retries));
+    }
+
+    /// Leaves the helping of a MCAS when dropped
+    pub(crate) struct HelpScope;
+
+    impl Drop for HelpScope {
+        fn drop(&mut self) {
+            with_local(|local| local.depth.set(local.depth.get() - 1));
+        }
+    }
+
+    /// Count a call to help the MCAS descriptor `desc`, which lasts as long as the returned
+    /// scope.
+    pub(crate) fn help(desc: *const ()) -> HelpScope {
+        with_local(|local| {
+            let counters = local.counters;
+            add(if std::ptr::eq(local.own.get(), desc) {
+                &counters.own_helps
+            } else {
+                &counters.other_helps
+            });
+            let depth = local.depth.get() + 1;
+            local.depth.set(depth);
+            counters.max_help_depth.fetch_max(depth, Ordering::Relaxed);
+        });
+        HelpScope
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+mod imp {
+    use super::FailureCause;
+
+    #[inline(always)]
+    pub(crate) fn m_cas_attempt(_: *const ()) {}
+
+    #[inline(always)]
+    pub(crate) fn m_cas_success() {}
+
+    #[inline(always)]
+    pub(crate) fn m_cas_failure(_: FailureCause) {}
+
+    #[inline(always)]
+    pub(crate) fn c_cas_retry() {}
+
+    pub(crate) struct HelpScope;
+
+    #[inline(always)]
+    pub(crate) fn help(_: *const ()) -> HelpScope {
+        HelpScope
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod test {
+    use super::*;
+    use crate::cas_utils::m_cas::{AtomicMCasPtr, MCas, MCasPtr, SingleCas};
+
+    fn location(val: i32) -> AtomicMCasPtr<i32> {
+        AtomicMCasPtr::new(Box::new(MCasPtr::new(val)))
+    }
+
+    #[test]
+    fn thread_counters() {
+        let x = location(1);
+        let y = location(2);
+        let before = thread_snapshot();
+
+        let (x_cell, y_cell) = (x.load(), y.load());
+        let new = Box::leak(Box::new(MCasPtr::new(3)));
+        assert!(vec![
+            SingleCas::new(&x, x_cell, new),
+            SingleCas::compare(&y, y_cell)
+        ]
+        .m_cas());
+        let other = Box::leak(Box::new(MCasPtr::new(4)));
+        assert!(!vec![SingleCas::new(&x, x_cell, other)].m_cas());
+        let y_new = Box::leak(Box::new(MCasPtr::new(5)));
+        assert!(vec![SingleCas::new(&y, y_cell, y_new)].m_cas());
+        assert!(!vec![
+            SingleCas::new(&x, x.load(), other),
+            SingleCas::compare(&y, y_cell)
+        ]
+        .m_cas());
+
+        let after = thread_snapshot();
+        assert_eq!(after.m_cas_attempts - before.m_cas_attempts, 4);
+        assert_eq!(after.m_cas_successes - before.m_cas_successes, 2);
+        assert_eq!(after.m_cas_mismatches - before.m_cas_mismatches, 1);
+        assert_eq!(
+            after.m_cas_read_check_failures - before.m_cas_read_check_failures,
+            1
+        );
+        assert_eq!(after.own_helps - before.own_helps, 4);
+        assert_eq!(after.other_helps, before.other_helps);
+        assert!(after.max_help_depth >= 1);
+        assert!(snapshot().m_cas_attempts >= after.m_cas_attempts);
+    }
+}
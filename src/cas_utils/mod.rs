@@ -1,6 +1,7 @@
 #[derive(PartialEq, Copy, Clone)]
 pub enum Status {
     Undecided,
+    ReadChecking,
     Failed,
     Successful,
 }
@@ -9,8 +10,9 @@ impl From<usize> for Status {
     fn from(num: usize) -> Status {
         match num {
             0 => Status::Undecided,
-            1 => Status::Failed,
-            2 => Status::Successful,
+            1 => Status::ReadChecking,
+            2 => Status::Failed,
+            3 => Status::Successful,
             _ => panic!(), // TODO: better way
         }
     }
@@ -22,5 +24,17 @@ impl Into<usize> for Status {
     }
 }
 
+pub mod backend;
 pub mod c_cas;
+pub mod contention;
+pub mod deadline;
+pub mod k_cas;
 pub mod m_cas;
+pub mod metrics;
+pub mod mvcc;
+#[cfg(unix)]
+pub mod pm_cas;
+mod pool;
+pub mod stm;
+pub mod tx;
+pub mod wf_m_cas;
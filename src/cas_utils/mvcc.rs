@@ -0,0 +1,390 @@
+//! # Usage
+//!
+//! `MvPtr` is a MCAS location which keeps its last `MAX_VERSIONS` values. Writers update it
+//! with `mv_m_cas`, and every successful MCAS is stamped with a global version clock. A reader
+//! takes a `Snapshot` and sees every location as of the version the snapshot started at.
+//!
+//! ```
+//! # use beee::cas_utils::mvcc::*;
+//! let x = MvPtr::new(1);
+//! let before = Snapshot::begin();
+//! assert!(mv_m_cas(vec![MvCas::new(&x, x.load(), 2)]));
+//!
+//! assert_eq!(before.read(&x), Some(&1));
+//! assert_eq!(Snapshot::begin().read(&x), Some(&2));
+//! ```
+//!
+//! # Notes
+//!
+//! A reader never helps a MCAS in progress and never aborts one: it looks through descriptors
+//! for the value they replace. The only thing it may write is the version of a MCAS which has
+//! succeeded but not been stamped by its writer yet. Stamping takes a fresh version from the
+//! clock, newer than any running snapshot, so such a MCAS is invisible to the reader.
+//!
+//! Versions are ordered along dependencies: a MCAS is stamped only after the MCASes which
+//! wrote the cells it expected, so a snapshot which sees a MCAS also sees everything it read.
+//!
+//! Versions cut from a chain are retired through `epoch`, and so are the dependencies of a
+//! commit once it is stamped. A `Snapshot` keeps its thread pinned, so a long-lived one holds
+//! back reclamation. A cell passed as `expect` must have been loaded under a guard which is
+//! still alive.
+
+use crate::cas_utils::m_cas::{AtomicMCasPtr, MCas, MCasPtr, SingleCas};
+use crate::epoch::{self, Guard};
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Versions a location keeps, the current one included
+pub const MAX_VERSIONS: usize = 8;
+
+/// The version clock. Version 1 is the initial value of every location.
+static CLOCK: AtomicU64 = AtomicU64::new(1);
+
+/// A successful MCAS, shared by all the cells it installed
+///
+/// # Fields
+///
+/// * `version`: Commit version, `0` until it is stamped
+/// * `deps`: Commits of the cells the MCAS expected, null once it is stamped
+struct Commit {
+    version: AtomicU64,
+    deps: AtomicPtr<Vec<Arc<Commit>>>,
+}
+
+impl Commit {
+    fn new(version: u64, deps: Vec<Arc<Commit>>) -> Arc<Commit> {
+        Arc::new(Commit {
+            version: AtomicU64::new(version),
+            deps: AtomicPtr::new(Box::into_raw(Box::new(deps))),
+        })
+    }
+
+    /// Version of the commit, stamping it first if needed.
+    fn stamp(&self, guard: &Guard) -> u64 {
+        let version = self.version.load(Ordering::SeqCst);
+        if version != 0 {
+            return version;
+        }
+        let deps = self.deps.load(Ordering::SeqCst);
+        // Null only once the commit is stamped, so the compare exchange below fails then.
+        if !deps.is_null() {
+            for dep in unsafe { &*deps } {
+                dep.stamp(guard);
+            }
+        }
+        let version = CLOCK.fetch_add(1, Ordering::SeqCst) + 1;
+        let version =
+            match self
+                .version
+                .compare_exchange(0, version, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => version,
+                Err(stamped) => stamped,
+            };
+        // Nothing walks the dependencies of a stamped commit, they would only keep the whole
+        // history alive.
+        let deps = self.deps.swap(null_mut(), Ordering::SeqCst);
+        if !deps.is_null() {
+            unsafe { guard.defer_destroy(deps) };
+        }
+        version
+    }
+}
+
+impl Drop for Commit {
+    fn drop(&mut self) {
+        let deps = *self.deps.get_mut();
+        if !deps.is_null() {
+            drop(unsafe { Box::from_raw(deps) });
+        }
+    }
+}
+
+/// # Fields
+///
+/// * `val`: The value
+/// * `commit`: The MCAS which installed this version
+/// * `prev`: The previous version, null once it has been trimmed
+pub struct Version<T> {
+    val: T,
+    commit: Arc<Commit>,
+    prev: AtomicPtr<MCasPtr<Version<T>>>,
+}
+
+fn version<'a, T>(cell: *mut MCasPtr<Version<T>>) -> &'a Version<T> {
+    unsafe { (*cell).get() }
+}
+
+/// Retire the chain `link` points to. Every cell is the target of a single `prev` link, so the
+/// thread which swaps that link to null is the only one retiring the cell.
+fn retire_chain<T: 'static>(link: &AtomicPtr<MCasPtr<Version<T>>>, guard: &Guard) {
+    let mut cell = link.swap(null_mut(), Ordering::SeqCst);
+    while !cell.is_null() {
+        let prev = version(cell).prev.swap(null_mut(), Ordering::SeqCst);
+        unsafe { MCasPtr::retire(cell, guard) };
+        cell = prev;
+    }
+}
+
+/// The location of a `MvPtr`, shared by its clones
+struct Location<T: 'static>(AtomicMCasPtr<Version<T>>);
+
+impl<T: 'static> Drop for Location<T> {
+    fn drop(&mut self) {
+        let guard = epoch::pin();
+        let cell = self.0.load();
+        retire_chain(&version(cell).prev, &guard);
+        unsafe { MCasPtr::retire(cell, &guard) };
+    }
+}
+
+pub struct MvPtr<T: 'static> {
+    inner: Arc<Location<T>>,
+}
+
+impl<T: 'static> Clone for MvPtr<T> {
+    fn clone(&self) -> Self {
+        MvPtr {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: 'static> MvPtr<T> {
+    pub fn new(val: T) -> MvPtr<T> {
+        let cell = Box::new(MCasPtr::new(Version {
+            val,
+            commit: Commit::new(1, Vec::new()),
+            prev: AtomicPtr::new(null_mut()),
+        }));
+        MvPtr {
+            inner: Arc::new(Location(AtomicMCasPtr::new(cell))),
+        }
+    }
+
+    /// The current cell, which writers pass as `expect`. MCASes in progress are helped.
+    pub fn load(&self) -> *mut MCasPtr<Version<T>> {
+        self.inner.0.load()
+    }
+
+    /// The current value. It stays valid while `guard` is alive.
+    pub fn latest<'g>(&self, _guard: &'g Guard) -> &'g T {
+        &version(self.load()).val
+    }
+}
+
+/// A read-only view of all locations as of one version. It keeps its thread pinned, so that
+/// the versions it reads are not freed.
+pub struct Snapshot {
+    version: u64,
+    guard: Guard,
+}
+
+impl Snapshot {
+    pub fn begin() -> Snapshot {
+        // Pinned first, so every version newer than the snapshot is still reachable.
+        let guard = epoch::pin();
+        Snapshot {
+            version: CLOCK.load(Ordering::SeqCst),
+            guard,
+        }
+    }
+
+    /// Value of `location` in this snapshot, or `None` if that version has been trimmed.
+    pub fn read<'a, T: 'static>(&'a self, location: &MvPtr<T>) -> Option<&'a T> {
+        let mut cell = location.inner.0.peek();
+        loop {
+            let current = version(cell);
+            if current.commit.stamp(&self.guard) <= self.version {
+                return Some(&current.val);
+            }
+            cell = current.prev.load(Ordering::SeqCst);
+            if cell.is_null() {
+                return None;
+            }
+        }
+    }
+}
+
+/// One entry of `mv_m_cas`. `expect` is a cell returned by `MvPtr::load`.
+pub struct MvCas<T: 'static> {
+    origin: MvPtr<T>,
+    expect: *mut MCasPtr<Version<T>>,
+    new: Option<T>,
+}
+
+impl<T: 'static> MvCas<T> {
+    pub fn new(origin: &MvPtr<T>, expect: *mut MCasPtr<Version<T>>, new: T) -> MvCas<T> {
+        MvCas {
+            origin: origin.clone(),
+            expect,
+            new: Some(new),
+        }
+    }
+    /// A compare-only entry, see `SingleCas::compare`
+    pub fn compare(origin: &MvPtr<T>, expect: *mut MCasPtr<Version<T>>) -> MvCas<T> {
+        MvCas {
+            origin: origin.clone(),
+            expect,
+            new: None,
+        }
+    }
+}
+
+/// MCAS over versioned locations. On success the new values become a new version, stamped
+/// with the clock.
+pub fn mv_m_cas<T: 'static>(entries: Vec<MvCas<T>>) -> bool {
+    let guard = epoch::pin();
+    let commit = Commit::new(
+        0,
+        entries
+            .iter()
+            .map(|entry| version(entry.expect).commit.clone())
+            .collect(),
+    );
+    let mut news = Vec::new();
+    let m_cas: Vec<SingleCas<Version<T>>> = entries
+        .into_iter()
+        .map(|entry| match entry.new {
+            Some(val) => {
+                let new = Box::into_raw(Box::new(MCasPtr::new(Version {
+                    val,
+                    commit: commit.clone(),
+                    prev: AtomicPtr::new(entry.expect),
+                })));
+                news.push(new);
+                SingleCas::new(&entry.origin.inner.0, entry.expect, new)
+            }
+            None => SingleCas::compare(&entry.origin.inner.0, entry.expect),
+        })
+        .collect();
+
+    if !m_cas.m_cas() {
+        // Neither the new cells nor the commit were ever installed.
+        for new in news {
+            drop(unsafe { Box::from_raw(new) });
+        }
+        return false;
+    }
+    commit.stamp(&guard);
+    for new in news {
+        trim(new, &guard);
+    }
+    true
+}
+
+/// Cut the version chain starting at `cell` after `MAX_VERSIONS` versions. The cut versions are
+/// retired, as a reader may still be looking at them.
+fn trim<T: 'static>(cell: *mut MCasPtr<Version<T>>, guard: &Guard) {
+    let mut current = version(cell);
+    for _ in 1..MAX_VERSIONS {
+        let prev = current.prev.load(Ordering::SeqCst);
+        if prev.is_null() {
+            return;
+        }
+        current = version(prev);
+    }
+    retire_chain(&current.prev, guard);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn snapshot_versions() {
+        let x = MvPtr::new(0);
+        let y = MvPtr::new(0);
+        let first = Snapshot::begin();
+
+        assert!(mv_m_cas(vec![
+            MvCas::new(&x, x.load(), 1),
+            MvCas::compare(&y, y.load())
+        ]));
+        assert!(!mv_m_cas(vec![MvCas::new(&y, x.load(), 1)]));
+        let second = Snapshot::begin();
+        assert_eq!(first.read(&x), Some(&0));
+        assert_eq!(second.read(&x), Some(&1));
+        assert_eq!(second.read(&y), Some(&0));
+
+        for i in 2..=MAX_VERSIONS as i32 {
+            assert!(mv_m_cas(vec![MvCas::new(&x, x.load(), i)]));
+        }
+        assert_eq!(*x.latest(&epoch::pin()), MAX_VERSIONS as i32);
+        assert_eq!(first.read(&x), None);
+        assert_eq!(second.read(&x), Some(&1));
+    }
+
+    #[test]
+    fn cut_and_dropped_versions_are_retired() {
+        let first = Arc::new(0);
+        let x = MvPtr::new(first.clone());
+        for i in 1..=MAX_VERSIONS {
+            assert!(mv_m_cas(vec![MvCas::new(&x, x.load(), Arc::new(i))]));
+        }
+        let last = x.latest(&epoch::pin()).clone();
+        drop(x);
+        for _ in 0..100_000 {
+            if Arc::strong_count(&first) == 1 && Arc::strong_count(&last) == 1 {
+                break;
+            }
+            epoch::pin().flush();
+            thread::yield_now();
+        }
+        assert_eq!(Arc::strong_count(&first), 1);
+        assert_eq!(Arc::strong_count(&last), 1);
+    }
+
+    #[test]
+    fn multi_thread_snapshot() {
+        const THREAD_NUM: usize = 4;
+        const ITER_NUM: usize = 2000;
+
+        let accounts: Vec<MvPtr<i64>> = (0..4).map(|_| MvPtr::new(100)).collect();
+        let writers: Vec<_> = (0..THREAD_NUM)
+            .map(|i| {
+                let accounts = accounts.clone();
+                thread::spawn(move || {
+                    for j in 0..ITER_NUM {
+                        let from = &accounts[(i + j) % 4];
+                        let to = &accounts[(i + j + 1) % 4];
+                        loop {
+                            let _guard = epoch::pin();
+                            let (from_cell, to_cell) = (from.load(), to.load());
+                            let (a, b) = (version(from_cell).val, version(to_cell).val);
+                            if mv_m_cas(vec![
+                                MvCas::new(from, from_cell, a - 1),
+                                MvCas::new(to, to_cell, b + 1),
+                            ]) {
+                                break;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        let readers: Vec<_> = (0..THREAD_NUM)
+            .map(|_| {
+                let accounts = accounts.clone();
+                thread::spawn(move || {
+                    for _ in 0..ITER_NUM {
+                        let snapshot = Snapshot::begin();
+                        let values: Option<Vec<i64>> =
+                            accounts.iter().map(|a| snapshot.read(a).copied()).collect();
+                        if let Some(values) = values {
+                            assert_eq!(values.iter().sum::<i64>(), 400);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in writers.into_iter().chain(readers) {
+            t.join().unwrap();
+        }
+        let snapshot = Snapshot::begin();
+        let total: i64 = accounts.iter().map(|a| *snapshot.read(a).unwrap()).sum();
+        assert_eq!(total, 400);
+    }
+}
@@ -0,0 +1,505 @@
+//! # Usage
+//!
+//! A persistent multi-word CAS. Words and descriptors live in a file mapped into memory, and
+//! `PmPool::open` rolls every MCAS interrupted by a crash forward or back before the pool can be
+//! used again.
+//!
+//! ```
+//! # use beee::cas_utils::pm_cas::*;
+//! let path = std::env::temp_dir().join(format!("beee-pm-cas-doc-{}", std::process::id()));
+//! let pool = PmPool::open(&path, 16, 4).unwrap();
+//! assert!(pool.pm_cas(&[(0, 0, 10), (3, 0, 30)]));
+//! assert!(!pool.pm_cas(&[(0, 0, 20)]));
+//! assert_eq!(pool.read(0), 10);
+//! drop(pool);
+//!
+//! let pool = PmPool::open(&path, 16, 4).unwrap();
+//! assert_eq!(pool.read(3), 30);
+//! # std::fs::remove_file(&path).unwrap();
+//! ```
+//!
+//! # Notes
+//!
+//! The algorithm is described in [Easy Lock-Free Indexing in Non-Volatile Memory](https://doi.org/10.1109/ICDE.2018.00049).
+//! Words hold values below `2^61`; the three high bits mark a word which is not persisted yet
+//! (`DIRTY`), which holds a descriptor (`MCAS`), or which holds a descriptor being installed
+//! (`RDCSS`). Descriptors are referred to by index, so the file can be mapped at any address.
+//!
+//! Cache lines are flushed with `clflush` on x86_64 before anything depends on them. On a
+//! regular file a killed process loses nothing which reached the mapping, so crashes of the
+//! process can be simulated but not power failures. Only one process may open a pool at a time.
+
+use crate::cas_utils::Status;
+use std::fs::OpenOptions;
+use std::io;
+use std::os::raw::{c_int, c_void};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+extern "C" {
+    fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        offset: i64,
+    ) -> *mut c_void;
+    fn munmap(addr: *mut c_void, len: usize) -> c_int;
+}
+
+const PROT_READ: c_int = 1;
+const PROT_WRITE: c_int = 2;
+const MAP_SHARED: c_int = 1;
+
+const DIRTY: u64 = 1 << 63;
+const MCAS: u64 = 1 << 62;
+const RDCSS: u64 = 1 << 61;
+const FLAGS: u64 = DIRTY | MCAS | RDCSS;
+
+const MAGIC: u64 = 0x6265_6565_706d_6373;
+/// Words per entry of a MCAS
+pub const MAX_WORDS: usize = 4;
+
+/// Layout of the file, in 64-bit words. The header is `[MAGIC, descriptors, words]`.
+const HEADER_LEN: usize = 8;
+/// A descriptor is `[state, status, count, (word, old, new) * MAX_WORDS]`. `state` is
+/// `in_use << 32 | pins`.
+const DESC_LEN: usize = 16;
+const IN_USE: u64 = 1 << 32;
+const STATE: usize = 0;
+const STATUS: usize = 1;
+const COUNT: usize = 2;
+const ENTRIES: usize = 3;
+
+/// Write back the cache line of `word` to the persistent medium.
+fn persist(word: &AtomicU64) {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        std::arch::x86_64::_mm_clflush(word as *const AtomicU64 as *const u8);
+        std::arch::x86_64::_mm_sfence();
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        let _ = word;
+        std::sync::atomic::fence(Ordering::SeqCst);
+    }
+}
+
+pub struct PmPool {
+    base: *mut AtomicU64,
+    len: usize,
+    descriptors: usize,
+    words: usize,
+}
+
+unsafe impl Send for PmPool {}
+unsafe impl Sync for PmPool {}
+
+impl PmPool {
+    /// Open the pool in `path`, creating it with `descriptors` descriptors and `words` words
+    /// set to zero if the file does not exist. An existing pool is recovered first.
+    pub fn open(path: &Path, descriptors: usize, words: usize) -> io::Result<PmPool> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let len = (HEADER_LEN + descriptors * DESC_LEN + words) * 8;
+        let fresh = file.metadata()?.len() == 0;
+        if fresh {
+            file.set_len(len as u64)?;
+        } else if file.metadata()?.len() != len as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "pool size does not match",
+            ));
+        }
+        let base = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                len,
+                PROT_READ | PROT_WRITE,
+                MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if base as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let pool = PmPool {
+            base: base as *mut AtomicU64,
+            len,
+            descriptors,
+            words,
+        };
+        if fresh {
+            pool.at(1).store(descriptors as u64, Ordering::SeqCst);
+            pool.at(2).store(words as u64, Ordering::SeqCst);
+            pool.at(0).store(MAGIC, Ordering::SeqCst);
+            for i in 0..3 {
+                persist(pool.at(i));
+            }
+        } else if pool.at(0).load(Ordering::SeqCst) != MAGIC
+            || pool.at(1).load(Ordering::SeqCst) != descriptors as u64
+            || pool.at(2).load(Ordering::SeqCst) != words as u64
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a pool with this layout",
+            ));
+        } else {
+            pool.recover();
+        }
+        Ok(pool)
+    }
+
+    fn at(&self, offset: usize) -> &AtomicU64 {
+        debug_assert!(offset * 8 < self.len);
+        unsafe { &*self.base.add(offset) }
+    }
+
+    fn desc(&self, desc: usize, field: usize) -> &AtomicU64 {
+        self.at(HEADER_LEN + desc * DESC_LEN + field)
+    }
+
+    /// `(word, old, new)` of an entry of a descriptor
+    fn entry(&self, desc: usize, i: usize) -> (usize, u64, u64) {
+        let field = |j| self.desc(desc, ENTRIES + 3 * i + j).load(Ordering::SeqCst);
+        (field(0) as usize, field(1), field(2))
+    }
+
+    fn word(&self, word: usize) -> &AtomicU64 {
+        self.at(HEADER_LEN + self.descriptors * DESC_LEN + word)
+    }
+
+    fn status(&self, desc: usize) -> Status {
+        let status = self.desc(desc, STATUS);
+        let val = status.load(Ordering::SeqCst);
+        if val & DIRTY != 0 {
+            persist(status);
+            let _ = status.compare_exchange(val, val & !DIRTY, Ordering::SeqCst, Ordering::SeqCst);
+        }
+        Status::from((val & !DIRTY) as usize)
+    }
+
+    /// Pin a descriptor whose reference `seen` has been read from `word`, see `pool::Recycle`.
+    fn pin(&self, desc: usize, word: usize, seen: u64) -> bool {
+        let state = self.desc(desc, STATE);
+        state.fetch_add(1, Ordering::SeqCst);
+        if self.word(word).load(Ordering::SeqCst) == seen {
+            true
+        } else {
+            state.fetch_sub(1, Ordering::SeqCst);
+            false
+        }
+    }
+
+    fn unpin(&self, desc: usize) {
+        self.desc(desc, STATE).fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Value of a word, helping any MCAS in progress. Values are below `2^61`.
+    pub fn read(&self, word: usize) -> u64 {
+        loop {
+            let val = self.word(word).load(Ordering::SeqCst);
+            if !self.resolve(word, val) {
+                return val;
+            }
+        }
+    }
+
+    /// Deal with a word which does not hold a plain, persisted value. Returns `false` if `val`
+    /// is one.
+    fn resolve(&self, word: usize, val: u64) -> bool {
+        if val & RDCSS != 0 {
+            let desc = ((val & !FLAGS) >> 8) as usize;
+            if self.pin(desc, word, val) {
+                self.complete_rdcss(desc, (val & 0xff) as usize);
+                self.unpin(desc);
+            }
+        } else if val & MCAS != 0 {
+            let desc = (val & !FLAGS) as usize;
+            if self.pin(desc, word, val) {
+                self.help(desc);
+                self.unpin(desc);
+            }
+        } else if val & DIRTY != 0 {
+            persist(self.word(word));
+            let _ = self.word(word).compare_exchange(
+                val,
+                val & !DIRTY,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            );
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /// Second half of the RDCSS installing entry `i` of `desc`: keep the descriptor only if the
+    /// MCAS is still undecided.
+    fn complete_rdcss(&self, desc: usize, i: usize) {
+        let (word, old, _) = self.entry(desc, i);
+        let rdcss = RDCSS | (desc as u64) << 8 | i as u64;
+        let next = if self.status(desc) == Status::Undecided {
+            MCAS | desc as u64
+        } else {
+            old
+        };
+        let _ = self
+            .word(word)
+            .compare_exchange(rdcss, next, Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    /// Run the MCAS of `desc` to completion. Returns whether it succeeded.
+    fn help(&self, desc: usize) -> bool {
+        let count = self.desc(desc, COUNT).load(Ordering::SeqCst) as usize;
+        let mut success = true;
+        'iter: for i in 0..count {
+            let (word, old, _) = self.entry(desc, i);
+            let rdcss = RDCSS | (desc as u64) << 8 | i as u64;
+            loop {
+                if self.status(desc) != Status::Undecided {
+                    break 'iter;
+                }
+                match self.word(word).compare_exchange(
+                    old,
+                    rdcss,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                ) {
+                    Ok(_) => {
+                        self.complete_rdcss(desc, i);
+                        continue;
+                    }
+                    Err(val) if val == MCAS | desc as u64 => break,
+                    Err(val) if val == rdcss => self.complete_rdcss(desc, i),
+                    Err(val) => {
+                        if !self.resolve(word, val) {
+                            success = false;
+                            break 'iter;
+                        }
+                    }
+                }
+            }
+        }
+
+        // Every word must be persisted before the decision, which recovery relies on.
+        for i in 0..count {
+            persist(self.word(self.entry(desc, i).0));
+        }
+        let decision = if success {
+            Status::Successful
+        } else {
+            Status::Failed
+        };
+        let status = self.desc(desc, STATUS);
+        let _ = status.compare_exchange(
+            Status::Undecided as u64,
+            decision as u64 | DIRTY,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+        let success = self.status(desc) == Status::Successful;
+
+        for i in 0..count {
+            let (word, old, new) = self.entry(desc, i);
+            let val = if success { new } else { old } | DIRTY;
+            if self
+                .word(word)
+                .compare_exchange(MCAS | desc as u64, val, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                persist(self.word(word));
+                let _ = self.word(word).compare_exchange(
+                    val,
+                    val & !DIRTY,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                );
+            }
+        }
+        success
+    }
+
+    /// Take a free descriptor which no helper pins anymore.
+    fn alloc(&self) -> usize {
+        loop {
+            for desc in 0..self.descriptors {
+                if self
+                    .desc(desc, STATE)
+                    .compare_exchange(0, IN_USE, Ordering::SeqCst, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    return desc;
+                }
+            }
+            std::thread::yield_now();
+        }
+    }
+
+    /// Atomically replace `old` by `new` in every `(word, old, new)` entry, or fail if any word
+    /// does not hold `old`. Every word may appear at most once, and at most `MAX_WORDS` times.
+    pub fn pm_cas(&self, entries: &[(usize, u64, u64)]) -> bool {
+        assert!(entries.len() <= MAX_WORDS);
+        let mut sorted = [(0, 0, 0); MAX_WORDS];
+        sorted[..entries.len()].copy_from_slice(entries);
+        let sorted = &mut sorted[..entries.len()];
+        sorted.sort_unstable_by_key(|entry| entry.0);
+        for &(word, old, new) in sorted.iter() {
+            assert!(word < self.words && (old | new) & FLAGS == 0);
+        }
+
+        // The descriptor is persisted before it can be installed anywhere.
+        let desc = self.alloc();
+        self.desc(desc, COUNT).store(0, Ordering::SeqCst);
+        persist(self.desc(desc, COUNT));
+        for (i, &(word, old, new)) in sorted.iter().enumerate() {
+            self.desc(desc, ENTRIES + 3 * i)
+                .store(word as u64, Ordering::SeqCst);
+            self.desc(desc, ENTRIES + 3 * i + 1)
+                .store(old, Ordering::SeqCst);
+            self.desc(desc, ENTRIES + 3 * i + 2)
+                .store(new, Ordering::SeqCst);
+        }
+        self.desc(desc, STATUS)
+            .store(Status::Undecided as u64, Ordering::SeqCst);
+        self.desc(desc, COUNT)
+            .store(sorted.len() as u64, Ordering::SeqCst);
+        for field in 0..DESC_LEN {
+            persist(self.desc(desc, field));
+        }
+
+        let success = self.help(desc);
+        self.desc(desc, STATE).fetch_sub(IN_USE, Ordering::SeqCst);
+        success
+    }
+
+    /// Roll every descriptor in use forward if it succeeded and back otherwise, then clear the
+    /// `DIRTY` bit of every word. Runs before any other thread can use the pool.
+    fn recover(&self) {
+        for desc in 0..self.descriptors {
+            if self.desc(desc, STATE).load(Ordering::SeqCst) & IN_USE != 0 {
+                let success = self.status(desc) == Status::Successful;
+                let count = (self.desc(desc, COUNT).load(Ordering::SeqCst) as usize).min(MAX_WORDS);
+                for i in 0..count {
+                    let (word, old, new) = self.entry(desc, i);
+                    if word >= self.words {
+                        continue;
+                    }
+                    let val = self.word(word).load(Ordering::SeqCst) & !DIRTY;
+                    if val == MCAS | desc as u64 || val == RDCSS | (desc as u64) << 8 | i as u64 {
+                        let val = if success && val & MCAS != 0 { new } else { old };
+                        self.word(word).store(val, Ordering::SeqCst);
+                        persist(self.word(word));
+                    }
+                }
+            }
+            self.desc(desc, STATE).store(0, Ordering::SeqCst);
+            persist(self.desc(desc, STATE));
+        }
+        for word in 0..self.words {
+            let val = self.word(word).load(Ordering::SeqCst);
+            if val & DIRTY != 0 {
+                self.word(word).store(val & !DIRTY, Ordering::SeqCst);
+                persist(self.word(word));
+            }
+        }
+    }
+}
+
+impl Drop for PmPool {
+    fn drop(&mut self) {
+        unsafe { munmap(self.base as *mut c_void, self.len) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::process::Command;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    const DESC_NUM: usize = 32;
+    const WORD_NUM: usize = 16;
+    const INITIAL: u64 = 1000;
+    const CRASH_FILE: &str = "BEEE_PM_CAS_CRASH_FILE";
+
+    /// Move one unit between two words, keeping the total constant.
+    fn transfer(pool: &PmPool, from: usize, to: usize) {
+        loop {
+            let (a, b) = (pool.read(from), pool.read(to));
+            if a == 0 || pool.pm_cas(&[(from, a, a - 1), (to, b, b + 1)]) {
+                return;
+            }
+        }
+    }
+
+    /// Run by `recovers_after_crash` in a child process, which is killed in the middle of it.
+    #[test]
+    #[ignore]
+    fn crash_child() {
+        let path = match std::env::var(CRASH_FILE) {
+            Ok(path) => path,
+            Err(_) => return,
+        };
+        let pool = Arc::new(PmPool::open(Path::new(&path), DESC_NUM, WORD_NUM).unwrap());
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let pool = pool.clone();
+                thread::spawn(move || {
+                    for i in 0.. {
+                        transfer(&pool, (t + i) % WORD_NUM, (t * 5 + i * 3 + 1) % WORD_NUM);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn recovers_after_crash() {
+        let path = std::env::temp_dir().join(format!("beee-pm-cas-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let pool = PmPool::open(&path, DESC_NUM, WORD_NUM).unwrap();
+            for word in 0..WORD_NUM {
+                assert!(pool.pm_cas(&[(word, 0, INITIAL)]));
+            }
+        }
+
+        for round in 0..8 {
+            let mut child = Command::new(std::env::current_exe().unwrap())
+                .args([
+                    "--ignored",
+                    "--exact",
+                    "cas_utils::pm_cas::test::crash_child",
+                ])
+                .env(CRASH_FILE, &path)
+                .stdout(std::process::Stdio::null())
+                .spawn()
+                .unwrap();
+            thread::sleep(Duration::from_millis(50 + 29 * round));
+            child.kill().unwrap();
+            child.wait().unwrap();
+
+            let pool = PmPool::open(&path, DESC_NUM, WORD_NUM).unwrap();
+            let words: Vec<u64> = (0..WORD_NUM)
+                .map(|word| pool.word(word).load(Ordering::SeqCst))
+                .collect();
+            assert!(words.iter().all(|val| val & FLAGS == 0));
+            assert_eq!(words.iter().sum::<u64>(), INITIAL * WORD_NUM as u64);
+            transfer(&pool, 0, 1);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,172 @@
+//! Per-thread pools of reusable descriptors.
+//!
+//! A descriptor taken from a pool is never freed: when its thread exits the pool is dropped but
+//! the descriptors are leaked, because helpers of other threads may still hold pointers to them.
+//!
+//! A helper which loaded a descriptor pointer from a location must `pin` it before reading any
+//! field. Pinning checks that the location still holds the pointer after the pin count has been
+//! raised, and a descriptor is only reused once its pin count drops to zero. Reusing bumps the
+//! sequence number of the descriptor, so a stale helper can always tell which incarnation it is
+//! looking at.
+
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+const PIN_BITS: u32 = 20;
+const PIN_MASK: usize = (1 << PIN_BITS) - 1;
+
+/// Reuse state of a pooled descriptor: `seq << PIN_BITS | pins`.
+pub struct Recycle {
+    state: AtomicUsize,
+}
+
+impl Recycle {
+    pub fn new() -> Recycle {
+        Recycle {
+            state: AtomicUsize::new(0),
+        }
+    }
+
+    /// Pin `ptr`, which has been loaded from `location`. If it returns `true`, the descriptor is
+    /// still installed and cannot be reused until `unpin` is called.
+    pub fn pin<X>(&self, location: &AtomicPtr<X>, ptr: *mut X) -> bool {
+        self.state.fetch_add(1, Ordering::SeqCst);
+        if std::ptr::eq(location.load(Ordering::SeqCst), ptr) {
+            true
+        } else {
+            self.unpin();
+            false
+        }
+    }
+
+    /// Pin a descriptor without any check. The caller must already pin it.
+    pub fn hold(&self) {
+        self.state.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn unpin(&self) {
+        self.state.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Sequence number of the current incarnation
+    #[cfg(test)]
+    pub fn seq(&self) -> usize {
+        self.state.load(Ordering::SeqCst) >> PIN_BITS
+    }
+
+    /// Start a new incarnation. Fails if any helper still pins the descriptor.
+    fn try_reuse(&self) -> bool {
+        let state = self.state.load(Ordering::SeqCst);
+        state & PIN_MASK == 0
+            && self
+                .state
+                .compare_exchange(
+                    state,
+                    state + (1 << PIN_BITS),
+                    Ordering::SeqCst,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+    }
+}
+
+impl Default for Recycle {
+    fn default() -> Self {
+        Recycle::new()
+    }
+}
+
+/// A descriptor type which can live in the pool `K`
+pub trait Pooled<K>: Sized + 'static {
+    fn fresh() -> Self;
+    fn recycle(&self) -> &Recycle;
+}
+
+thread_local! {
+    static POOLS: RefCell<Vec<(TypeId, Box<dyn Any>)>> = RefCell::new(Vec::new());
+}
+
+fn with_pool<K: 'static, D: Pooled<K>, R>(f: impl FnOnce(&mut VecDeque<*mut D>) -> R) -> Option<R> {
+    POOLS
+        .try_with(|pools| {
+            let mut pools = pools.borrow_mut();
+            let id = TypeId::of::<(K, D)>();
+            let index = match pools.iter().position(|(pool_id, _)| *pool_id == id) {
+                Some(index) => index,
+                None => {
+                    pools.push((id, Box::new(VecDeque::<*mut D>::new())));
+                    pools.len() - 1
+                }
+            };
+            f(pools[index].1.downcast_mut().unwrap())
+        })
+        .ok()
+}
+
+/// Take a descriptor from the pool `K` of this thread, or allocate one if every pooled
+/// descriptor is still pinned. The caller has exclusive access to it until `give_back`.
+pub fn take<K: 'static, D: Pooled<K>>() -> *mut D {
+    let pooled = with_pool::<K, D, _>(|pool| {
+        for _ in 0..pool.len() {
+            let desc = pool.pop_front().unwrap();
+            if unsafe { (*desc).recycle().try_reuse() } {
+                return Some(desc);
+            }
+            pool.push_back(desc);
+        }
+        None
+    });
+    match pooled.flatten() {
+        Some(desc) => desc,
+        None => Box::leak(Box::new(D::fresh())),
+    }
+}
+
+/// Return a descriptor which is no longer installed anywhere to the pool `K` of this thread.
+pub fn give_back<K: 'static, D: Pooled<K>>(desc: *mut D) {
+    with_pool::<K, D, _>(|pool| pool.push_back(desc));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicPtr;
+
+    struct Kind;
+    struct Desc {
+        recycle: Recycle,
+    }
+    impl Pooled<Kind> for Desc {
+        fn fresh() -> Self {
+            Desc {
+                recycle: Recycle::new(),
+            }
+        }
+        fn recycle(&self) -> &Recycle {
+            &self.recycle
+        }
+    }
+
+    #[test]
+    fn pinned_desc_is_not_reused() {
+        let first = take::<Kind, Desc>();
+        let location = AtomicPtr::new(first);
+        give_back::<Kind, Desc>(first);
+
+        assert!(unsafe { (*first).recycle.pin(&location, first) });
+        let second = take::<Kind, Desc>();
+        assert!(!std::ptr::eq(first, second));
+        give_back::<Kind, Desc>(second);
+
+        unsafe { (*first).recycle.unpin() };
+        let seq = unsafe { (*first).recycle.seq() };
+        let third = take::<Kind, Desc>();
+        assert!(std::ptr::eq(first, third));
+        assert_eq!(unsafe { (*third).recycle.seq() }, seq + 1);
+
+        location.store(std::ptr::null_mut(), Ordering::SeqCst);
+        assert!(!unsafe { (*third).recycle.pin(&location, third) });
+    }
+}
@@ -0,0 +1,466 @@
+//! # Usage
+//!
+//! An object-based software transactional memory. A `TVar<T>` holds a value of any type, and a
+//! transaction may read and write `TVar`s of different types; it commits as a single MCAS.
+//!
+//! ```
+//! # use beee::cas_utils::stm::*;
+//! let count = TVar::new(0usize);
+//! let name = TVar::new(String::from("none"));
+//!
+//! atomically(|tx| {
+//!     let n = tx.read(&count);
+//!     tx.write(&count, n + 1);
+//!     tx.write(&name, format!("entry {}", n));
+//!     Ok::<_, StmControl<()>>(())
+//! })
+//! .unwrap();
+//! assert_eq!(count.load(), 1);
+//! assert_eq!(name.load(), "entry 0");
+//! ```
+//!
+//! `retry` blocks the transaction until one of the `TVar`s it read is changed by another
+//! transaction, and `Transaction::or_else` runs an alternative instead when the first one
+//! retries:
+//!
+//! ```
+//! # use beee::cas_utils::stm::*;
+//! let items = TVar::new(0usize);
+//! let taken = atomically(|tx| {
+//!     tx.or_else(
+//!         |tx| match tx.read(&items) {
+//!             0 => retry(),
+//!             n => {
+//!                 tx.write(&items, n - 1);
+//!                 Ok(true)
+//!             }
+//!         },
+//!         |_| Ok::<_, StmControl<()>>(false),
+//!     )
+//! });
+//! assert_eq!(taken, Ok(false));
+//! ```
+//!
+//! # Notes
+//!
+//! This is the OSTM of [Practical lock-freedom](https://www.cl.cam.ac.uk/techreports/UCAM-CL-TR-579.pdf),
+//! run on `AtomicMCasPtr` cells: a `TVar` is a location holding a type-erased value. The read
+//! set is validated by the compare-only entries of the commit MCAS. The cells a commit replaces
+//! are retired through `epoch`, and a run stays pinned until it commits or aborts, so the cells
+//! it read are not reused before. A run waiting in `retry` is not pinned, it is run again after
+//! any commit which wrote something.
+//!
+//! Until it commits, a transaction may see values of different `TVar`s which never existed at
+//! the same time. Such a run cannot commit and is retried, but the closure must not rely on
+//! invariants between `TVar`s for its own safety.
+
+use crate::cas_utils::m_cas::{AtomicMCasPtr, MCas, MCasPtr, SingleCas};
+use crate::epoch::{self, Guard};
+use std::any::Any;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+type Erased = Box<dyn Any + Send + Sync>;
+
+/// The location of a `TVar`, shared by its clones and the transactions which accessed it
+struct Location(AtomicMCasPtr<Erased>);
+
+impl Drop for Location {
+    fn drop(&mut self) {
+        // Nothing can replace the cell any more, but a thread may still be reading it.
+        let guard = epoch::pin();
+        unsafe { MCasPtr::retire(self.0.load(), &guard) };
+    }
+}
+
+/// A transactional variable
+pub struct TVar<T> {
+    inner: Arc<Location>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for TVar<T> {
+    fn clone(&self) -> Self {
+        TVar {
+            inner: self.inner.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Any + Send + Sync + Clone> TVar<T> {
+    pub fn new(val: T) -> TVar<T> {
+        TVar {
+            inner: Arc::new(Location(AtomicMCasPtr::new(Box::new(MCasPtr::new(
+                Box::new(val) as Erased,
+            ))))),
+            _marker: PhantomData,
+        }
+    }
+
+    /// The committed value, outside of any transaction
+    pub fn load(&self) -> T {
+        let _guard = epoch::pin();
+        value::<T>(self.inner.0.load()).clone()
+    }
+}
+
+fn value<'a, T: Any>(cell: *mut MCasPtr<Erased>) -> &'a T {
+    unsafe { (*cell).get() }.downcast_ref().unwrap()
+}
+
+/// Why a transaction stopped before returning a value
+#[derive(Debug, PartialEq)]
+pub enum StmControl<E> {
+    /// Block until a `TVar` which has been read changes, then run again.
+    Retry,
+    /// Give up. Nothing is written and `atomically` returns the error.
+    Abort(E),
+}
+
+pub type StmResult<R, E = ()> = Result<R, StmControl<E>>;
+
+pub fn retry<R, E>() -> StmResult<R, E> {
+    Err(StmControl::Retry)
+}
+
+/// # Fields
+///
+/// * `origin`: The `TVar`
+/// * `expect`: Cell seen by the first access of the transaction
+struct ReadEntry {
+    origin: Arc<Location>,
+    expect: *mut MCasPtr<Erased>,
+}
+
+/// # Fields
+///
+/// * `reads`: Every `TVar` accessed so far. Reads are never rolled back, so a transaction that
+///   retries waits for, and validates, everything it looked at.
+/// * `writes`: Cells written, as index into `reads` and new cell. Later writes shadow earlier
+///   ones, and rolling back a nested transaction truncates this log.
+pub struct Transaction {
+    reads: Vec<ReadEntry>,
+    writes: Vec<(usize, *mut MCasPtr<Erased>)>,
+}
+
+impl Transaction {
+    fn index(&mut self, origin: &Arc<Location>) -> usize {
+        match self
+            .reads
+            .iter()
+            .position(|entry| Arc::ptr_eq(&entry.origin, origin))
+        {
+            Some(index) => index,
+            None => {
+                self.reads.push(ReadEntry {
+                    origin: origin.clone(),
+                    expect: origin.0.load(),
+                });
+                self.reads.len() - 1
+            }
+        }
+    }
+
+    fn current(&self, index: usize) -> *mut MCasPtr<Erased> {
+        self.writes
+            .iter()
+            .rev()
+            .find(|(written, _)| *written == index)
+            .map_or(self.reads[index].expect, |(_, cell)| *cell)
+    }
+
+    pub fn read<T: Any + Send + Sync + Clone>(&mut self, tvar: &TVar<T>) -> T {
+        let index = self.index(&tvar.inner);
+        value::<T>(self.current(index)).clone()
+    }
+
+    pub fn write<T: Any + Send + Sync + Clone>(&mut self, tvar: &TVar<T>, val: T) {
+        let index = self.index(&tvar.inner);
+        let cell = Box::into_raw(Box::new(MCasPtr::new(Box::new(val) as Erased)));
+        self.writes.push((index, cell));
+    }
+
+    /// Run `f` as a nested transaction. If it aborts, only its own writes are rolled back and the
+    /// error is returned to the enclosing transaction, which may carry on.
+    pub fn nested<R, E>(
+        &mut self,
+        f: impl FnOnce(&mut Transaction) -> StmResult<R, E>,
+    ) -> StmResult<R, E> {
+        let checkpoint = self.writes.len();
+        let res = f(self);
+        if res.is_err() {
+            self.rollback(checkpoint);
+        }
+        res
+    }
+
+    /// Run `first`, or `second` instead if `first` retries. The whole transaction only retries
+    /// if both do.
+    pub fn or_else<R, E>(
+        &mut self,
+        first: impl FnOnce(&mut Transaction) -> StmResult<R, E>,
+        second: impl FnOnce(&mut Transaction) -> StmResult<R, E>,
+    ) -> StmResult<R, E> {
+        match self.nested(first) {
+            Err(StmControl::Retry) => self.nested(second),
+            res => res,
+        }
+    }
+
+    /// Drop the writes after `checkpoint`. They were never installed.
+    fn rollback(&mut self, checkpoint: usize) {
+        for (_, cell) in self.writes.drain(checkpoint..) {
+            drop(unsafe { Box::from_raw(cell) });
+        }
+    }
+
+    /// Whether every `TVar` read still holds the cell it was read from
+    fn is_valid(&self) -> bool {
+        self.reads
+            .iter()
+            .all(|entry| std::ptr::eq(entry.origin.0.load(), entry.expect))
+    }
+
+    fn commit(&mut self, guard: &Guard) -> bool {
+        let m_cas: Vec<SingleCas<Erased>> = (0..self.reads.len())
+            .map(|index| {
+                let entry = &self.reads[index];
+                let current = self.current(index);
+                if std::ptr::eq(current, entry.expect) {
+                    SingleCas::compare(&entry.origin.0, entry.expect)
+                } else {
+                    SingleCas::new(&entry.origin.0, entry.expect, current)
+                }
+            })
+            .collect();
+        if !m_cas.m_cas() {
+            return false;
+        }
+        for (index, entry) in self.reads.iter().enumerate() {
+            if !std::ptr::eq(self.current(index), entry.expect) {
+                unsafe { MCasPtr::retire(entry.expect, guard) };
+            }
+        }
+        // Only the last write of every `TVar` has been installed.
+        let writes = std::mem::take(&mut self.writes);
+        for (i, (index, cell)) in writes.iter().enumerate() {
+            if writes[i + 1..].iter().any(|(later, _)| later == index) {
+                drop(unsafe { Box::from_raw(*cell) });
+            }
+        }
+        true
+    }
+
+    fn clear(&mut self) {
+        self.rollback(0);
+        self.reads.clear();
+    }
+}
+
+/// Every commit which wrote something bumps `COMMITS`. Transactions waiting in `retry` are
+/// counted in `WAITERS` and sleep on `COMMITTED` until `COMMITS` moves. A commit only takes
+/// `COMMIT_LOCK` and notifies if it sees a waiter; otherwise the waiter sees the new count.
+static COMMITS: AtomicUsize = AtomicUsize::new(0);
+static WAITERS: AtomicUsize = AtomicUsize::new(0);
+static COMMIT_LOCK: Mutex<()> = Mutex::new(());
+static COMMITTED: Condvar = Condvar::new();
+
+/// Block until a commit may have changed what `tx` read. The read set is validated while `guard`
+/// is alive: once unpinned, its cells may be freed and their addresses reused, so a woken
+/// transaction is run again instead of validated.
+fn wait_for_change(tx: &Transaction, guard: Guard) {
+    WAITERS.fetch_add(1, Ordering::SeqCst);
+    let seen = COMMITS.load(Ordering::SeqCst);
+    if tx.is_valid() {
+        drop(guard);
+        let mut lock = COMMIT_LOCK.lock().unwrap();
+        while COMMITS.load(Ordering::SeqCst) == seen {
+            lock = COMMITTED.wait(lock).unwrap();
+        }
+    }
+    WAITERS.fetch_sub(1, Ordering::SeqCst);
+}
+
+fn notify_commit() {
+    COMMITS.fetch_add(1, Ordering::SeqCst);
+    if WAITERS.load(Ordering::SeqCst) > 0 {
+        drop(COMMIT_LOCK.lock().unwrap());
+        COMMITTED.notify_all();
+    }
+}
+
+/// Run `f` as a transaction until it commits or aborts.
+pub fn atomically<R, E>(mut f: impl FnMut(&mut Transaction) -> StmResult<R, E>) -> Result<R, E> {
+    let mut tx = Transaction {
+        reads: Vec::new(),
+        writes: Vec::new(),
+    };
+    loop {
+        let guard = epoch::pin();
+        match f(&mut tx) {
+            Ok(res) => {
+                let wrote = !tx.writes.is_empty();
+                if tx.commit(&guard) {
+                    if wrote {
+                        notify_commit();
+                    }
+                    return Ok(res);
+                }
+            }
+            Err(StmControl::Retry) => wait_for_change(&tx, guard),
+            Err(StmControl::Abort(err)) => {
+                tx.clear();
+                return Err(err);
+            }
+        }
+        tx.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn nested_and_or_else() {
+        let a = TVar::new(1i32);
+        let b = TVar::new(String::from("b"));
+
+        let res = atomically(|tx| {
+            tx.write(&a, 2);
+            let inner = tx.nested(|tx| {
+                tx.write(&a, 3);
+                tx.write(&b, String::from("inner"));
+                Err::<(), _>(StmControl::Abort("inner"))
+            });
+            assert_eq!(inner, Err(StmControl::Abort("inner")));
+            assert_eq!(tx.read(&a), 2);
+            assert_eq!(tx.read(&b), "b");
+
+            tx.or_else(
+                |tx| {
+                    tx.write(&b, String::from("first"));
+                    retry::<i32, &str>()
+                },
+                |tx| {
+                    let n = tx.read(&a);
+                    tx.write(&a, n * 10);
+                    Ok(n)
+                },
+            )
+        });
+        assert_eq!(res, Ok(2));
+        assert_eq!(a.load(), 20);
+        assert_eq!(b.load(), "b");
+
+        let res = atomically(|tx| {
+            tx.write(&a, 0);
+            Err::<(), _>(StmControl::Abort(()))
+        });
+        assert_eq!(res, Err(()));
+        assert_eq!(a.load(), 20);
+    }
+
+    #[test]
+    fn replaced_and_dropped_cells_are_retired() {
+        let first = Arc::new(1);
+        let second = Arc::new(2);
+        let a = TVar::new(first.clone());
+        atomically(|tx| {
+            tx.write(&a, second.clone());
+            Ok::<_, StmControl<()>>(())
+        })
+        .unwrap();
+        assert_eq!(*a.load(), 2);
+        let b = a.clone();
+        drop(a);
+        assert_eq!(*b.load(), 2);
+        drop(b);
+        for _ in 0..100_000 {
+            if Arc::strong_count(&first) == 1 && Arc::strong_count(&second) == 1 {
+                break;
+            }
+            epoch::pin().flush();
+            thread::yield_now();
+        }
+        assert_eq!(Arc::strong_count(&first), 1);
+        assert_eq!(Arc::strong_count(&second), 1);
+    }
+
+    #[test]
+    fn waiting_transaction_is_not_pinned() {
+        let flag = TVar::new(false);
+        let waiter = {
+            let flag = flag.clone();
+            thread::spawn(move || {
+                atomically(|tx| match tx.read(&flag) {
+                    false => retry(),
+                    true => Ok::<_, StmControl<()>>(()),
+                })
+                .unwrap()
+            })
+        };
+        while WAITERS.load(Ordering::SeqCst) == 0 {
+            thread::yield_now();
+        }
+        // Reclamation goes on while the waiter sleeps.
+        let val = Arc::new(());
+        let guard = epoch::pin();
+        unsafe { guard.defer_destroy(Box::into_raw(Box::new(val.clone()))) };
+        drop(guard);
+        for _ in 0..100_000 {
+            if Arc::strong_count(&val) == 1 {
+                break;
+            }
+            epoch::pin().flush();
+            thread::yield_now();
+        }
+        assert_eq!(Arc::strong_count(&val), 1);
+
+        atomically(|tx| {
+            tx.write(&flag, true);
+            Ok::<_, StmControl<()>>(())
+        })
+        .unwrap();
+        waiter.join().unwrap();
+    }
+
+    #[test]
+    fn retry_blocks_until_write() {
+        const ITEM_NUM: usize = 100;
+
+        let items = TVar::new(0usize);
+        let consumer = {
+            let items = items.clone();
+            thread::spawn(move || {
+                for _ in 0..ITEM_NUM {
+                    atomically(|tx| match tx.read(&items) {
+                        0 => retry(),
+                        n => {
+                            tx.write(&items, n - 1);
+                            Ok::<_, StmControl<()>>(())
+                        }
+                    })
+                    .unwrap();
+                }
+            })
+        };
+        for i in 0..ITEM_NUM {
+            if i % 10 == 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+            atomically(|tx| {
+                let n = tx.read(&items);
+                tx.write(&items, n + 1);
+                Ok::<_, StmControl<()>>(())
+            })
+            .unwrap();
+        }
+        consumer.join().unwrap();
+        assert_eq!(items.load(), 0);
+    }
+}
@@ -0,0 +1,277 @@
+//! # Usage
+//!
+//! `atomically` runs a closure as a transaction over `AtomicMCasPtr` locations. Reads and writes
+//! go through the `Tx` and are committed together as one MCAS. If the MCAS fails because a
+//! location changed in the meantime, the closure is run again.
+//!
+//! ```
+//! # use beee::cas_utils::m_cas::*;
+//! # use beee::epoch;
+//! # use beee::cas_utils::tx::*;
+//! let x = AtomicMCasPtr::new(Box::new(MCasPtr::new(1)));
+//! let y = AtomicMCasPtr::new(Box::new(MCasPtr::new(0)));
+//!
+//! let sum = atomically(|tx| {
+//!     let a = tx.read(&x);
+//!     tx.write(&y, a + 1);
+//!     Ok::<_, ()>(a + tx.read(&y))
+//! });
+//! assert_eq!(sum, Ok(3));
+//! assert_eq!(*y.read(&epoch::pin()), 2);
+//! ```
+//!
+//! # Notes
+//!
+//! Locations which are only read are committed as compare-only entries. Locations which are
+//! written are compared against the cell seen by their first read or write. The cells a commit
+//! replaces are retired through `epoch`, which also keeps the cells a run reads alive.
+//!
+//! Until it commits, a transaction may see values of different locations which never existed
+//! at the same time. Such a run cannot commit and is retried, but the closure must not rely on
+//! invariants between locations for its own safety.
+
+use crate::cas_utils::m_cas::{AtomicMCasPtr, MCas, MCasPtr, SingleCas};
+use crate::epoch::{self, Guard};
+
+#[derive(Debug, PartialEq)]
+pub enum TxError<E> {
+    /// The closure returned `Err`. Nothing has been written.
+    Aborted(E),
+    /// The transaction conflicted more often than the retry limit.
+    RetryLimit,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TxOptions {
+    /// Number of times a conflicting transaction is run again. `None` retries forever.
+    pub retry_limit: Option<usize>,
+}
+
+/// # Fields
+///
+/// * `origin`: The location
+/// * `expect`: Cell seen by the first access of this transaction
+/// * `new`: Cell written by this transaction, not installed anywhere yet
+struct TxEntry<T> {
+    origin: AtomicMCasPtr<T>,
+    expect: *mut MCasPtr<T>,
+    new: Option<*mut MCasPtr<T>>,
+}
+
+/// Read and write log of a transaction
+pub struct Tx<T> {
+    log: Vec<TxEntry<T>>,
+}
+
+impl<T: 'static> Tx<T> {
+    fn entry(&mut self, origin: &AtomicMCasPtr<T>) -> &mut TxEntry<T> {
+        let index = match self
+            .log
+            .iter()
+            .position(|entry| entry.origin.same_location(origin))
+        {
+            Some(index) => index,
+            None => {
+                self.log.push(TxEntry {
+                    origin: origin.clone(),
+                    expect: origin.load(),
+                    new: None,
+                });
+                self.log.len() - 1
+            }
+        };
+        &mut self.log[index]
+    }
+
+    /// Value of `origin` in this transaction
+    pub fn read(&mut self, origin: &AtomicMCasPtr<T>) -> T
+    where
+        T: Clone,
+    {
+        let entry = self.entry(origin);
+        let cell = entry.new.unwrap_or(entry.expect);
+        unsafe { (*cell).get().clone() }
+    }
+
+    /// Write `val` to `origin` when the transaction commits
+    pub fn write(&mut self, origin: &AtomicMCasPtr<T>, val: T) {
+        let cell = Box::into_raw(Box::new(MCasPtr::new(val)));
+        let entry = self.entry(origin);
+        if let Some(old) = entry.new.replace(cell) {
+            drop(unsafe { Box::from_raw(old) });
+        }
+    }
+
+    fn commit(&self) -> bool {
+        let m_cas: Vec<SingleCas<T>> = self
+            .log
+            .iter()
+            .map(|entry| match entry.new {
+                Some(new) => SingleCas::new(&entry.origin, entry.expect, new),
+                None => SingleCas::compare(&entry.origin, entry.expect),
+            })
+            .collect();
+        m_cas.m_cas()
+    }
+
+    /// Retire the cells replaced by a successful commit and forget the log.
+    fn retire(&mut self, guard: &Guard) {
+        for entry in self.log.drain(..) {
+            if entry.new.is_some() {
+                unsafe { MCasPtr::retire(entry.expect, guard) };
+            }
+        }
+    }
+
+    /// Forget the log. Cells written by this run were never installed and are freed.
+    fn clear(&mut self) {
+        for entry in self.log.drain(..) {
+            if let Some(new) = entry.new {
+                drop(unsafe { Box::from_raw(new) });
+            }
+        }
+    }
+}
+
+/// Run `f` atomically, retrying forever on conflicts.
+pub fn atomically<T, R, E, F>(f: F) -> Result<R, TxError<E>>
+where
+    T: 'static,
+    F: FnMut(&mut Tx<T>) -> Result<R, E>,
+{
+    atomically_with(TxOptions::default(), f)
+}
+
+pub fn atomically_with<T, R, E, F>(options: TxOptions, mut f: F) -> Result<R, TxError<E>>
+where
+    T: 'static,
+    F: FnMut(&mut Tx<T>) -> Result<R, E>,
+{
+    let mut tx = Tx { log: Vec::new() };
+    let mut retries = 0;
+    loop {
+        let guard = epoch::pin();
+        match f(&mut tx) {
+            Ok(res) => {
+                if tx.commit() {
+                    // The written cells are installed now.
+                    tx.retire(&guard);
+                    return Ok(res);
+                }
+            }
+            Err(err) => {
+                tx.clear();
+                return Err(TxError::Aborted(err));
+            }
+        }
+        tx.clear();
+        if options.retry_limit.is_some_and(|limit| retries >= limit) {
+            return Err(TxError::RetryLimit);
+        }
+        retries += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::epoch;
+    use std::thread;
+
+    fn location(val: i64) -> AtomicMCasPtr<i64> {
+        AtomicMCasPtr::new(Box::new(MCasPtr::new(val)))
+    }
+
+    #[test]
+    fn abort_and_retry_limit() {
+        let x = location(1);
+        let res = atomically(|tx| {
+            tx.write(&x, 2);
+            if tx.read(&x) == 2 {
+                return Err("abort");
+            }
+            Ok(())
+        });
+        assert_eq!(res, Err(TxError::Aborted("abort")));
+        assert_eq!(*x.read(&epoch::pin()), 1);
+
+        // Every run changes `x` behind the back of the transaction, so it never commits.
+        let mut runs = 0;
+        let options = TxOptions {
+            retry_limit: Some(3),
+        };
+        let res = atomically_with(options, |tx| {
+            runs += 1;
+            let a = tx.read(&x);
+            assert!(atomically(|inner| {
+                inner.write(&x, a + 1);
+                Ok::<_, ()>(())
+            })
+            .is_ok());
+            tx.write(&x, 0);
+            Ok::<_, ()>(())
+        });
+        assert_eq!(res, Err(TxError::RetryLimit));
+        assert_eq!(runs, 4);
+        assert_eq!(*x.read(&epoch::pin()), 5);
+    }
+
+    #[test]
+    fn replaced_cells_are_retired() {
+        use std::sync::Arc;
+
+        let first = Arc::new(1);
+        let x = AtomicMCasPtr::new(Box::new(MCasPtr::new(first.clone())));
+        let res = atomically(|tx| {
+            let a = tx.read(&x);
+            tx.write(&x, Arc::new(*a + 1));
+            Ok::<_, ()>(())
+        });
+        assert_eq!(res, Ok(()));
+        assert_eq!(**x.read(&epoch::pin()), 2);
+        for _ in 0..100_000 {
+            if Arc::strong_count(&first) == 1 {
+                break;
+            }
+            epoch::pin().flush();
+            thread::yield_now();
+        }
+        assert_eq!(Arc::strong_count(&first), 1);
+    }
+
+    #[test]
+    fn multi_thread_transfer() {
+        const THREAD_NUM: usize = 8;
+        const ITER_NUM: usize = 1000;
+
+        let accounts: Vec<AtomicMCasPtr<i64>> = (0..4).map(|_| location(100)).collect();
+        let threads: Vec<_> = (0..THREAD_NUM)
+            .map(|i| {
+                let accounts = accounts.clone();
+                thread::spawn(move || {
+                    for j in 0..ITER_NUM {
+                        let from = &accounts[(i + j) % 4];
+                        let to = &accounts[(i + j + 1) % 4];
+                        let res = atomically(|tx| {
+                            let balance = tx.read(from);
+                            tx.write(from, balance - 1);
+                            let balance = tx.read(to);
+                            tx.write(to, balance + 1);
+                            Ok::<_, ()>(())
+                        });
+                        assert!(res.is_ok());
+
+                        // A read-only transaction sees a consistent total.
+                        let total = atomically(|tx| {
+                            Ok::<_, ()>(accounts.iter().map(|a| tx.read(a)).sum::<i64>())
+                        });
+                        assert_eq!(total, Ok(400));
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+}
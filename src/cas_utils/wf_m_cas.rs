@@ -0,0 +1,470 @@
+//! # Usage
+//!
+//! A wait-free variant of `m_cas`. It works on the same `AtomicMCasPtr` locations with the same
+//! `SingleCas` entries: wrapping the entries in `WaitFree` is the only difference.
+//!
+//! ```
+//! # use beee::cas_utils::m_cas::*;
+//! # use beee::epoch;
+//! # use beee::cas_utils::wf_m_cas::WaitFree;
+//! let atomic_num = AtomicMCasPtr::new(Box::new(MCasPtr::new(1)));
+//! let num1_ptr = atomic_num.load();
+//! let num2_ptr = Box::into_raw(Box::new(MCasPtr::new(2)));
+//!
+//! assert!(WaitFree(vec![SingleCas::new(&atomic_num, num1_ptr, num2_ptr)]).m_cas());
+//! assert_eq!(*atomic_num.read(&epoch::pin()), 2);
+//! ```
+//!
+//! # Notes
+//!
+//! This follows the fast-path/slow-path methodology of
+//! [A Methodology for Creating Fast Wait-Free Data Structures](https://dl.acm.org/doi/10.1145/2145816.2145835).
+//!
+//! * Fast path: the lock-free MCAS, except that the owner gives up after `MAX_CONFLICTS`
+//!   conflicts with other operations. Giving up fails the attempt, which has no effect.
+//! * Slow path: the MCAS is announced in a global announcement array and then helped to
+//!   completion. Before every operation, a thread helps the next announced operation in
+//!   round-robin order, so an announced operation is finished by every thread at the latest
+//!   `ANNOUNCE_SLOTS` operations later.
+//!
+//! A thread claims an announcement slot on its first MCAS and releases it when it exits. When
+//! more than `ANNOUNCE_SLOTS` threads are alive the slow path of the others is only lock-free.
+//!
+//! A contention manager of another MCAS which decides `AbortOther` on a MCAS of this module
+//! helps it instead, on both paths. An abort would make it fail although its locations match,
+//! and its caller would retry without bound. For the same reason another MCAS checking its
+//! reads fails itself rather than aborting one of this module. Only when two MCASes of this
+//! module check reads against each other one of them is aborted, and then run again: against
+//! each other they are only lock-free.
+
+use crate::cas_utils::c_cas::CCasUnion;
+use crate::cas_utils::contention::{Conflict, ContentionManager, Decision};
+use crate::cas_utils::m_cas::{self, MCas, MCasKind, MCasUnion, SingleCas};
+use crate::cas_utils::pool::{self, Pooled, Recycle};
+use std::cell::Cell;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+/// Conflicts the fast path tolerates before the MCAS moves to the slow path
+const MAX_CONFLICTS: usize = 16;
+/// Size of the announcement array
+const ANNOUNCE_SLOTS: usize = 64;
+
+/// The type-erased part of an announcement. `help` knows the real type of the record.
+#[repr(C)]
+struct Header {
+    help: fn(*const Header),
+}
+
+/// Announcement record of a thread for MCAS over `T`. Records are pooled and never freed, so
+/// a helper can always read a record it found in a slot; `desc` tells whether it is still
+/// announced.
+#[repr(C)]
+struct Announce<T> {
+    header: Header,
+    recycle: Recycle,
+    desc: AtomicPtr<CCasUnion<MCasUnion<T>>>,
+}
+
+/// Pool marker of announcement records
+struct AnnounceKind;
+
+impl<T: 'static> Pooled<AnnounceKind> for Announce<T> {
+    fn fresh() -> Self {
+        Announce {
+            header: Header {
+                help: help_announced::<T>,
+            },
+            recycle: Recycle::new(),
+            desc: AtomicPtr::new(null_mut()),
+        }
+    }
+    fn recycle(&self) -> &Recycle {
+        &self.recycle
+    }
+}
+
+fn help_announced<T: 'static>(header: *const Header) {
+    let announce = unsafe { &*(header as *const Announce<T>) };
+    let desc_ptr = announce.desc.load(Ordering::SeqCst);
+    if desc_ptr.is_null() {
+        return;
+    }
+    let recycle = Pooled::<MCasKind>::recycle(unsafe { &*desc_ptr });
+    if recycle.pin(&announce.desc, desc_ptr) {
+        m_cas::desc_of(desc_ptr).help(desc_ptr);
+        recycle.unpin();
+    }
+}
+
+struct Slot {
+    owned: AtomicBool,
+    op: AtomicPtr<Header>,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: Slot = Slot {
+    owned: AtomicBool::new(false),
+    op: AtomicPtr::new(null_mut()),
+};
+
+static ANNOUNCEMENTS: [Slot; ANNOUNCE_SLOTS] = [EMPTY_SLOT; ANNOUNCE_SLOTS];
+
+/// The announcement slot of a thread, and the next slot it is going to help
+struct ThreadSlot {
+    index: Option<usize>,
+    next_check: Cell<usize>,
+}
+
+impl ThreadSlot {
+    fn claim() -> ThreadSlot {
+        let index = ANNOUNCEMENTS.iter().position(|slot| {
+            slot.owned
+                .compare_exchange(false, true, Ordering::SeqCst, Ordering::Relaxed)
+                .is_ok()
+        });
+        ThreadSlot {
+            index,
+            next_check: Cell::new(index.unwrap_or(0)),
+        }
+    }
+
+    /// Help the announced operation of the next slot, if any.
+    fn help_next(&self) {
+        let index = self.next_check.get();
+        self.next_check.set((index + 1) % ANNOUNCE_SLOTS);
+        let op = ANNOUNCEMENTS[index].op.load(Ordering::SeqCst);
+        if !op.is_null() && Some(index) != self.index {
+            (unsafe { &*op }.help)(op);
+        }
+    }
+}
+
+impl Drop for ThreadSlot {
+    fn drop(&mut self) {
+        if let Some(index) = self.index {
+            ANNOUNCEMENTS[index].owned.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+thread_local! {
+    static THREAD_SLOT: ThreadSlot = ThreadSlot::claim();
+}
+
+/// Contention manager of the fast path: help, but give up after `max_conflicts` conflicts.
+struct FastPath {
+    max_conflicts: usize,
+    gave_up: Cell<bool>,
+}
+
+impl ContentionManager for FastPath {
+    fn on_conflict(&self, conflict: &Conflict) -> Decision {
+        if conflict.attempt > self.max_conflicts {
+            self.gave_up.set(true);
+            Decision::AbortSelf
+        } else {
+            Decision::Help
+        }
+    }
+}
+
+/// Run a MCAS on the slow path: announce it, then help it to completion. It is run again if
+/// another wait-free MCAS aborted it.
+fn slow_path<T: 'static>(entries: &[SingleCas<T>]) -> bool {
+    let announce_ptr = pool::take::<AnnounceKind, Announce<T>>();
+    let announce = unsafe { &*announce_ptr };
+    let index = THREAD_SLOT.try_with(|slot| slot.index).ok().flatten();
+    let success = loop {
+        let desc_ptr = m_cas::take_desc(|desc_entries| {
+            desc_entries.extend(entries.iter().cloned());
+            desc_entries.sort_unstable();
+        });
+        m_cas::desc_of(desc_ptr).set_wait_free();
+
+        announce.desc.store(desc_ptr, Ordering::SeqCst);
+        if let Some(index) = index {
+            ANNOUNCEMENTS[index].op.store(
+                &announce.header as *const Header as *mut Header,
+                Ordering::SeqCst,
+            );
+        }
+        let success = m_cas::desc_of(desc_ptr).help(desc_ptr);
+        if let Some(index) = index {
+            ANNOUNCEMENTS[index].op.store(null_mut(), Ordering::SeqCst);
+        }
+        announce.desc.store(null_mut(), Ordering::SeqCst);
+
+        let aborted = m_cas::desc_of(desc_ptr).was_aborted();
+        pool::give_back::<MCasKind, CCasUnion<MCasUnion<T>>>(desc_ptr);
+        if success || !aborted {
+            break success;
+        }
+    };
+    pool::give_back::<AnnounceKind, Announce<T>>(announce_ptr);
+    success
+}
+
+/// Run a MCAS on the fast path. Returns `None` if it gave up after `max_conflicts` conflicts.
+fn fast_path<T: 'static>(entries: &[SingleCas<T>], max_conflicts: usize) -> Option<bool> {
+    let desc_ptr = m_cas::take_desc(|desc_entries| {
+        desc_entries.extend(entries.iter().cloned());
+        desc_entries.sort_unstable();
+    });
+    m_cas::desc_of(desc_ptr).set_wait_free();
+    let fast_path = FastPath {
+        max_conflicts,
+        gave_up: Cell::new(false),
+    };
+    let success = m_cas::desc_of(desc_ptr).help_with(desc_ptr, &fast_path);
+    let aborted = m_cas::desc_of(desc_ptr).was_aborted();
+    pool::give_back::<MCasKind, CCasUnion<MCasUnion<T>>>(desc_ptr);
+
+    // A MCAS which gave up or was aborted may also have failed on its own, but retrying a
+    // failed MCAS is harmless: the failed attempt did not change anything.
+    if success || !(fast_path.gave_up.get() || aborted) {
+        Some(success)
+    } else {
+        None
+    }
+}
+
+fn wait_free_m_cas<T: 'static>(entries: &[SingleCas<T>]) -> bool {
+    let _ = THREAD_SLOT.try_with(ThreadSlot::help_next);
+    fast_path(entries, MAX_CONFLICTS).unwrap_or_else(|| slow_path(entries))
+}
+
+/// Entries of a MCAS which is run wait-free, e.g. `WaitFree(vec![...]).m_cas()`.
+pub struct WaitFree<M>(pub M);
+
+impl<T: 'static, M: AsRef<[SingleCas<T>]>> MCas<T> for WaitFree<M> {
+    fn m_cas(&self) -> bool {
+        wait_free_m_cas(self.0.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cas_utils::contention::{Aggressive, Managed};
+    use crate::cas_utils::m_cas::{AtomicMCasPtr, MCasPtr};
+    use crate::cas_utils::Status;
+    use crate::epoch;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn single_thread_wait_free_m_cas() {
+        let ptrs: Vec<*mut MCasPtr<i32>> = (0..4)
+            .map(|val| Box::into_raw(Box::new(MCasPtr::new(val))))
+            .collect();
+        let atomic: Vec<AtomicMCasPtr<i32>> = ptrs[..2]
+            .iter()
+            .map(|&ptr| unsafe { AtomicMCasPtr::from_raw(ptr) })
+            .collect();
+
+        assert!(!WaitFree([
+            SingleCas::new(&atomic[0], ptrs[0], ptrs[2]),
+            SingleCas::new(&atomic[1], ptrs[2], ptrs[3]),
+        ])
+        .m_cas());
+        assert!(WaitFree(vec![
+            SingleCas::new(&atomic[1], ptrs[1], ptrs[3]),
+            SingleCas::new(&atomic[0], ptrs[0], ptrs[2]),
+        ])
+        .m_cas());
+        assert_eq!(*atomic[0].read(&epoch::pin()), 2);
+        assert_eq!(*atomic[1].read(&epoch::pin()), 3);
+
+        // The slow path on its own must behave the same.
+        assert!(slow_path(&[SingleCas::new(&atomic[0], ptrs[2], ptrs[0])]));
+        assert_eq!(*atomic[0].read(&epoch::pin()), 0);
+    }
+
+    #[test]
+    fn wait_free_m_cas_is_not_aborted() {
+        let location = AtomicMCasPtr::new(Box::new(MCasPtr::new(0)));
+        let cell = location.load();
+        let new = Box::into_raw(Box::new(MCasPtr::new(1)));
+        let desc_ptr =
+            m_cas::take_desc(|entries| entries.push(SingleCas::new(&location, cell, new)));
+        m_cas::desc_of(desc_ptr).set_wait_free();
+        assert!(m_cas::stall(desc_ptr, Status::Undecided));
+
+        // `Aggressive` decides `AbortOther`, but helps the stalled MCAS instead, which then
+        // makes its own expectation fail.
+        assert!(!Managed([SingleCas::new(&location, cell, cell)], Aggressive).m_cas());
+        assert!(m_cas::desc_of(desc_ptr).help(desc_ptr));
+        assert_eq!(*location.read(&epoch::pin()), 1);
+        pool::give_back::<MCasKind, CCasUnion<MCasUnion<i32>>>(desc_ptr);
+    }
+
+    #[test]
+    fn slow_path_completes_under_contention() {
+        const THREAD_NUM: usize = 4;
+        const ITER_NUM: usize = 2000;
+
+        // Every MCAS writes the shared cell back into `shared`, so the expected cells of a
+        // wait-free MCAS always match and it may only fail if it is aborted.
+        let shared = AtomicMCasPtr::new(Box::new(MCasPtr::new(0)));
+        let counters: Vec<_> = (0..THREAD_NUM)
+            .map(|_| AtomicMCasPtr::new(Box::new(MCasPtr::new(0))))
+            .collect();
+        let slow_paths = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicBool::new(false));
+        let stalls = Arc::new(AtomicUsize::new(0));
+        // Keeps a MCAS stalled in `shared` until another thread has helped it, so that the
+        // wait-free MCASes keep running into it.
+        let staller = {
+            let (shared, done) = (shared.clone(), done.clone());
+            let stalls = stalls.clone();
+            thread::spawn(move || {
+                let shared_cell = shared.load();
+                while !done.load(Ordering::SeqCst) {
+                    let desc_ptr = m_cas::take_desc(|entries| {
+                        entries.push(SingleCas::new(&shared, shared_cell, shared_cell))
+                    });
+                    if m_cas::stall(desc_ptr, Status::Undecided) {
+                        stalls.fetch_add(1, Ordering::SeqCst);
+                        while !done.load(Ordering::SeqCst)
+                            && std::ptr::eq(shared.get_m_cas_ptr(Ordering::SeqCst), desc_ptr as _)
+                        {
+                            thread::yield_now();
+                        }
+                    }
+                    m_cas::desc_of(desc_ptr).help(desc_ptr);
+                    pool::give_back::<MCasKind, CCasUnion<MCasUnion<usize>>>(desc_ptr);
+                }
+            })
+        };
+        while stalls.load(Ordering::SeqCst) == 0 {
+            thread::yield_now();
+        }
+        let wait_free: Vec<_> = counters
+            .iter()
+            .map(|counter| {
+                let (shared, counter) = (shared.clone(), counter.clone());
+                let slow_paths = slow_paths.clone();
+                thread::spawn(move || {
+                    let shared_cell = shared.load();
+                    for _ in 0..ITER_NUM {
+                        let cell = counter.load();
+                        let new =
+                            Box::into_raw(Box::new(MCasPtr::new(unsafe { *(*cell).get() + 1 })));
+                        let entries = [
+                            SingleCas::new(&shared, shared_cell, shared_cell),
+                            SingleCas::new(&counter, cell, new),
+                        ];
+                        // Give up at the first conflict, as after `MAX_CONFLICTS` of them.
+                        let success = fast_path(&entries, 0).unwrap_or_else(|| {
+                            slow_paths.fetch_add(1, Ordering::SeqCst);
+                            slow_path(&entries)
+                        });
+                        assert!(success);
+                        thread::yield_now();
+                    }
+                })
+            })
+            .collect();
+        let aggressive: Vec<_> = (0..THREAD_NUM)
+            .map(|_| {
+                let (shared, done) = (shared.clone(), done.clone());
+                thread::spawn(move || {
+                    let shared_cell = shared.load();
+                    while !done.load(Ordering::SeqCst) {
+                        let entries = [SingleCas::new(&shared, shared_cell, shared_cell)];
+                        Managed(entries, Aggressive).m_cas();
+                        thread::yield_now();
+                    }
+                })
+            })
+            .collect();
+        for t in wait_free {
+            t.join().unwrap();
+        }
+        done.store(true, Ordering::SeqCst);
+        for t in aggressive.into_iter().chain(Some(staller)) {
+            t.join().unwrap();
+        }
+        for counter in &counters {
+            assert_eq!(*counter.read(&epoch::pin()), ITER_NUM);
+        }
+        assert!(slow_paths.load(Ordering::SeqCst) > 0);
+    }
+
+    #[test]
+    fn read_check_is_not_aborted() {
+        const THREAD_NUM: usize = 4;
+        const ITER_NUM: usize = 1000;
+
+        // The wait-free MCASes only read `shared`, and the readers only write the shared cell
+        // back into it, so a wait-free MCAS may only fail if it is aborted. Every thread
+        // alternates between a wait-free MCAS on its counter and a reader of the counter of
+        // the next thread, which stalls while checking that read. The wait-free MCAS writing
+        // the counter then runs into the reader when checking its own read of `shared`.
+        let shared = AtomicMCasPtr::new(Box::new(MCasPtr::new(0)));
+        let counters: Vec<_> = (0..THREAD_NUM)
+            .map(|_| AtomicMCasPtr::new(Box::new(MCasPtr::new(0))))
+            .collect();
+        let finished = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..THREAD_NUM)
+            .map(|i| {
+                let shared = shared.clone();
+                let counter = counters[i].clone();
+                let next = counters[(i + 1) % THREAD_NUM].clone();
+                let finished = finished.clone();
+                thread::spawn(move || {
+                    let shared_cell = shared.load();
+                    for _ in 0..ITER_NUM {
+                        let cell = counter.load();
+                        let new =
+                            Box::into_raw(Box::new(MCasPtr::new(unsafe { *(*cell).get() + 1 })));
+                        assert!(WaitFree([
+                            SingleCas::compare(&shared, shared_cell),
+                            SingleCas::new(&counter, cell, new),
+                        ])
+                        .m_cas());
+
+                        let desc_ptr = m_cas::take_desc(|entries| {
+                            entries.push(SingleCas::new(&shared, shared_cell, shared_cell));
+                            entries.push(SingleCas::compare(&next, next.load()));
+                            entries.sort_unstable();
+                        });
+                        if m_cas::stall(desc_ptr, Status::ReadChecking) {
+                            while finished.load(Ordering::SeqCst) < THREAD_NUM - 1
+                                && std::ptr::eq(
+                                    shared.get_m_cas_ptr(Ordering::SeqCst),
+                                    desc_ptr as _,
+                                )
+                            {
+                                thread::yield_now();
+                            }
+                        }
+                        m_cas::desc_of(desc_ptr).help(desc_ptr);
+                        pool::give_back::<MCasKind, CCasUnion<MCasUnion<usize>>>(desc_ptr);
+                        thread::yield_now();
+                    }
+                    finished.fetch_add(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        for counter in &counters {
+            assert_eq!(*counter.read(&epoch::pin()), ITER_NUM);
+        }
+    }
+
+    #[test]
+    fn slots_are_released_on_thread_exit() {
+        for _ in 0..ANNOUNCE_SLOTS * 2 {
+            thread::spawn(|| {
+                assert!(THREAD_SLOT.with(|slot| slot.index.is_some()));
+            })
+            .join()
+            .unwrap();
+        }
+    }
+}
@@ -1,25 +1,335 @@
-use std::sync::atomic::AtomicI32;
+//! # Usage
+//!
+//! Epoch based reclamation. A thread `pin`s itself before reading shared memory, and memory
+//! unlinked from a shared structure is handed to the `Guard` instead of being freed. It is
+//! freed once every thread which was pinned at that time has unpinned.
+//!
+//! ```
+//! # use beee::epoch;
+//! let ptr = Box::into_raw(Box::new(1));
+//! let guard = epoch::pin();
+//! // ... unlink `ptr`, other threads may still be reading it ...
+//! unsafe { guard.defer_destroy(ptr) };
+//! ```
+//!
+//! # Notes
+//!
+//! This is the epoch scheme of
+//! [Practical lock-freedom](https://www.cl.cam.ac.uk/techreports/UCAM-CL-TR-579.pdf), section
+//! 5.2.3. The global epoch only advances when every pinned thread has seen the current one,
+//! so garbage retired in epoch `e` cannot be reached by anybody once the global epoch is
+//! `e + 2`.
+//!
+//! Garbage left by a thread which exits is moved to a global list and freed by other threads.
 
-pub static GLOBAL_EPOCH: AtomicI32 = AtomicI32::new(0);
+use std::cell::{Cell, RefCell};
+use std::marker::PhantomData;
+use std::ptr::null_mut;
+use std::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::Mutex;
 
-thread_local! {
-    pub(crate) static thread_status: RefCell<ThreadStatus> = RefCell::new(ThreadStatus::new());
+pub static GLOBAL_EPOCH: AtomicUsize = AtomicUsize::new(0);
+
+/// Set in the state of a pinned participant, whose epoch is in the other bits
+const PINNED: usize = 1;
+/// A thread tries to advance the epoch and collect every `COLLECT_EVERY` pins
+const COLLECT_EVERY: usize = 64;
+/// Garbage a thread keeps before it tries to collect on its own
+const MAX_RETIRED: usize = 256;
+
+/// Epoch state of a thread, `epoch << 1 | PINNED` while pinned and `0` otherwise. Participants
+/// are leaked and reused by new threads, so the list in `PARTICIPANTS` is only ever pushed to
+/// and is walked without locking.
+struct Participant {
+    in_use: AtomicBool,
+    state: AtomicUsize,
+    next: AtomicPtr<Participant>,
+}
+
+static PARTICIPANTS: AtomicPtr<Participant> = AtomicPtr::new(null_mut());
+
+/// Every participant, newest first
+fn participants() -> impl Iterator<Item = &'static Participant> {
+    let head = unsafe { PARTICIPANTS.load(Ordering::Acquire).as_ref() };
+    std::iter::successors(head, |participant| unsafe {
+        participant.next.load(Ordering::Acquire).as_ref()
+    })
+}
+
+/// A destructor waiting for its epoch to pass
+struct Deferred {
+    epoch: usize,
+    f: Box<dyn FnOnce()>,
 }
 
+/// Deferred destructors move to `ORPHANS` when their thread exits. Whoever defers them
+/// guarantees they may run on any thread.
+unsafe impl Send for Deferred {}
+
+static ORPHANS: Mutex<Vec<Deferred>> = Mutex::new(Vec::new());
+
+/// # Fields
+///
+/// * `participant`: The participant of this thread
+/// * `guards`: Guards alive on this thread, it is pinned while this is not zero
+/// * `pins`: Pins so far, to collect every `COLLECT_EVERY` of them
+/// * `retired_list`: Garbage of this thread, oldest first
 struct ThreadStatus {
-    pub(crate) retired_list: Vec<Box<Fn()>>
+    participant: &'static Participant,
+    guards: Cell<usize>,
+    pins: Cell<usize>,
+    retired_list: RefCell<Vec<Deferred>>,
 }
 
-struct Guard {}
+thread_local! {
+    static THREAD_STATUS: ThreadStatus = ThreadStatus::new();
+}
 
 impl ThreadStatus {
-    pub fn new() -> ThreadStatus {
-        return ThreadStatus {
-            retired_list: Vec::new()
+    fn new() -> ThreadStatus {
+        let free = participants().find(|participant| {
+            participant
+                .in_use
+                .compare_exchange(false, true, Ordering::SeqCst, Ordering::Relaxed)
+                .is_ok()
+        });
+        let participant = match free {
+            Some(participant) => participant,
+            None => {
+                let participant: &'static Participant = Box::leak(Box::new(Participant {
+                    in_use: AtomicBool::new(true),
+                    state: AtomicUsize::new(0),
+                    next: AtomicPtr::new(null_mut()),
+                }));
+                let mut head = PARTICIPANTS.load(Ordering::Acquire);
+                loop {
+                    participant.next.store(head, Ordering::Relaxed);
+                    match PARTICIPANTS.compare_exchange(
+                        head,
+                        participant as *const Participant as *mut Participant,
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    ) {
+                        Ok(_) => break participant,
+                        Err(current) => head = current,
+                    }
+                }
+            }
+        };
+        ThreadStatus {
+            participant,
+            guards: Cell::new(0),
+            pins: Cell::new(0),
+            retired_list: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn pin(&self) {
+        let guards = self.guards.get();
+        self.guards.set(guards + 1);
+        if guards > 0 {
+            return;
+        }
+        let epoch = GLOBAL_EPOCH.load(Ordering::SeqCst);
+        self.participant
+            .state
+            .store(epoch << 1 | PINNED, Ordering::SeqCst);
+        // The announcement must be visible before any shared memory is read.
+        fence(Ordering::SeqCst);
+
+        let pins = self.pins.get() + 1;
+        self.pins.set(pins);
+        if pins.is_multiple_of(COLLECT_EVERY) {
+            self.collect();
+        }
+    }
+
+    fn unpin(&self) {
+        let guards = self.guards.get() - 1;
+        self.guards.set(guards);
+        if guards == 0 {
+            self.participant.state.store(0, Ordering::Release);
+        }
+    }
+
+    fn retire(&self, f: Box<dyn FnOnce()>) {
+        let epoch = GLOBAL_EPOCH.load(Ordering::SeqCst);
+        let len = {
+            let mut retired_list = self.retired_list.borrow_mut();
+            retired_list.push(Deferred { epoch, f });
+            retired_list.len()
+        };
+        if len >= MAX_RETIRED {
+            self.collect();
+        }
+    }
+
+    /// Try to advance the epoch, then run the destructors whose epoch has passed.
+    fn collect(&self) {
+        let epoch = try_advance();
+        let ready: Vec<Deferred> = {
+            let mut retired_list = self.retired_list.borrow_mut();
+            let split = retired_list
+                .iter()
+                .position(|deferred| deferred.epoch + 2 > epoch)
+                .unwrap_or(retired_list.len());
+            retired_list.drain(..split).collect()
+        };
+        let orphans: Vec<Deferred> = match ORPHANS.try_lock() {
+            Ok(mut orphans) => {
+                let (ready, waiting) = orphans
+                    .drain(..)
+                    .partition(|deferred| deferred.epoch + 2 <= epoch);
+                *orphans = waiting;
+                ready
+            }
+            Err(_) => Vec::new(),
+        };
+        // Destructors may pin and retire again, so no borrow is held while they run.
+        for deferred in ready.into_iter().chain(orphans) {
+            (deferred.f)();
         }
     }
+}
+
+impl Drop for ThreadStatus {
+    fn drop(&mut self) {
+        let retired_list = std::mem::take(&mut *self.retired_list.borrow_mut());
+        ORPHANS.lock().unwrap().extend(retired_list);
+        self.participant.state.store(0, Ordering::SeqCst);
+        self.participant.in_use.store(false, Ordering::SeqCst);
+    }
+}
 
-    pub fn pin() -> Guard {
-        return Guard {}
+/// Advance the global epoch if every pinned thread has seen it. Returns the global epoch.
+fn try_advance() -> usize {
+    let epoch = GLOBAL_EPOCH.load(Ordering::SeqCst);
+    fence(Ordering::SeqCst);
+    for participant in participants() {
+        let state = participant.state.load(Ordering::SeqCst);
+        if state & PINNED != 0 && state >> 1 != epoch {
+            return epoch;
+        }
+    }
+    match GLOBAL_EPOCH.compare_exchange(epoch, epoch + 1, Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) => epoch + 1,
+        Err(current) => current,
     }
-}
\ No newline at end of file
+}
+
+/// Pin the current thread. Shared memory read while the guard is alive is not freed.
+pub fn pin() -> Guard {
+    THREAD_STATUS.with(ThreadStatus::pin);
+    Guard {
+        _marker: PhantomData,
+    }
+}
+
+/// Keeps the thread pinned. Guards are bound to their thread.
+pub struct Guard {
+    _marker: PhantomData<*mut ()>,
+}
+
+impl Guard {
+    /// Run `f` once no thread pinned now can still be reading what it frees.
+    pub fn defer<F: FnOnce() + Send + 'static>(&self, f: F) {
+        unsafe { self.defer_unchecked(f) }
+    }
+
+    /// `defer` for a destructor which is not `Send`.
+    ///
+    /// # Safety
+    ///
+    /// `f` may run on any thread.
+    pub unsafe fn defer_unchecked<F: FnOnce() + 'static>(&self, f: F) {
+        THREAD_STATUS.with(|status| status.retire(Box::new(f)));
+    }
+
+    /// Drop the `Box` behind `ptr` once no thread pinned now can still be reading it.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must come from `Box::into_raw`, be unreachable for threads which pin from now on,
+    /// and be destroyed only once. `T` may be dropped on any thread.
+    pub unsafe fn defer_destroy<T: 'static>(&self, ptr: *mut T) {
+        self.defer_unchecked(move || drop(Box::from_raw(ptr)));
+    }
+
+    /// Try to advance the epoch and run every destructor of this thread whose epoch has passed.
+    pub fn flush(&self) {
+        THREAD_STATUS.with(ThreadStatus::collect);
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let _ = THREAD_STATUS.try_with(ThreadStatus::unpin);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::thread;
+
+    /// Flush until `count` reaches `expected`. Other tests may keep threads pinned for a while.
+    fn flush_until(count: &AtomicUsize, expected: usize) {
+        for _ in 0..100_000 {
+            if count.load(Ordering::SeqCst) == expected {
+                return;
+            }
+            pin().flush();
+            thread::yield_now();
+        }
+        assert_eq!(count.load(Ordering::SeqCst), expected);
+    }
+
+    #[test]
+    fn pinned_thread_delays_destruction() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let (pinned_send, pinned_recv) = channel();
+        let (release_send, release_recv) = channel::<()>();
+        let reader = thread::spawn(move || {
+            let _guard = pin();
+            pinned_send.send(()).unwrap();
+            release_recv.recv().unwrap();
+        });
+        pinned_recv.recv().unwrap();
+
+        {
+            let guard = pin();
+            let c_count = count.clone();
+            guard.defer(move || {
+                c_count.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        for _ in 0..100 {
+            pin().flush();
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+
+        release_send.send(()).unwrap();
+        reader.join().unwrap();
+        flush_until(&count, 1);
+    }
+
+    #[test]
+    fn orphans_are_collected() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let c_count = count.clone();
+        thread::spawn(move || {
+            let guard = pin();
+            for _ in 0..10 {
+                let c_count = c_count.clone();
+                guard.defer(move || {
+                    c_count.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        })
+        .join()
+        .unwrap();
+        flush_until(&count, 10);
+    }
+}
@@ -0,0 +1,251 @@
+use std::hint;
+use std::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, Thread};
+use std::time::Instant;
+
+const MIN_SPIN: usize = 1 << 4;
+const MAX_SPIN: usize = 1 << 10;
+
+/// Epoch of an `EventCount` as read by `prepare_wait`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key(usize);
+
+struct Waiter {
+    thread: Thread,
+    notified: AtomicBool,
+}
+
+/// A condition variable for lock-free structures
+///
+/// A consumer calls `prepare_wait`, checks its condition once more, and then either
+/// `cancel_wait`s or `wait`s. A producer makes the condition true and then calls `notify_one` or
+/// `notify_all`. A notification between `prepare_wait` and `wait` changes the epoch, so `wait`
+/// returns at once instead of missing it. A notification finds out with one load that nobody is
+/// waiting.
+///
+/// # Fields
+///
+/// * `epoch`: Incremented by every notification with waiters
+/// * `waiters`: Number of consumers between `prepare_wait` and the end of their wait
+/// * `parked`: Waiters which have found the epoch unchanged and park until notified
+/// * `spin_limit`: Attempts `wait_for` makes before parking. It grows when an attempt succeeds
+///   while spinning and shrinks when the consumer has to park.
+pub struct EventCount {
+    epoch: AtomicUsize,
+    waiters: AtomicUsize,
+    parked: Mutex<Vec<Arc<Waiter>>>,
+    spin_limit: AtomicUsize,
+}
+
+impl EventCount {
+    pub fn new() -> EventCount {
+        EventCount {
+            epoch: AtomicUsize::new(0),
+            waiters: AtomicUsize::new(0),
+            parked: Mutex::new(Vec::new()),
+            spin_limit: AtomicUsize::new(MIN_SPIN),
+        }
+    }
+
+    /// Announce a wait. The condition must be checked again before `wait`.
+    pub fn prepare_wait(&self) -> Key {
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        Key(self.epoch.load(Ordering::SeqCst))
+    }
+
+    /// Withdraw a wait announced by `prepare_wait`.
+    pub fn cancel_wait(&self) {
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Park until a notification after `prepare_wait` returned `key`.
+    pub fn wait(&self, key: Key) {
+        self.park(key, None);
+    }
+
+    /// `wait`, giving up at `deadline`. Returns whether it was notified.
+    pub fn wait_deadline(&self, key: Key, deadline: Instant) -> bool {
+        self.park(key, Some(deadline))
+    }
+
+    fn park(&self, key: Key, deadline: Option<Instant>) -> bool {
+        let waiter = {
+            let mut parked = self.parked.lock().unwrap();
+            if self.epoch.load(Ordering::SeqCst) != key.0 {
+                drop(parked);
+                self.cancel_wait();
+                return true;
+            }
+            let waiter = Arc::new(Waiter {
+                thread: thread::current(),
+                notified: AtomicBool::new(false),
+            });
+            parked.push(waiter.clone());
+            waiter
+        };
+        let notified = loop {
+            if waiter.notified.load(Ordering::SeqCst) {
+                break true;
+            }
+            match deadline {
+                None => thread::park(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break false;
+                    }
+                    thread::park_timeout(deadline - now);
+                }
+            }
+        };
+        if !notified {
+            let mut parked = self.parked.lock().unwrap();
+            parked.retain(|other| !Arc::ptr_eq(other, &waiter));
+        }
+        self.cancel_wait();
+        // A notification may have picked the waiter after it timed out.
+        notified || waiter.notified.load(Ordering::SeqCst)
+    }
+
+    /// Wake one waiter, if there is any.
+    pub fn notify_one(&self) {
+        self.notify(false)
+    }
+
+    /// Wake all waiters.
+    pub fn notify_all(&self) {
+        self.notify(true)
+    }
+
+    fn notify(&self, all: bool) {
+        // Orders the write which made the condition true before the load of `waiters`, against
+        // `prepare_wait` which increments `waiters` before the condition is checked again.
+        fence(Ordering::SeqCst);
+        if self.waiters.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+        let woken: Vec<_> = {
+            let mut parked = self.parked.lock().unwrap();
+            if all {
+                parked.drain(..).collect()
+            } else if parked.is_empty() {
+                Vec::new()
+            } else {
+                vec![parked.remove(0)]
+            }
+        };
+        for waiter in woken {
+            waiter.notified.store(true, Ordering::SeqCst);
+            waiter.thread.unpark();
+        }
+    }
+
+    /// Call `cond` until it returns `Some`, spinning for a while and then parking between
+    /// attempts. Returns `None` if `deadline` passes first.
+    pub fn wait_for<R>(
+        &self,
+        mut cond: impl FnMut() -> Option<R>,
+        deadline: Option<Instant>,
+    ) -> Option<R> {
+        let spin_limit = self.spin_limit.load(Ordering::Relaxed);
+        for _ in 0..spin_limit {
+            if let Some(res) = cond() {
+                self.spin_limit
+                    .store((spin_limit * 2).min(MAX_SPIN), Ordering::Relaxed);
+                return Some(res);
+            }
+            hint::spin_loop();
+        }
+        self.spin_limit
+            .store((spin_limit / 2).max(MIN_SPIN), Ordering::Relaxed);
+        loop {
+            let key = self.prepare_wait();
+            if let Some(res) = cond() {
+                self.cancel_wait();
+                return Some(res);
+            }
+            match deadline {
+                None => self.wait(key),
+                Some(deadline) => {
+                    if !self.wait_deadline(key, deadline) {
+                        return cond();
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Default for EventCount {
+    fn default() -> Self {
+        EventCount::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    #[test]
+    fn notified_between_prepare_and_wait() {
+        let event = EventCount::new();
+        let key = event.prepare_wait();
+        event.notify_one();
+        // Returns at once, as the epoch has changed.
+        event.wait(key);
+        assert_eq!(event.waiters.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn wait_deadline_times_out() {
+        let event = EventCount::new();
+        let key = event.prepare_wait();
+        let start = Instant::now();
+        assert!(!event.wait_deadline(key, start + Duration::from_millis(20)));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert!(event.parked.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn wait_for_wakes_parked_threads() {
+        const THREAD_NUM: usize = 4;
+
+        let event = Arc::new(EventCount::new());
+        let ready = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..THREAD_NUM)
+            .map(|_| {
+                let event = event.clone();
+                let ready = ready.clone();
+                thread::spawn(move || {
+                    event.wait_for(
+                        || {
+                            let n = ready.load(Ordering::SeqCst);
+                            if n > 0
+                                && ready
+                                    .compare_exchange(n, n - 1, Ordering::SeqCst, Ordering::SeqCst)
+                                    .is_ok()
+                            {
+                                Some(())
+                            } else {
+                                None
+                            }
+                        },
+                        None,
+                    )
+                })
+            })
+            .collect();
+        for _ in 0..THREAD_NUM {
+            thread::sleep(Duration::from_millis(5));
+            ready.fetch_add(1, Ordering::SeqCst);
+            event.notify_one();
+        }
+        for t in threads {
+            assert_eq!(t.join().unwrap(), Some(()));
+        }
+    }
+}
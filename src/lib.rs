@@ -2,7 +2,15 @@
 #![feature(cfg_target_has_atomic)]
 extern crate test;
 
+pub mod async_adapters;
+pub mod blocking;
+pub mod bounded_queue;
 pub mod cas_utils;
+pub mod epoch;
+pub mod eventcount;
+pub mod mcas_deque;
 pub mod mcas_queue;
+pub mod ms_queue;
+pub mod spsc;
 pub mod trieber_stack;
 pub mod utils;
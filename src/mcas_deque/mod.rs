@@ -0,0 +1,278 @@
+use crate::cas_utils::m_cas::{AtomicMCasPtr, MCas, MCasPtr, SingleCas};
+use crate::epoch;
+use std::mem::MaybeUninit;
+use std::sync::atomic::Ordering;
+
+/// A node of the deque. The cell of a node is its identity, the links of its neighbours point to
+/// it.
+///
+/// # Fields
+///
+/// * `val`: The value, uninitialized in the sentinels
+/// * `prev`: Cell of the node towards the front
+/// * `next`: Cell of the node towards the back
+struct Node<T> {
+    val: MaybeUninit<T>,
+    prev: AtomicMCasPtr<Option<Node<T>>>,
+    next: AtomicMCasPtr<Option<Node<T>>>,
+}
+
+type Cell<T> = MCasPtr<Option<Node<T>>>;
+
+fn node<'a, T>(cell: *mut Cell<T>) -> &'a Node<T> {
+    match unsafe { (*cell).get() } {
+        Some(node) => node,
+        None => unreachable!(),
+    }
+}
+
+fn new_node<T>(val: MaybeUninit<T>, prev: *mut Cell<T>, next: *mut Cell<T>) -> *mut Cell<T> {
+    Box::into_raw(Box::new(MCasPtr::new(Some(Node {
+        val,
+        prev: unsafe { AtomicMCasPtr::from_raw(prev) },
+        next: unsafe { AtomicMCasPtr::from_raw(next) },
+    }))))
+}
+
+/// A double-ended queue on MCAS
+///
+/// The nodes form a doubly linked list between the sentinels `front` and `back`. An operation
+/// changes both links around the node it adds or removes in one MCAS, so the list is always
+/// consistent in both directions. A pop also checks the link of the removed node towards its
+/// neighbour, which conflicts with a pop from the other end removing that neighbour. Popped
+/// cells are retired through `epoch`.
+///
+/// # Fields
+///
+/// * `front`: The front sentinel, whose `next` is the first node, or `back` if empty
+/// * `back`: The back sentinel, whose `prev` is the last node, or `front` if empty
+/// * `nil`: A cell of `None`, the outward links of the sentinels and the initial outward link
+///   of a new node
+pub struct Deque<T> {
+    front: *mut Cell<T>,
+    back: *mut Cell<T>,
+    nil: *mut Cell<T>,
+}
+
+unsafe impl<T: Send> Send for Deque<T> {}
+unsafe impl<T: Send> Sync for Deque<T> {}
+
+impl<T: 'static> Deque<T> {
+    pub fn new() -> Deque<T> {
+        let nil = Box::into_raw(Box::new(MCasPtr::new(None)));
+        let front = new_node(MaybeUninit::uninit(), nil, nil);
+        let back = new_node(MaybeUninit::uninit(), front, nil);
+        assert!([SingleCas::new(&node(front).next, nil, back)].m_cas());
+        Deque { front, back, nil }
+    }
+
+    pub fn push_front(&self, val: T) {
+        let _guard = epoch::pin();
+        let new = new_node(MaybeUninit::new(val), self.front, self.nil);
+        loop {
+            let first = node(self.front).next.load();
+            let m_cas = [
+                SingleCas::new(&node(self.front).next, first, new),
+                SingleCas::new(&node(first).prev, self.front, new),
+                SingleCas::new(&node(new).next, self.nil, first),
+            ];
+            if m_cas.m_cas() {
+                return;
+            }
+        }
+    }
+
+    pub fn push_back(&self, val: T) {
+        let _guard = epoch::pin();
+        let new = new_node(MaybeUninit::new(val), self.nil, self.back);
+        loop {
+            let last = node(self.back).prev.load();
+            let m_cas = [
+                SingleCas::new(&node(self.back).prev, last, new),
+                SingleCas::new(&node(last).next, self.back, new),
+                SingleCas::new(&node(new).prev, self.nil, last),
+            ];
+            if m_cas.m_cas() {
+                return;
+            }
+        }
+    }
+
+    pub fn pop_front(&self) -> Option<T> {
+        let guard = epoch::pin();
+        loop {
+            let first = node(self.front).next.load();
+            if std::ptr::eq(first, self.back) {
+                return None;
+            }
+            let second = node(first).next.load();
+            let m_cas = [
+                SingleCas::new(&node(self.front).next, first, second),
+                SingleCas::new(&node(second).prev, first, self.front),
+                SingleCas::compare(&node(first).next, second),
+            ];
+            if m_cas.m_cas() {
+                // Only the popper which unlinked `first` reads its value.
+                let val = unsafe { node(first).val.as_ptr().read() };
+                unsafe { MCasPtr::retire(first, &guard) };
+                return Some(val);
+            }
+        }
+    }
+
+    pub fn pop_back(&self) -> Option<T> {
+        let guard = epoch::pin();
+        loop {
+            let last = node(self.back).prev.load();
+            if std::ptr::eq(last, self.front) {
+                return None;
+            }
+            let second = node(last).prev.load();
+            let m_cas = [
+                SingleCas::new(&node(self.back).prev, last, second),
+                SingleCas::new(&node(second).next, last, self.back),
+                SingleCas::compare(&node(last).prev, second),
+            ];
+            if m_cas.m_cas() {
+                // Only the popper which unlinked `last` reads its value.
+                let val = unsafe { node(last).val.as_ptr().read() };
+                unsafe { MCasPtr::retire(last, &guard) };
+                return Some(val);
+            }
+        }
+    }
+}
+
+impl<T> Drop for Deque<T> {
+    fn drop(&mut self) {
+        let mut cell = self.front;
+        loop {
+            let mut current = unsafe { Box::from_raw(cell) };
+            let mut node = current.get_mut().take().unwrap();
+            if std::ptr::eq(cell, self.back) {
+                break;
+            }
+            if !std::ptr::eq(cell, self.front) {
+                unsafe { node.val.assume_init_drop() };
+            }
+            cell = node.next.get_m_cas_ptr(Ordering::SeqCst);
+        }
+        drop(unsafe { Box::from_raw(self.nil) });
+    }
+}
+
+impl<T: 'static> Default for Deque<T> {
+    fn default() -> Self {
+        Deque::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use test::Bencher;
+
+    #[test]
+    fn single_thread_both_ends() {
+        let d = Deque::new();
+        assert_eq!(d.pop_front(), None);
+        assert_eq!(d.pop_back(), None);
+        d.push_back(1);
+        d.push_back(2);
+        d.push_front(0);
+        assert_eq!(d.pop_back(), Some(2));
+        assert_eq!(d.pop_front(), Some(0));
+        assert_eq!(d.pop_front(), Some(1));
+        assert_eq!(d.pop_back(), None);
+        for i in 0..100 {
+            d.push_front(i);
+        }
+        for i in 0..100 {
+            assert_eq!(d.pop_back(), Some(i));
+        }
+        assert_eq!(d.pop_front(), None);
+    }
+
+    #[test]
+    fn drop_remaining_values() {
+        let val = Arc::new(());
+        let d = Deque::new();
+        for _ in 0..5 {
+            d.push_front(val.clone());
+            d.push_back(val.clone());
+        }
+        drop(d.pop_front());
+        drop(d.pop_back());
+        assert_eq!(Arc::strong_count(&val), 9);
+        drop(d);
+        assert_eq!(Arc::strong_count(&val), 1);
+    }
+
+    #[test]
+    fn both_ends_multi_thread() {
+        const THREAD_NUM: usize = 4;
+        const ITER_NUM: usize = 2000;
+
+        let d = Arc::new(Deque::new());
+        let threads: Vec<_> = (0..THREAD_NUM)
+            .map(|t| {
+                let d = d.clone();
+                thread::spawn(move || {
+                    let mut popped = Vec::new();
+                    for i in 0..ITER_NUM {
+                        let val = t * ITER_NUM + i;
+                        if (t + i) % 2 == 0 {
+                            d.push_front(val);
+                        } else {
+                            d.push_back(val);
+                        }
+                        let val = if t % 2 == 0 {
+                            d.pop_front()
+                        } else {
+                            d.pop_back()
+                        };
+                        popped.extend(val);
+                    }
+                    popped
+                })
+            })
+            .collect();
+        let mut popped: Vec<_> = threads
+            .into_iter()
+            .flat_map(|t| t.join().unwrap())
+            .collect();
+        while let Some(val) = d.pop_back() {
+            popped.push(val);
+        }
+        popped.sort_unstable();
+        assert_eq!(popped, (0..THREAD_NUM * ITER_NUM).collect::<Vec<_>>());
+    }
+
+    #[bench]
+    fn bench_push_pop(b: &mut Bencher) {
+        b.iter(|| {
+            let d = Arc::new(Deque::new());
+            let threads: Vec<_> = (0..4)
+                .map(|t| {
+                    let d = d.clone();
+                    thread::spawn(move || {
+                        for i in 0..1 << 8 {
+                            if t % 2 == 0 {
+                                d.push_front(i);
+                                d.pop_back();
+                            } else {
+                                d.push_back(i);
+                                d.pop_front();
+                            }
+                        }
+                    })
+                })
+                .collect();
+            for t in threads {
+                t.join().unwrap();
+            }
+        });
+    }
+}
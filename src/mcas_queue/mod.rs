@@ -1,45 +1,536 @@
-use crate::cas_utils::m_cas::{AtomicMCasPtr, MCasPtr, SingleCas, MCas};
+use crate::cas_utils::deadline::{try_m_cas_until, Busy, Until};
+use crate::cas_utils::m_cas::{AtomicMCasPtr, MCas, MCasPtr, SingleCas};
+use crate::epoch;
+use std::mem::MaybeUninit;
 use std::sync::atomic::Ordering;
 
-pub struct Node<T> {
-    pub val: T,
-    pub(crate) next: AtomicMCasPtr<Option<Node<T>>>,
+/// A node of the queue. The cell of a node is its identity: `head`, `tail` and the `next` of
+/// the previous node all point to the same cell.
+///
+/// # Fields
+///
+/// * `val`: The value, uninitialized in the sentinel. The popper which makes a node the new
+///   sentinel moves it out.
+/// * `next`: Cell of the next node, or a cell of `End` at the end of the queue
+struct Node<T> {
+    val: MaybeUninit<T>,
+    next: AtomicMCasPtr<Link<T>>,
 }
 
+/// Content of a cell. All locations of a MCAS hold cells of one type, so the count is a variant
+/// next to the nodes.
+enum Link<T> {
+    Node(Node<T>),
+    End,
+    Len(usize),
+}
+
+type Cell<T> = MCasPtr<Link<T>>;
+
+fn node<'a, T>(cell: *mut Cell<T>) -> &'a Node<T> {
+    match unsafe { (*cell).get() } {
+        Link::Node(node) => node,
+        _ => unreachable!(),
+    }
+}
+
+fn len_of<T>(cell: *mut Cell<T>) -> usize {
+    match unsafe { (*cell).get() } {
+        Link::Len(len) => *len,
+        _ => unreachable!(),
+    }
+}
+
+/// A new node cell holding `val`, followed by a new cell of `End`
+fn new_node<T>(val: MaybeUninit<T>) -> *mut Cell<T> {
+    Box::into_raw(Box::new(MCasPtr::new(Link::Node(Node {
+        val,
+        next: AtomicMCasPtr::new(Box::new(MCasPtr::new(Link::End))),
+    }))))
+}
+
+/// A new cell of a count, which is rewritten in place until it is published
+fn new_len<T>(len: usize) -> *mut Cell<T> {
+    Box::into_raw(Box::new(MCasPtr::new(Link::Len(len))))
+}
+
+/// A FIFO queue on MCAS
+///
+/// `head` points to a sentinel node, whose next node is the front of the queue. `tail` points to
+/// the last node and is swung in the same MCAS which links a new node, so it never lags behind.
+/// A pop moves `head` to the next node, which becomes the new sentinel, so `tail` stays valid
+/// when the queue empties. Both also change `len` in their MCAS, so it is exact at every instant.
+///
+/// A queue is shared between threads only if its values can be sent:
+///
+/// ```compile_fail
+/// fn shared<Q: Sync>(_: &Q) {}
+/// shared(&beee::mcas_queue::Queue::<std::rc::Rc<()>>::new());
+/// ```
 pub struct Queue<T> {
-    pub head: AtomicMCasPtr<Option<Node<T>>>,
-    pub tail: AtomicMCasPtr<Option<Node<T>>>,
+    head: AtomicMCasPtr<Link<T>>,
+    tail: AtomicMCasPtr<Link<T>>,
+    len: AtomicMCasPtr<Link<T>>,
 }
 
-impl<T> Queue<T> {
+unsafe impl<T: Send> Send for Queue<T> {}
+unsafe impl<T: Send> Sync for Queue<T> {}
+
+impl<T: 'static> Queue<T> {
     pub fn new() -> Queue<T> {
-        let mut none = MCasPtr::new(None);
-        return Queue::<T> {
-            head: AtomicMCasPtr::new(&mut none),
-            tail: AtomicMCasPtr::new(&mut none),
+        let sentinel = new_node(MaybeUninit::uninit());
+        Queue {
+            head: unsafe { AtomicMCasPtr::from_raw(sentinel) },
+            tail: unsafe { AtomicMCasPtr::from_raw(sentinel) },
+            len: AtomicMCasPtr::new(Box::new(MCasPtr::new(Link::Len(0)))),
+        }
+    }
+
+    /// Number of values, as of one instant
+    pub fn len(&self) -> usize {
+        match self.len.read(&epoch::pin()) {
+            Link::Len(len) => *len,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn push(&self, val: T) {
+        if self.try_push_until(val, &Until::never()).is_err() {
+            unreachable!()
+        }
+    }
+
+    /// `push`, giving `val` back once `until` has expired.
+    pub fn try_push_until(&self, val: T, until: &Until) -> Result<(), T> {
+        let new = new_node(MaybeUninit::new(val));
+        let len = new_len(0);
+        let guard = epoch::pin();
+        loop {
+            if until.expired() {
+                break;
+            }
+            let last = self.tail.load();
+            let end = node(last).next.load();
+            let old_len = self.len.load();
+            unsafe { *(*len).get_mut() = Link::Len(len_of(old_len) + 1) };
+            let m_cas = [
+                SingleCas::new(&node(last).next, end, new),
+                SingleCas::new(&self.tail, last, new),
+                SingleCas::new(&self.len, old_len, len),
+            ];
+            match try_m_cas_until(m_cas, until) {
+                Ok(true) => {
+                    unsafe { MCasPtr::retire(end, &guard) };
+                    unsafe { MCasPtr::retire(old_len, &guard) };
+                    return Ok(());
+                }
+                Ok(false) => {}
+                Err(Busy) => break,
+            }
+        }
+        // The node and the count have never been published.
+        drop(unsafe { Box::from_raw(len) });
+        let new = match std::mem::replace(unsafe { Box::from_raw(new) }.get_mut(), Link::End) {
+            Link::Node(new) => new,
+            _ => unreachable!(),
         };
+        drop(unsafe { Box::from_raw(new.next.load()) });
+        Err(unsafe { new.val.assume_init() })
     }
 
     pub fn pop(&self) -> Option<T> {
+        match self.try_pop_until(&Until::never()) {
+            Ok(res) => res,
+            Err(Busy) => unreachable!(),
+        }
+    }
+
+    /// `pop`, giving up with `Busy` once `until` has expired.
+    pub fn try_pop_until(&self, until: &Until) -> Result<Option<T>, Busy> {
+        let len = new_len(0);
+        let guard = epoch::pin();
+        let res = loop {
+            if until.expired() {
+                break Err(Busy);
+            }
+            let sentinel = self.head.load();
+            let first = node(sentinel).next.load();
+            if let Link::End = unsafe { (*first).get() } {
+                break Ok(None);
+            }
+            let old_len = self.len.load();
+            // Wraps if `old_len` is already stale, the MCAS then fails.
+            unsafe { *(*len).get_mut() = Link::Len(len_of(old_len).wrapping_sub(1)) };
+            // `next` of a node is written only once, so `first` follows `sentinel` for good.
+            let m_cas = [
+                SingleCas::new(&self.head, sentinel, first),
+                SingleCas::new(&self.len, old_len, len),
+            ];
+            match try_m_cas_until(m_cas, until) {
+                Ok(true) => {
+                    // Only the popper which made `first` the sentinel reads its value.
+                    let val = unsafe { node(first).val.as_ptr().read() };
+                    unsafe { MCasPtr::retire(sentinel, &guard) };
+                    unsafe { MCasPtr::retire(old_len, &guard) };
+                    return Ok(Some(val));
+                }
+                Ok(false) => {}
+                Err(Busy) => break Err(Busy),
+            }
+        };
+        // The count has never been published.
+        drop(unsafe { Box::from_raw(len) });
+        res
+    }
+
+    /// Push the values of `vals` in order. Their nodes are linked into a chain first, which is
+    /// spliced in with a single MCAS.
+    pub fn push_batch(&self, vals: impl IntoIterator<Item = T>) {
+        // Built from the back, as the `next` of a node is set when it is created.
+        let vals: Vec<_> = vals.into_iter().collect();
+        let count = vals.len();
+        let mut vals = vals.into_iter().rev();
+        let chain_last = match vals.next() {
+            Some(val) => new_node(MaybeUninit::new(val)),
+            None => return,
+        };
+        let mut chain_first = chain_last;
+        for val in vals {
+            chain_first = Box::into_raw(Box::new(MCasPtr::new(Link::Node(Node {
+                val: MaybeUninit::new(val),
+                next: unsafe { AtomicMCasPtr::from_raw(chain_first) },
+            }))));
+        }
+        let len = new_len(0);
+        let guard = epoch::pin();
+        loop {
+            let last = self.tail.load();
+            let end = node(last).next.load();
+            let old_len = self.len.load();
+            unsafe { *(*len).get_mut() = Link::Len(len_of(old_len) + count) };
+            let m_cas = [
+                SingleCas::new(&node(last).next, end, chain_first),
+                SingleCas::new(&self.tail, last, chain_last),
+                SingleCas::new(&self.len, old_len, len),
+            ];
+            if m_cas.m_cas() {
+                unsafe { MCasPtr::retire(end, &guard) };
+                unsafe { MCasPtr::retire(old_len, &guard) };
+                return;
+            }
+        }
+    }
+
+    /// Pop up to `n` values, front first, with a single MCAS which detaches their nodes. Fewer
+    /// than `n` are popped only if the queue held fewer at that instant.
+    pub fn pop_batch(&self, n: usize) -> Vec<T> {
+        if n == 0 {
+            return Vec::new();
+        }
+        let len = new_len(0);
+        let guard = epoch::pin();
+        let mut nodes = Vec::new();
         loop {
-            let top = self.head.read();
-            match top {
-                Some(top) => {
-                    let origin_head = self.head.get_m_cas_ptr(Ordering::Relaxed);
-                    let next = top.next.get_m_cas_ptr(Ordering::Relaxed);
-                    let cas = SingleCas::new(
-                        &self.head,
-                        origin_head,
-                        next
-                    );
-
-                    if vec![cas].m_cas() {
-                        let retired_head = unsafe { &mut *(*origin_head).read_mut()};
-                        return Some(retired_head.take().unwrap().val);
+            nodes.clear();
+            let sentinel = self.head.load();
+            let mut last = sentinel;
+            let mut next = node(last).next.load();
+            while nodes.len() < n {
+                if let Link::End = unsafe { (*next).get() } {
+                    break;
+                }
+                nodes.push(next);
+                last = next;
+                next = node(last).next.load();
+            }
+            if nodes.is_empty() {
+                // The count has never been published.
+                drop(unsafe { Box::from_raw(len) });
+                return Vec::new();
+            }
+            let old_len = self.len.load();
+            // Wraps if `old_len` is already stale, the MCAS then fails.
+            unsafe { *(*len).get_mut() = Link::Len(len_of(old_len).wrapping_sub(nodes.len())) };
+            let head = SingleCas::new(&self.head, sentinel, last);
+            let count = SingleCas::new(&self.len, old_len, len);
+            let done = if nodes.len() < n {
+                // The queue must still end at `last`.
+                [head, count, SingleCas::compare(&node(last).next, next)].m_cas()
+            } else {
+                [head, count].m_cas()
+            };
+            if done {
+                // `last` is the new sentinel, the nodes before it are unlinked.
+                let vals = nodes
+                    .iter()
+                    .map(|&cell| unsafe { node(cell).val.as_ptr().read() })
+                    .collect();
+                unsafe { MCasPtr::retire(sentinel, &guard) };
+                for &cell in &nodes[..nodes.len() - 1] {
+                    unsafe { MCasPtr::retire(cell, &guard) };
+                }
+                unsafe { MCasPtr::retire(old_len, &guard) };
+                return vals;
+            }
+        }
+    }
+}
+
+impl<T> Drop for Queue<T> {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(self.len.get_m_cas_ptr(Ordering::SeqCst)) });
+        let mut cell = self.head.get_m_cas_ptr(Ordering::SeqCst);
+        let mut sentinel = true;
+        loop {
+            let mut current = unsafe { Box::from_raw(cell) };
+            match std::mem::replace(current.get_mut(), Link::End) {
+                Link::Node(mut node) => {
+                    if !sentinel {
+                        unsafe { node.val.assume_init_drop() };
                     }
+                    sentinel = false;
+                    cell = node.next.get_m_cas_ptr(Ordering::SeqCst);
                 }
-                None => {return None;}
+                _ => return,
+            }
+        }
+    }
+}
+
+impl<T: 'static> Default for Queue<T> {
+    fn default() -> Self {
+        Queue::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use std::thread;
+    use test::Bencher;
+
+    #[test]
+    fn single_thread_fifo() {
+        let q = Queue::new();
+        assert_eq!(q.pop(), None);
+        assert!(q.is_empty());
+        for i in 0..1000 {
+            q.push(i);
+        }
+        assert_eq!(q.len(), 1000);
+        for i in 0..500 {
+            assert_eq!(q.pop(), Some(i));
+        }
+        assert_eq!(q.len(), 500);
+        for i in 1000..1500 {
+            q.push(i);
+        }
+        for i in 500..1500 {
+            assert_eq!(q.pop(), Some(i));
+        }
+        assert_eq!(q.pop(), None);
+        q.push(0);
+        assert_eq!(q.pop(), Some(0));
+        assert_eq!(q.pop(), None);
+        assert_eq!(q.len(), 0);
+    }
+
+    #[test]
+    fn batches() {
+        let q = Queue::new();
+        q.push_batch(Vec::new());
+        assert!(q.pop_batch(4).is_empty());
+        q.push(0);
+        q.push_batch(1..5);
+        q.push(5);
+        assert_eq!(q.len(), 6);
+        assert_eq!(q.pop_batch(0), Vec::<i32>::new());
+        assert_eq!(q.pop_batch(4), vec![0, 1, 2, 3]);
+        assert_eq!(q.len(), 2);
+        q.push_batch(vec![6, 7]);
+        assert_eq!(q.pop(), Some(4));
+        assert_eq!(q.pop_batch(10), vec![5, 6, 7]);
+        assert!(q.is_empty());
+        q.push_batch(0..3);
+        assert_eq!(q.pop_batch(3), vec![0, 1, 2]);
+        assert_eq!(q.pop(), None);
+    }
+
+    #[test]
+    fn multi_thread_batches() {
+        const THREAD_NUM: usize = 4;
+        const BATCH_NUM: usize = 500;
+        const BATCH_LEN: usize = 8;
+
+        let q = Arc::new(Queue::new());
+        let total = Arc::new(AtomicUsize::new(0));
+        let producers: Vec<_> = (0..THREAD_NUM)
+            .map(|producer| {
+                let q = q.clone();
+                thread::spawn(move || {
+                    for batch in 0..BATCH_NUM {
+                        let start = batch * BATCH_LEN;
+                        q.push_batch((start..start + BATCH_LEN).map(|i| (producer, i)));
+                    }
+                })
+            })
+            .collect();
+        let consumers: Vec<_> = (0..THREAD_NUM)
+            .map(|consumer| {
+                let q = q.clone();
+                let total = total.clone();
+                thread::spawn(move || {
+                    // A batch is contiguous, so values of a producer stay in order.
+                    let mut last = [None; THREAD_NUM];
+                    let mut popped = Vec::new();
+                    while total.load(Ordering::SeqCst) < THREAD_NUM * BATCH_NUM * BATCH_LEN {
+                        let batch = q.pop_batch(consumer + 1);
+                        total.fetch_add(batch.len(), Ordering::SeqCst);
+                        for (producer, i) in batch {
+                            assert!(last[producer].is_none_or(|last| last < i));
+                            last[producer] = Some(i);
+                            popped.push((producer, i));
+                        }
+                        assert!(q.len() <= THREAD_NUM * BATCH_NUM * BATCH_LEN);
+                    }
+                    popped
+                })
+            })
+            .collect();
+        for t in producers {
+            t.join().unwrap();
+        }
+        let mut popped: Vec<_> = consumers
+            .into_iter()
+            .flat_map(|t| t.join().unwrap())
+            .collect();
+        popped.sort_unstable();
+        let expected: Vec<_> = (0..THREAD_NUM)
+            .flat_map(|producer| (0..BATCH_NUM * BATCH_LEN).map(move |i| (producer, i)))
+            .collect();
+        assert_eq!(popped, expected);
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn len_stays_in_bounds() {
+        const THREAD_NUM: usize = 4;
+        const ITER_NUM: usize = 2000;
+
+        let q = Arc::new(Queue::new());
+        let threads: Vec<_> = (0..THREAD_NUM)
+            .map(|_| {
+                let q = q.clone();
+                thread::spawn(move || {
+                    for i in 0..ITER_NUM {
+                        q.push(i);
+                        assert!((1..=THREAD_NUM).contains(&q.len()));
+                        assert!(q.pop().is_some());
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(q.len(), 0);
+    }
+
+    #[test]
+    fn drop_remaining_values() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        struct Counted(Arc<AtomicUsize>);
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::SeqCst);
             }
         }
+
+        let q = Queue::new();
+        for _ in 0..10 {
+            q.push(Counted(drops.clone()));
+        }
+        drop(q.pop());
+        drop(q.pop());
+        assert_eq!(drops.load(Ordering::SeqCst), 2);
+        drop(q);
+        assert_eq!(drops.load(Ordering::SeqCst), 10);
+    }
+
+    #[test]
+    fn multi_producer_multi_consumer() {
+        const PRODUCER_NUM: usize = 4;
+        const CONSUMER_NUM: usize = 4;
+        const ITER_NUM: usize = 5000;
+
+        let q = Arc::new(Queue::new());
+        let producers: Vec<_> = (0..PRODUCER_NUM)
+            .map(|producer| {
+                let q = q.clone();
+                thread::spawn(move || {
+                    for i in 0..ITER_NUM {
+                        q.push((producer, i));
+                    }
+                })
+            })
+            .collect();
+        let consumers: Vec<_> = (0..CONSUMER_NUM)
+            .map(|_| {
+                let q = q.clone();
+                thread::spawn(move || {
+                    // Values of every producer come out in the order they were pushed.
+                    let mut last = [None; PRODUCER_NUM];
+                    let mut popped = Vec::new();
+                    while popped.len() < PRODUCER_NUM * ITER_NUM / CONSUMER_NUM {
+                        if let Some((producer, i)) = q.pop() {
+                            assert!(last[producer].is_none_or(|last| last < i));
+                            last[producer] = Some(i);
+                            popped.push((producer, i));
+                        }
+                    }
+                    popped
+                })
+            })
+            .collect();
+        for t in producers {
+            t.join().unwrap();
+        }
+        let mut popped: Vec<_> = consumers
+            .into_iter()
+            .flat_map(|t| t.join().unwrap())
+            .collect();
+        popped.sort_unstable();
+        let expected: Vec<_> = (0..PRODUCER_NUM)
+            .flat_map(|producer| (0..ITER_NUM).map(move |i| (producer, i)))
+            .collect();
+        assert_eq!(popped, expected);
+        assert_eq!(q.pop(), None);
+    }
+
+    #[bench]
+    fn bench_push_pop(b: &mut Bencher) {
+        b.iter(|| {
+            let q = Arc::new(Queue::new());
+            let threads: Vec<_> = (0..4)
+                .map(|_| {
+                    let q = q.clone();
+                    thread::spawn(move || {
+                        for i in 0..1 << 8 {
+                            q.push(i);
+                            q.pop().unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for t in threads {
+                t.join().unwrap();
+            }
+        });
     }
 }
@@ -0,0 +1,247 @@
+use crate::cas_utils::deadline::{Busy, Until};
+use crate::epoch;
+use std::mem::MaybeUninit;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+/// # Fields
+///
+/// * `val`: The value, uninitialized in the sentinel. The popper which makes a node the new
+///   sentinel moves it out.
+/// * `next`: The next node, null at the end of the queue
+struct Node<T> {
+    val: MaybeUninit<T>,
+    next: AtomicPtr<Node<T>>,
+}
+
+impl<T> Node<T> {
+    fn new(val: MaybeUninit<T>) -> *mut Node<T> {
+        Box::into_raw(Box::new(Node {
+            val,
+            next: AtomicPtr::new(null_mut()),
+        }))
+    }
+}
+
+/// The lock-free FIFO queue of
+/// [Simple, Fast, and Practical Non-Blocking and Blocking Concurrent Queue Algorithms](https://www.cs.rochester.edu/~scott/papers/1996_PODC_queues.pdf)
+///
+/// `head` points to a sentinel node, whose next node is the front of the queue. `tail` points to
+/// the last node or lags one node behind it, and every operation which sees it lagging swings it
+/// forward. Popped sentinels are freed through `epoch`, like the cells of `mcas_queue`.
+pub struct Queue<T> {
+    head: AtomicPtr<Node<T>>,
+    tail: AtomicPtr<Node<T>>,
+}
+
+unsafe impl<T: Send> Send for Queue<T> {}
+unsafe impl<T: Send> Sync for Queue<T> {}
+
+impl<T: 'static> Queue<T> {
+    pub fn new() -> Queue<T> {
+        let sentinel = Node::new(MaybeUninit::uninit());
+        Queue {
+            head: AtomicPtr::new(sentinel),
+            tail: AtomicPtr::new(sentinel),
+        }
+    }
+
+    pub fn push(&self, val: T) {
+        if self.try_push_until(val, &Until::never()).is_err() {
+            unreachable!()
+        }
+    }
+
+    /// `push`, giving `val` back once `until` has expired.
+    pub fn try_push_until(&self, val: T, until: &Until) -> Result<(), T> {
+        let node = Node::new(MaybeUninit::new(val));
+        let _guard = epoch::pin();
+        loop {
+            if until.expired() {
+                // The node has never been linked.
+                let node = unsafe { Box::from_raw(node) };
+                return Err(unsafe { node.val.assume_init() });
+            }
+            let tail = self.tail.load(Ordering::SeqCst);
+            let next = unsafe { (*tail).next.load(Ordering::SeqCst) };
+            if !next.is_null() {
+                let _ = self
+                    .tail
+                    .compare_exchange(tail, next, Ordering::SeqCst, Ordering::Relaxed);
+                continue;
+            }
+            if unsafe { &(*tail).next }
+                .compare_exchange(next, node, Ordering::SeqCst, Ordering::Relaxed)
+                .is_ok()
+            {
+                let _ = self
+                    .tail
+                    .compare_exchange(tail, node, Ordering::SeqCst, Ordering::Relaxed);
+                return Ok(());
+            }
+        }
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        match self.try_pop_until(&Until::never()) {
+            Ok(res) => res,
+            Err(Busy) => unreachable!(),
+        }
+    }
+
+    /// `pop`, giving up with `Busy` once `until` has expired.
+    pub fn try_pop_until(&self, until: &Until) -> Result<Option<T>, Busy> {
+        let guard = epoch::pin();
+        loop {
+            if until.expired() {
+                return Err(Busy);
+            }
+            let head = self.head.load(Ordering::SeqCst);
+            let next = unsafe { (*head).next.load(Ordering::SeqCst) };
+            if next.is_null() {
+                return Ok(None);
+            }
+            let tail = self.tail.load(Ordering::SeqCst);
+            if std::ptr::eq(head, tail) {
+                // `tail` lags behind, it must not be left pointing to a freed sentinel.
+                let _ = self
+                    .tail
+                    .compare_exchange(tail, next, Ordering::SeqCst, Ordering::Relaxed);
+                continue;
+            }
+            if self
+                .head
+                .compare_exchange(head, next, Ordering::SeqCst, Ordering::Relaxed)
+                .is_ok()
+            {
+                // Only the popper which made `next` the sentinel reads its value.
+                let val = unsafe { (*next).val.as_ptr().read() };
+                unsafe { guard.defer_destroy(head) };
+                return Ok(Some(val));
+            }
+        }
+    }
+}
+
+impl<T> Drop for Queue<T> {
+    fn drop(&mut self) {
+        let mut node = self.head.load(Ordering::SeqCst);
+        let mut sentinel = true;
+        while !node.is_null() {
+            let mut current = unsafe { Box::from_raw(node) };
+            if !sentinel {
+                unsafe { current.val.assume_init_drop() };
+            }
+            sentinel = false;
+            node = current.next.load(Ordering::SeqCst);
+        }
+    }
+}
+
+impl<T: 'static> Default for Queue<T> {
+    fn default() -> Self {
+        Queue::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use std::thread;
+    use test::Bencher;
+
+    #[test]
+    fn single_thread_fifo() {
+        let q = Queue::new();
+        assert_eq!(q.pop(), None);
+        for i in 0..1 << 16 {
+            q.push(i);
+        }
+        for i in 0..1 << 16 {
+            assert_eq!(q.pop(), Some(i));
+        }
+        assert_eq!(q.pop(), None);
+    }
+
+    #[test]
+    fn drop_remaining_values() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        struct Counted(Arc<AtomicUsize>);
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let q = Queue::new();
+        for _ in 0..10 {
+            q.push(Counted(drops.clone()));
+        }
+        drop(q.pop());
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+        drop(q);
+        assert_eq!(drops.load(Ordering::SeqCst), 10);
+    }
+
+    #[test]
+    fn multi_producer_multi_consumer() {
+        const PRODUCER_NUM: usize = 4;
+        const CONSUMER_NUM: usize = 4;
+        const ITER_NUM: usize = 1 << 14;
+
+        let q = Arc::new(Queue::new());
+        let producers: Vec<_> = (0..PRODUCER_NUM)
+            .map(|producer| {
+                let q = q.clone();
+                thread::spawn(move || {
+                    for i in 0..ITER_NUM {
+                        q.push((producer, i));
+                    }
+                })
+            })
+            .collect();
+        let consumers: Vec<_> = (0..CONSUMER_NUM)
+            .map(|_| {
+                let q = q.clone();
+                thread::spawn(move || {
+                    let mut last = [None; PRODUCER_NUM];
+                    let mut count = 0;
+                    while count < PRODUCER_NUM * ITER_NUM / CONSUMER_NUM {
+                        if let Some((producer, i)) = q.pop() {
+                            assert!(last[producer].is_none_or(|last| last < i));
+                            last[producer] = Some(i);
+                            count += 1;
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in producers.into_iter().chain(consumers) {
+            t.join().unwrap();
+        }
+        assert_eq!(q.pop(), None);
+    }
+
+    #[bench]
+    fn bench_push_pop(b: &mut Bencher) {
+        b.iter(|| {
+            let q = Arc::new(Queue::new());
+            let threads: Vec<_> = (0..4)
+                .map(|_| {
+                    let q = q.clone();
+                    thread::spawn(move || {
+                        for i in 0..1 << 8 {
+                            q.push(i);
+                            q.pop().unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for t in threads {
+                t.join().unwrap();
+            }
+        });
+    }
+}
@@ -0,0 +1,277 @@
+use crate::utils::CachePadded;
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// # Fields
+///
+/// * `slots`: The values, initialized from position `head` up to position `tail`. The slot of a
+///   position is the position modulo the capacity.
+/// * `head`: Position of the next pop, written only by the consumer
+/// * `tail`: Position of the next push, written only by the producer
+struct Ring<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    head: CachePadded<AtomicUsize>,
+    tail: CachePadded<AtomicUsize>,
+}
+
+impl<T> Ring<T> {
+    fn slot(&self, pos: usize) -> *mut MaybeUninit<T> {
+        self.slots[pos % self.slots.len()].get()
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        for pos in *self.head.get_mut()..*self.tail.get_mut() {
+            unsafe { (*self.slot(pos)).assume_init_drop() };
+        }
+    }
+}
+
+/// The pushing end of a ring
+///
+/// # Fields
+///
+/// * `tail`: Own copy of `ring.tail`
+/// * `head_cache`: `ring.head` as last read. The ring has at least the free slots it implies, so
+///   `ring.head` is read again only when these run out.
+pub struct Producer<T> {
+    ring: Arc<Ring<T>>,
+    tail: usize,
+    head_cache: usize,
+}
+
+/// The popping end of a ring
+///
+/// # Fields
+///
+/// * `head`: Own copy of `ring.head`
+/// * `tail_cache`: `ring.tail` as last read. The ring has at least the values it implies, so
+///   `ring.tail` is read again only when these run out.
+pub struct Consumer<T> {
+    ring: Arc<Ring<T>>,
+    head: usize,
+    tail_cache: usize,
+}
+
+unsafe impl<T: Send> Send for Producer<T> {}
+unsafe impl<T: Send> Send for Consumer<T> {}
+
+/// A ring holding up to `capacity` values, split into its two ends. Every `push` and `pop`
+/// finishes in a bounded number of steps, as each index has a single writer. Panics if
+/// `capacity` is 0.
+pub fn channel<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    assert!(capacity > 0, "capacity must be positive");
+    let ring = Arc::new(Ring {
+        slots: (0..capacity)
+            .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+            .collect(),
+        head: CachePadded::new(AtomicUsize::new(0)),
+        tail: CachePadded::new(AtomicUsize::new(0)),
+    });
+    (
+        Producer {
+            ring: ring.clone(),
+            tail: 0,
+            head_cache: 0,
+        },
+        Consumer {
+            ring,
+            head: 0,
+            tail_cache: 0,
+        },
+    )
+}
+
+impl<T> Producer<T> {
+    pub fn capacity(&self) -> usize {
+        self.ring.slots.len()
+    }
+
+    /// Number of free slots, at least `wanted` of them if there are that many
+    fn free(&mut self, wanted: usize) -> usize {
+        let free = self.capacity() - (self.tail - self.head_cache);
+        if free >= wanted {
+            return free;
+        }
+        self.head_cache = self.ring.head.load(Ordering::Acquire);
+        self.capacity() - (self.tail - self.head_cache)
+    }
+
+    /// Push `val`, giving it back if the ring is full.
+    pub fn push(&mut self, val: T) -> Result<(), T> {
+        if self.free(1) == 0 {
+            return Err(val);
+        }
+        unsafe { (*self.ring.slot(self.tail)).write(val) };
+        self.tail += 1;
+        self.ring.tail.store(self.tail, Ordering::Release);
+        Ok(())
+    }
+
+    /// Push clones of the longest prefix of `vals` which fits, with a single publication.
+    /// Returns the length of the prefix.
+    pub fn push_slice(&mut self, vals: &[T]) -> usize
+    where
+        T: Clone,
+    {
+        let n = self.free(vals.len()).min(vals.len());
+        for (i, val) in vals[..n].iter().enumerate() {
+            unsafe { (*self.ring.slot(self.tail + i)).write(val.clone()) };
+        }
+        self.tail += n;
+        self.ring.tail.store(self.tail, Ordering::Release);
+        n
+    }
+}
+
+impl<T> Consumer<T> {
+    pub fn capacity(&self) -> usize {
+        self.ring.slots.len()
+    }
+
+    /// Number of values ready, at least `wanted` of them if there are that many
+    fn ready(&mut self, wanted: usize) -> usize {
+        let ready = self.tail_cache - self.head;
+        if ready >= wanted {
+            return ready;
+        }
+        self.tail_cache = self.ring.tail.load(Ordering::Acquire);
+        self.tail_cache - self.head
+    }
+
+    /// Pop a value, or `None` if the ring is empty.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.ready(1) == 0 {
+            return None;
+        }
+        let val = unsafe { (*self.ring.slot(self.head)).as_ptr().read() };
+        self.head += 1;
+        self.ring.head.store(self.head, Ordering::Release);
+        Some(val)
+    }
+
+    /// Pop values into the front of `out`, replacing its elements, and free their slots with a
+    /// single publication. Returns the number of values popped.
+    pub fn pop_into(&mut self, out: &mut [T]) -> usize {
+        let n = self.ready(out.len()).min(out.len());
+        for (i, dst) in out[..n].iter_mut().enumerate() {
+            *dst = unsafe { (*self.ring.slot(self.head + i)).as_ptr().read() };
+        }
+        self.head += n;
+        self.ring.head.store(self.head, Ordering::Release);
+        n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use test::Bencher;
+
+    #[test]
+    fn single_thread_fifo() {
+        let (mut tx, mut rx) = channel(3);
+        assert_eq!(rx.pop(), None);
+        for lap in 0..4 {
+            for i in 0..3 {
+                assert_eq!(tx.push(lap * 3 + i), Ok(()));
+            }
+            assert_eq!(tx.push(-1), Err(-1));
+            for i in 0..3 {
+                assert_eq!(rx.pop(), Some(lap * 3 + i));
+            }
+            assert_eq!(rx.pop(), None);
+        }
+    }
+
+    #[test]
+    fn batches() {
+        let (mut tx, mut rx) = channel(5);
+        assert_eq!(tx.push_slice(&[0, 1, 2]), 3);
+        assert_eq!(tx.push_slice(&[3, 4, 5, 6]), 2);
+        let mut out = [-1; 4];
+        assert_eq!(rx.pop_into(&mut out), 4);
+        assert_eq!(out, [0, 1, 2, 3]);
+        assert_eq!(tx.push_slice(&[5, 6, 7, 8, 9]), 4);
+        assert_eq!(rx.pop_into(&mut out), 4);
+        assert_eq!(out, [4, 5, 6, 7]);
+        assert_eq!(rx.pop_into(&mut out), 1);
+        assert_eq!(out[0], 8);
+        assert_eq!(rx.pop(), None);
+    }
+
+    #[test]
+    fn drop_remaining_values() {
+        let val = Arc::new(());
+        let (mut tx, mut rx) = channel(8);
+        for _ in 0..5 {
+            tx.push(val.clone()).unwrap();
+        }
+        drop(rx.pop());
+        assert_eq!(Arc::strong_count(&val), 5);
+        drop(tx);
+        drop(rx);
+        assert_eq!(Arc::strong_count(&val), 1);
+    }
+
+    #[test]
+    fn two_thread() {
+        const ITER_NUM: usize = 1 << 16;
+
+        let (mut tx, mut rx) = channel(64);
+        let producer = thread::spawn(move || {
+            let mut i = 0;
+            while i < ITER_NUM {
+                if i % 2 == 0 {
+                    let batch: Vec<_> = (i..ITER_NUM.min(i + 7)).collect();
+                    i += tx.push_slice(&batch);
+                } else if tx.push(i).is_ok() {
+                    i += 1;
+                } else {
+                    thread::yield_now();
+                }
+            }
+        });
+        let mut expected = 0;
+        let mut out = [0; 5];
+        while expected < ITER_NUM {
+            let n = rx.pop_into(&mut out);
+            assert_eq!(&out[..n], &(expected..expected + n).collect::<Vec<_>>()[..]);
+            expected += n;
+            match rx.pop() {
+                Some(i) => {
+                    assert_eq!(i, expected);
+                    expected += 1;
+                }
+                None if n == 0 => thread::yield_now(),
+                None => {}
+            }
+        }
+        producer.join().unwrap();
+        assert_eq!(rx.pop(), None);
+    }
+
+    #[bench]
+    fn bench_push_pop(b: &mut Bencher) {
+        b.iter(|| {
+            let (mut tx, mut rx) = channel(64);
+            let producer = thread::spawn(move || {
+                for i in 0..1 << 10 {
+                    while tx.push(i).is_err() {
+                        thread::yield_now();
+                    }
+                }
+            });
+            for _ in 0..1 << 10 {
+                while rx.pop().is_none() {
+                    thread::yield_now();
+                }
+            }
+            producer.join().unwrap();
+        });
+    }
+}
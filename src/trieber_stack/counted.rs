@@ -0,0 +1,239 @@
+use crate::cas_utils::deadline::{try_m_cas_until, Busy, Until};
+use crate::cas_utils::m_cas::{AtomicMCasPtr, MCasPtr, SingleCas};
+use crate::epoch;
+use std::mem::MaybeUninit;
+use std::ptr::null_mut;
+use std::sync::atomic::Ordering;
+
+/// # Fields
+///
+/// * `val`: The value, moved out by the popper which unlinks the node
+/// * `next`: Cell of the node below, or the bottom cell. Fixed once the node is pushed.
+struct Node<T> {
+    val: MaybeUninit<T>,
+    next: *mut Cell<T>,
+}
+
+/// Content of a cell. All locations of a MCAS hold cells of one type, so the count is a variant
+/// next to the nodes.
+enum Link<T> {
+    Node(Node<T>),
+    Bottom,
+    Len(usize),
+}
+
+type Cell<T> = MCasPtr<Link<T>>;
+
+fn len_of<T>(cell: *mut Cell<T>) -> usize {
+    match unsafe { (*cell).get() } {
+        Link::Len(len) => *len,
+        _ => unreachable!(),
+    }
+}
+
+/// A new cell, which is rewritten in place until it is published
+fn new_cell<T>(link: Link<T>) -> *mut Cell<T> {
+    Box::into_raw(Box::new(MCasPtr::new(link)))
+}
+
+/// A stack which knows its length
+///
+/// A push or pop swings `top` and replaces `len` in one MCAS, so `len` is exact at every
+/// instant. Popped cells are retired through `epoch`, which also rules out ABA on `top`.
+pub struct CountedStack<T> {
+    top: AtomicMCasPtr<Link<T>>,
+    len: AtomicMCasPtr<Link<T>>,
+}
+
+unsafe impl<T: Send> Send for CountedStack<T> {}
+unsafe impl<T: Send> Sync for CountedStack<T> {}
+
+impl<T: 'static> CountedStack<T> {
+    pub fn new() -> CountedStack<T> {
+        CountedStack {
+            top: AtomicMCasPtr::new(Box::new(MCasPtr::new(Link::Bottom))),
+            len: AtomicMCasPtr::new(Box::new(MCasPtr::new(Link::Len(0)))),
+        }
+    }
+
+    /// Number of values, as of one instant
+    pub fn len(&self) -> usize {
+        match self.len.read(&epoch::pin()) {
+            Link::Len(len) => *len,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn push(&self, val: T) {
+        if self.try_push_until(val, &Until::never()).is_err() {
+            unreachable!()
+        }
+    }
+
+    /// `push`, giving `val` back once `until` has expired.
+    pub fn try_push_until(&self, val: T, until: &Until) -> Result<(), T> {
+        let new = new_cell(Link::Node(Node {
+            val: MaybeUninit::new(val),
+            next: null_mut(),
+        }));
+        let len = new_cell(Link::Len(0));
+        let guard = epoch::pin();
+        loop {
+            if until.expired() {
+                break;
+            }
+            let top = self.top.load();
+            let old_len = self.len.load();
+            match unsafe { (*new).get_mut() } {
+                Link::Node(node) => node.next = top,
+                _ => unreachable!(),
+            }
+            unsafe { *(*len).get_mut() = Link::Len(len_of(old_len) + 1) };
+            let m_cas = [
+                SingleCas::new(&self.top, top, new),
+                SingleCas::new(&self.len, old_len, len),
+            ];
+            match try_m_cas_until(m_cas, until) {
+                Ok(true) => {
+                    unsafe { MCasPtr::retire(old_len, &guard) };
+                    return Ok(());
+                }
+                Ok(false) => {}
+                Err(Busy) => break,
+            }
+        }
+        // Neither cell has been published.
+        drop(unsafe { Box::from_raw(len) });
+        match std::mem::replace(unsafe { Box::from_raw(new) }.get_mut(), Link::Bottom) {
+            Link::Node(node) => Err(unsafe { node.val.assume_init() }),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        match self.try_pop_until(&Until::never()) {
+            Ok(res) => res,
+            Err(Busy) => unreachable!(),
+        }
+    }
+
+    /// `pop`, giving up with `Busy` once `until` has expired.
+    pub fn try_pop_until(&self, until: &Until) -> Result<Option<T>, Busy> {
+        let len = new_cell(Link::Len(0));
+        let guard = epoch::pin();
+        let res = loop {
+            if until.expired() {
+                break Err(Busy);
+            }
+            let top = self.top.load();
+            let node = match unsafe { (*top).get() } {
+                Link::Node(node) => node,
+                _ => break Ok(None),
+            };
+            let old_len = self.len.load();
+            // Wraps if `old_len` is already stale, the MCAS then fails.
+            unsafe { *(*len).get_mut() = Link::Len(len_of(old_len).wrapping_sub(1)) };
+            let m_cas = [
+                SingleCas::new(&self.top, top, node.next),
+                SingleCas::new(&self.len, old_len, len),
+            ];
+            match try_m_cas_until(m_cas, until) {
+                Ok(true) => {
+                    // Only the popper which unlinked `top` reads its value.
+                    let val = unsafe { node.val.as_ptr().read() };
+                    unsafe { MCasPtr::retire(top, &guard) };
+                    unsafe { MCasPtr::retire(old_len, &guard) };
+                    return Ok(Some(val));
+                }
+                Ok(false) => {}
+                Err(Busy) => break Err(Busy),
+            }
+        };
+        // The count has never been published.
+        drop(unsafe { Box::from_raw(len) });
+        res
+    }
+}
+
+impl<T> Drop for CountedStack<T> {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(self.len.get_m_cas_ptr(Ordering::SeqCst)) });
+        let mut cell = self.top.get_m_cas_ptr(Ordering::SeqCst);
+        loop {
+            let mut current = unsafe { Box::from_raw(cell) };
+            match std::mem::replace(current.get_mut(), Link::Bottom) {
+                Link::Node(mut node) => {
+                    unsafe { node.val.assume_init_drop() };
+                    cell = node.next;
+                }
+                _ => return,
+            }
+        }
+    }
+}
+
+impl<T: 'static> Default for CountedStack<T> {
+    fn default() -> Self {
+        CountedStack::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn single_thread_lifo() {
+        let s = CountedStack::new();
+        assert_eq!(s.pop(), None);
+        assert!(s.is_empty());
+        for i in 0..100 {
+            s.push(i);
+        }
+        assert_eq!(s.len(), 100);
+        for i in (50..100).rev() {
+            assert_eq!(s.pop(), Some(i));
+        }
+        assert_eq!(s.len(), 50);
+        let val = Arc::new(());
+        let s = CountedStack::new();
+        for _ in 0..5 {
+            s.push(val.clone());
+        }
+        drop(s.pop());
+        assert_eq!(Arc::strong_count(&val), 5);
+        drop(s);
+        assert_eq!(Arc::strong_count(&val), 1);
+    }
+
+    #[test]
+    fn len_stays_in_bounds() {
+        const THREAD_NUM: usize = 4;
+        const ITER_NUM: usize = 2000;
+
+        let s = Arc::new(CountedStack::new());
+        let threads: Vec<_> = (0..THREAD_NUM)
+            .map(|_| {
+                let s = s.clone();
+                thread::spawn(move || {
+                    for i in 0..ITER_NUM {
+                        s.push(i);
+                        assert!((1..=THREAD_NUM).contains(&s.len()));
+                        assert!(s.pop().is_some());
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(s.len(), 0);
+        assert_eq!(s.pop(), None);
+    }
+}
@@ -1,9 +1,16 @@
-use std::ptr::null_mut;
+use crate::cas_utils::deadline::{Busy, Until};
+use crate::epoch;
+use std::mem::ManuallyDrop;
+use std::ptr::{self, null_mut};
 use std::sync::atomic::AtomicPtr;
 use std::sync::atomic::Ordering;
 
+pub mod counted;
+
+/// The value is moved out by the thread which unlinks the node. The node itself is retired
+/// through `epoch`, as other threads may still read its `next`.
 struct Node<T> {
-    pub val: T,
+    pub val: ManuallyDrop<T>,
     pub(crate) next: AtomicPtr<Option<Node<T>>>,
 }
 
@@ -11,7 +18,10 @@ pub struct Stack<T> {
     top: AtomicPtr<Option<Node<T>>>,
 }
 
-impl<T> Stack<T> {
+unsafe impl<T: Send> Send for Stack<T> {}
+unsafe impl<T: Send> Sync for Stack<T> {}
+
+impl<T: 'static> Stack<T> {
     pub fn new() -> Stack<T> {
         let none = Box::new(None);
         let none_ptr = Box::leak(none);
@@ -20,13 +30,24 @@ impl<T> Stack<T> {
         };
     }
     pub fn push(&self, val: T) {
+        if self.try_push_until(val, &Until::never()).is_err() {
+            unreachable!()
+        }
+    }
+
+    /// `push`, giving `val` back once `until` has expired.
+    pub fn try_push_until(&self, val: T, until: &Until) -> Result<(), T> {
         let node = Box::new(Some(Node {
-            val,
+            val: ManuallyDrop::new(val),
             next: AtomicPtr::new(null_mut()),
         }));
         let node_ptr = Box::leak(node);
 
         loop {
+            if until.expired() {
+                let node = unsafe { Box::from_raw(node_ptr as *mut Option<Node<T>>) };
+                return Err(ManuallyDrop::into_inner(node.unwrap().val));
+            }
             let top = self.top.load(Ordering::Relaxed);
             match node_ptr {
                 Some(node) => {
@@ -40,28 +61,117 @@ impl<T> Stack<T> {
                 Ordering::SeqCst,
                 Ordering::Relaxed,
             ) {
-                break;
+                break Ok(());
             }
         }
     }
 
     pub fn pop(&self) -> Option<T> {
+        match self.try_pop_until(&Until::never()) {
+            Ok(res) => res,
+            Err(Busy) => unreachable!(),
+        }
+    }
+
+    /// `pop`, giving up with `Busy` once `until` has expired.
+    pub fn try_pop_until(&self, until: &Until) -> Result<Option<T>, Busy> {
+        let guard = epoch::pin();
         loop {
-            let top = self.top.load(Ordering::Relaxed);
-            match unsafe { &mut *top } {
+            if until.expired() {
+                break Err(Busy);
+            }
+            let top = self.top.load(Ordering::Acquire);
+            match unsafe { &*top } {
                 Some(n) => {
                     let next = n.next.load(Ordering::Relaxed);
                     if let Ok(_) =
                         self.top
                             .compare_exchange(top, next, Ordering::SeqCst, Ordering::Relaxed)
                     {
-                        let retired_top = unsafe { (*top).take().unwrap() };
-                        break Some(retired_top.val);
+                        let val = ManuallyDrop::into_inner(unsafe { ptr::read(&n.val) });
+                        unsafe { guard.defer_destroy(top) };
+                        break Ok(Some(val));
                     }
                 }
                 None => {
-                    break None;
+                    break Ok(None);
+                }
+            }
+        }
+    }
+
+    /// Push the values of `vals` in order. Their nodes are linked into a chain first, which is
+    /// swung onto `top` with a single CAS.
+    pub fn push_all(&self, vals: impl IntoIterator<Item = T>) {
+        let mut vals = vals.into_iter();
+        let bottom = match vals.next() {
+            Some(val) => Box::leak(Box::new(Some(Node {
+                val: ManuallyDrop::new(val),
+                next: AtomicPtr::new(null_mut()),
+            }))) as *mut Option<Node<T>>,
+            None => return,
+        };
+        let mut chain_top = bottom;
+        for val in vals {
+            chain_top = Box::leak(Box::new(Some(Node {
+                val: ManuallyDrop::new(val),
+                next: AtomicPtr::new(chain_top),
+            })));
+        }
+
+        loop {
+            let top = self.top.load(Ordering::Relaxed);
+            match unsafe { &mut *bottom } {
+                Some(node) => node.next.store(top, Ordering::Relaxed),
+                None => unreachable!(),
+            }
+            if self
+                .top
+                .compare_exchange(top, chain_top, Ordering::SeqCst, Ordering::Relaxed)
+                .is_ok()
+            {
+                break;
+            }
+        }
+    }
+
+    /// Pop all values, top first, by swapping `top` with an empty stack. The swapped out chain
+    /// ends with the `None` the new one replaces, and is retired node by node.
+    pub fn pop_all(&self) -> Vec<T> {
+        let guard = epoch::pin();
+        if unsafe { &*self.top.load(Ordering::Acquire) }.is_none() {
+            return Vec::new();
+        }
+        let none_ptr = Box::leak(Box::new(None));
+        let mut top = self.top.swap(none_ptr, Ordering::SeqCst);
+        let mut vals = Vec::new();
+        loop {
+            let next = match unsafe { &*top } {
+                Some(node) => {
+                    vals.push(ManuallyDrop::into_inner(unsafe { ptr::read(&node.val) }));
+                    node.next.load(Ordering::Relaxed)
                 }
+                None => null_mut(),
+            };
+            unsafe { guard.defer_destroy(top) };
+            if next.is_null() {
+                break vals;
+            }
+            top = next;
+        }
+    }
+}
+
+impl<T> Drop for Stack<T> {
+    fn drop(&mut self) {
+        let mut top = *self.top.get_mut();
+        loop {
+            match *unsafe { Box::from_raw(top) } {
+                Some(mut node) => {
+                    unsafe { ManuallyDrop::drop(&mut node.val) };
+                    top = *node.next.get_mut();
+                }
+                None => return,
             }
         }
     }
@@ -70,8 +180,10 @@ impl<T> Stack<T> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cas_utils::deadline::CancellationToken;
     use std::sync::Arc;
     use std::thread;
+    use std::time::{Duration, Instant};
     use test::Bencher;
 
     #[test]
@@ -139,6 +251,119 @@ mod tests {
         }
     }
 
+    #[test]
+    fn try_until_expired() {
+        let s = Stack::new();
+        assert_eq!(s.try_push_until(1, &Until::never()), Ok(()));
+        let token = CancellationToken::new();
+        let until = Until::from(Instant::now() + Duration::from_secs(60)).or_cancelled(&token);
+        assert_eq!(s.try_push_until(2, &until), Ok(()));
+        token.cancel();
+        assert_eq!(s.try_push_until(3, &until), Err(3));
+        assert_eq!(s.try_pop_until(&until), Err(Busy));
+        assert_eq!(s.try_pop_until(&Instant::now().into()), Err(Busy));
+        assert_eq!(s.pop(), Some(2));
+        assert_eq!(s.pop(), Some(1));
+        assert_eq!(s.pop(), None);
+    }
+
+    #[test]
+    fn drop_remaining_values() {
+        let val = Arc::new(());
+        let s = Stack::new();
+        for _ in 0..5 {
+            s.push(val.clone());
+        }
+        s.push_all(vec![val.clone(); 5]);
+        drop(s.pop());
+        assert_eq!(Arc::strong_count(&val), 10);
+        drop(s);
+        assert_eq!(Arc::strong_count(&val), 1);
+    }
+
+    #[test]
+    fn push_all_and_pop_all() {
+        let s = Stack::new();
+        assert_eq!(s.pop_all(), Vec::<i32>::new());
+        s.push_all(Vec::new());
+        s.push(0);
+        s.push_all(1..4);
+        s.push(4);
+        assert_eq!(s.pop(), Some(4));
+        assert_eq!(s.pop_all(), vec![3, 2, 1, 0]);
+        assert_eq!(s.pop(), None);
+        s.push_all(0..2);
+        assert_eq!(s.pop(), Some(1));
+        assert_eq!(s.pop(), Some(0));
+        assert_eq!(s.pop(), None);
+    }
+
+    #[test]
+    fn multi_thread_pop_all_and_pop() {
+        const ITER_NUM: usize = 1 << 12;
+
+        let s = Arc::new(Stack::new());
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let c_s = s.clone();
+                thread::spawn(move || {
+                    let mut popped = Vec::new();
+                    for i in 0..ITER_NUM {
+                        c_s.push_all((0..4).map(|j| (t * ITER_NUM + i) * 4 + j));
+                        if t % 2 == 0 {
+                            popped.extend(c_s.pop_all());
+                        } else {
+                            popped.extend(c_s.pop());
+                        }
+                        thread::yield_now();
+                    }
+                    popped
+                })
+            })
+            .collect();
+        let mut popped: Vec<_> = threads
+            .into_iter()
+            .flat_map(|t| t.join().unwrap())
+            .collect();
+        popped.extend(s.pop_all());
+        popped.sort_unstable();
+        assert_eq!(popped, (0..4 * ITER_NUM * 4).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn multi_thread_push_all_and_pop_all() {
+        let s = Arc::new(Stack::new());
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let c_s = s.clone();
+                thread::spawn(move || {
+                    let mut popped = Vec::new();
+                    for i in 0..1 << 10 {
+                        let start = (t << 20) + (i << 3);
+                        c_s.push_all(start..start + 8);
+                        let vals = c_s.pop_all();
+                        // A chain is pushed with one CAS, so it is never split.
+                        for chain in vals.chunks(8) {
+                            assert_eq!(chain[0] % 8, 7);
+                            assert!(chain.windows(2).all(|w| w[0] == w[1] + 1));
+                        }
+                        popped.extend(vals);
+                    }
+                    popped
+                })
+            })
+            .collect();
+        let mut popped: Vec<_> = threads
+            .into_iter()
+            .flat_map(|t| t.join().unwrap())
+            .collect();
+        popped.sort_unstable();
+        let expected: Vec<_> = (0..4)
+            .flat_map(|t| (0..8 << 10).map(move |i| (t << 20) + i))
+            .collect();
+        assert_eq!(popped, expected);
+    }
+
     #[bench]
     fn bench_add_two(b: &mut Bencher) {
         b.iter(|| {
@@ -1,4 +1,5 @@
 use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
 use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 use std::sync::Arc;
 
@@ -28,6 +29,7 @@ pub struct AtomicNumLikes {
 pub trait AtomicNumLikesMethods<T: From<usize> + Into<usize> + Copy> {
     fn new(v: T) -> AtomicNumLikes;
     fn get(&self, order: Ordering) -> T;
+    fn set(&self, v: T, order: Ordering);
     fn compare_and_swap(&self, current: T, new: T, order: Ordering) -> T;
 }
 
@@ -42,6 +44,10 @@ impl<T: From<usize> + Into<usize> + Copy> AtomicNumLikesMethods<T> for AtomicNum
         T::from(self.inner.load(order).clone())
     }
 
+    fn set(&self, v: T, order: Ordering) {
+        self.inner.store(v.into(), order)
+    }
+
     fn compare_and_swap(&self, current: T, new: T, order: Ordering) -> T {
         T::from(
             self.inner
@@ -49,3 +55,38 @@ impl<T: From<usize> + Into<usize> + Copy> AtomicNumLikesMethods<T> for AtomicNum
         )
     }
 }
+
+/// `T` aligned to its own cache line, so that writes to it do not invalidate the lines of
+/// neighbouring data. Adjacent lines are prefetched in pairs on x86_64, hence 128 bytes there.
+#[cfg_attr(
+    any(target_arch = "x86_64", target_arch = "aarch64"),
+    repr(C, align(128))
+)]
+#[cfg_attr(
+    not(any(target_arch = "x86_64", target_arch = "aarch64")),
+    repr(C, align(64))
+)]
+#[derive(Debug, Default)]
+pub struct CachePadded<T> {
+    value: T,
+}
+
+impl<T> CachePadded<T> {
+    pub fn new(value: T) -> CachePadded<T> {
+        CachePadded { value }
+    }
+}
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for CachePadded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
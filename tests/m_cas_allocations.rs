@@ -0,0 +1,67 @@
+//! The counting allocator replaces the global allocator of this test binary only.
+
+use beee::cas_utils::m_cas::{AtomicMCasPtr, MCas, MCasPtr, SingleCas};
+use beee::epoch;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(|count| count.get())
+}
+
+#[test]
+fn steady_state_m_cas_does_not_allocate() {
+    let ptrs: Vec<*mut MCasPtr<i32>> = (0..4)
+        .map(|val| Box::into_raw(Box::new(MCasPtr::new(val))))
+        .collect();
+    let atomic: Vec<AtomicMCasPtr<i32>> = ptrs[..2]
+        .iter()
+        .map(|&ptr| unsafe { AtomicMCasPtr::from_raw(ptr) })
+        .collect();
+
+    let forward = vec![
+        SingleCas::new(&atomic[0], ptrs[0], ptrs[2]),
+        SingleCas::new(&atomic[1], ptrs[1], ptrs[3]),
+    ];
+    let backward = vec![
+        SingleCas::new(&atomic[0], ptrs[2], ptrs[0]),
+        SingleCas::compare(&atomic[1], ptrs[3]),
+    ];
+    let fixed = [
+        SingleCas::new(&atomic[1], ptrs[3], ptrs[1]),
+        SingleCas::compare(&atomic[0], ptrs[0]),
+    ];
+    let run = || {
+        assert!(forward.m_cas());
+        assert!(backward.m_cas());
+        assert!(fixed.m_cas());
+        assert_eq!(*atomic[0].read(&epoch::pin()), 0);
+        assert_eq!(*atomic[1].read(&epoch::pin()), 1);
+    };
+
+    run();
+    let before = allocations();
+    for _ in 0..100 {
+        run();
+    }
+    assert_eq!(allocations(), before);
+}